@@ -1,13 +1,25 @@
-use crate::{dgramostream, gdl90, traffic_infos::TrafficInfos};
+use crate::{config::{ClientConfig, DeviceConfig}, dgramostream, gdl90, geo, protocol::{self, ClientMsg, Filter, RejectReason}, traffic_infos::{AddressType, EncodedTraffic, TrafficInfos}};
 
-use std::{net::{SocketAddr, TcpStream}, os::fd::{AsFd, BorrowedFd}, time::Duration};
+use std::{collections::HashMap, io::Read, net::{Shutdown, SocketAddr, TcpStream}, os::fd::{AsFd, BorrowedFd}, sync::Arc, time::{Duration, Instant}};
 
 
-// client 2D position
+// An aircraft not sent for longer is no longer counted in the targets of the client
+const TARGET_TIMEOUT: Duration = Duration::from_secs(30);
+
+
+// client position
 #[derive(Clone)]
 pub struct Position {
     pub latitude: f64,
     pub longitude: f64,
+    pub altitude: Option<i32>,      // In ft with QNH of 1013 hPa, if sent by the client
+}
+
+
+// Aircraft sent to the client
+struct Target {
+    last_sent: Instant,
+    distance: f64,                  // In km, from the client when last sent
 }
 
 
@@ -15,25 +27,74 @@ pub struct Client {
     socket: TcpStream,
     address: SocketAddr,
     position: Option<Position>,
+    snapshot_position: Option<Position>,    // Position of the client when it received the last traffic snapshot
+    config: ClientConfig,
+    filter_request: Option<Filter>,         // Filter requested by the client, if any
+    // Filter applied, from the request of the client within the limits of the configuration
+    radius: f64,                            // In km
+    altitude_band: u32,                     // In ft
+    max_targets: usize,
+    update_interval: Duration,
+    targets: HashMap<(AddressType, u32), Target>,
+    ownship: Vec<(AddressType, u32)>,       // Addresses of the client's own aircraft, never sent to it
+    capabilities: u32,                      // Negotiated with the HELLO message
+    nb_traffic_sent: u16,                   // Since the last heartbeat
+    nb_msg_received: u64,
     recv_dgram: dgramostream::RecvDgram,
+    send_queue: dgramostream::SendQueue<(AddressType, u32)>,    // Traffic reports are identified by their aircraft
+    write_interest: bool,                   // The pool waits for the socket to be writable
+    closing: bool,                          // Nothing else is sent, the connection is closed once the queue is sent
+    write_closed: bool,
 }
 
 
 impl Client {
     /// Creation of a new client
-    pub fn new(socket: TcpStream) -> Self {
+    pub fn new(socket: TcpStream, config: &ClientConfig) -> Self {
         // Set the client socket to be able to detect a connectivity problem as quickly as possible
         Self::set_sock_options(&socket);
 
         // Get the address of the connected client
         let address = socket.peer_addr().unwrap();
 
-        Self {
+        let mut client = Self {
             socket,
             address,
             position: None,
-            recv_dgram: dgramostream::RecvDgram::new(16),
+            snapshot_position: None,
+            config: config.clone(),
+            filter_request: None,
+            radius: 0.0,
+            altitude_band: 0,
+            max_targets: 0,
+            update_interval: Duration::ZERO,
+            targets: HashMap::new(),
+            ownship: Vec::new(),
+            capabilities: protocol::CAP_GDL90,
+            nb_traffic_sent: 0,
+            nb_msg_received: 0,
+            recv_dgram: dgramostream::RecvDgram::new(protocol::CLIENT_MSG_MAX_LEN),
+            send_queue: dgramostream::SendQueue::new(config.send_queue_len, config.drop_policy),
+            write_interest: false,
+            closing: false,
+            write_closed: false,
+        };
+        client.apply_filter();
+        client
+    }
+
+
+    /// Apply a new configuration to the client
+    pub fn set_config(&mut self, config: &ClientConfig) -> anyhow::Result<()> {
+        self.config = config.clone();
+        self.send_queue.set_limits(config.send_queue_len, config.drop_policy);
+        let filter = self.apply_filter();
+
+        // The limits may have changed, the client is informed of its new filter
+        if self.filter_request.is_some() {
+            self.send_filter_ack(&filter)?;
         }
+        Ok(())
     }
 
 
@@ -43,36 +104,231 @@ impl Client {
     }
 
 
-    /// Receive the position of the client
+    /// Get the last position sent by the client
+    pub fn position(&self) -> Option<&Position> {
+        self.position.as_ref()
+    }
+
+
+    /// Get the radius in km around the client within which the traffic is sent to it
+    pub fn radius(&self) -> f64 {
+        self.radius
+    }
+
+
+    /// Receive a message from the client
+    /// Returns the position of the client if the message contains a new one
     pub fn recv_position(&mut self) -> anyhow::Result<Option<Position>> {
-        // Reading the position datagram from the client
-        match self.recv_dgram.recv(&self.socket)? {
-            None => Ok(None),                   // The datagram is not yet reconstituted, nothing to do
-            Some(position_dgram) => {    // The datagram is reconstituted, we parse it
-                self.position = Some(Self::parse_client_position_msg(position_dgram)?);
+        // Reading the datagram from the client
+        let msg = match self.recv_dgram.recv(&self.socket)? {
+            None => return Ok(None),            // The datagram is not yet reconstituted, nothing to do
+            Some(dgram) => protocol::parse_client_msg(dgram)?,    // The datagram is reconstituted, we parse it
+        };
+        self.nb_msg_received += 1;
+
+        match msg {
+            ClientMsg::Hello { version, capabilities } => {
+                // The negotiation can only take place at the beginning of the connection
+                anyhow::ensure!(self.nb_msg_received == 1, "Hello message not received first");
+                self.negotiate(version, capabilities)?;
+                Ok(None)
+            }
+            ClientMsg::Ownship(ownship) => {
+                log::info!("Ownship addresses of client {} : {:06X?}", self.address, ownship);
+                self.ownship = ownship;
+                Ok(None)
+            }
+            ClientMsg::Position(position, filter_request) => {
+                self.position = Some(position);
+
+                // A new filter request is applied and acknowledged with the values really used
+                if filter_request.is_some() && (filter_request != self.filter_request) {
+                    self.filter_request = filter_request;
+                    let filter = self.apply_filter();
+                    self.send_filter_ack(&filter)?;
+                }
+
                 Ok(self.position.clone())
             }
         }
     }
 
 
-    /// Send traffic information to the client, only if it is nearby
-    pub fn send_traffic(&self, traffic_infos: &TrafficInfos) -> anyhow::Result<()> {
-        if self.traffic_close(traffic_infos) {
-            // The traffic is close to the client, we send it the information
+    /// Indicates whether the client must receive a snapshot of all the known traffic around it:
+    /// it has just sent its first position, or it has moved far enough from the position of
+    /// its last snapshot for its proximity area to change significantly
+    pub fn snapshot_needed(&mut self) -> bool {
+        let Some(position) = &self.position else {
+            return false;
+        };
+
+        let needed = match &self.snapshot_position {
+            None => true,
+            Some(snapshot_position) => {
+                geo::distance_km(position.latitude, position.longitude,
+                    snapshot_position.latitude, snapshot_position.longitude) > self.radius / 2.0
+            }
+        };
+        if needed {
+            self.snapshot_position = Some(position.clone());
+        }
+        needed
+    }
+
+
+    /// Send traffic information to the client, only if it is nearby and allowed by the filter of the client
+    /// Returns true if the information has been sent
+    pub fn send_traffic(&mut self, traffic: &EncodedTraffic) -> anyhow::Result<bool> {
+        let traffic_infos = &traffic.infos;
+        let key = (traffic_infos.addr_type, traffic_infos.address);
+
+        let Some(distance) = self.traffic_distance(traffic_infos) else {
+            // The traffic is not close to the client, it is no longer one of its targets
+            self.targets.remove(&key);
+            return Ok(false);
+        };
+
+        if !self.accept_target(key, distance) {
+            return Ok(false);
+        }
+
+        // Send the message, already in GDL90 format, as a datagram
+        self.send(Some(key), traffic.report.clone())?;
+        self.nb_traffic_sent = self.nb_traffic_sent.saturating_add(1);
+        Ok(true)
+    }
+
+
+    /// Send a heartbeat to the client, with the status of the server completed with the client's one,
+    /// followed by its own position if known
+    pub fn send_heartbeat(&mut self, server_status: &gdl90::HeartbeatStatus) -> anyhow::Result<()> {
+        let status = gdl90::HeartbeatStatus {
+            position_valid: self.position.is_some(),
+            nb_messages: self.nb_traffic_sent,
+            ..*server_status
+        };
+        self.nb_traffic_sent = 0;
+
+        // Prepare the message in GDL90 format
+        let mut buffer = [0u8; 32];
+        let len = gdl90::make_heartbeat_message(&status, &mut buffer).unwrap();
+
+        // Send the message as a datagram
+        self.send(None, Arc::from(&buffer[..len]))?;
+
+        self.send_ownship()
+    }
+
+
+    /// Send the identification of the server to the client
+    pub fn send_device_id(&mut self, device: &DeviceConfig) -> anyhow::Result<()> {
+        // Prepare the message in GDL90 format
+        let mut buffer = [0u8; 100];
+        let len = gdl90::make_foreflight_id_message(device.serial_number, &device.name, &device.long_name,
+            device.capabilities, &mut buffer).unwrap();
+
+        // Send the message as a datagram
+        self.send(None, Arc::from(&buffer[..len]))
+    }
 
-            // Prepare the message in GDL90 format
-            let mut buffer = [0u8; 100];
-            let len = gdl90::make_traffic_report_message(traffic_infos, &mut buffer).unwrap();
 
-            // Send the message as a datagram
-            dgramostream::send(&self.socket, &buffer[..len])?;
+    // Send the client's position back to it, for the applications without their own GPS integration
+    fn send_ownship(&mut self) -> anyhow::Result<()> {
+        let Some(position) = &self.position else {
+            return Ok(());
+        };
+
+        // The ownship is identified by the first address registered by the client, if any
+        let (addr_type, address) = self.ownship.first().copied().unwrap_or_default();
+
+        let mut buffer = [0u8; 100];
+        let len = gdl90::make_ownship_report_message(addr_type, address,
+            position.latitude, position.longitude, position.altitude, &mut buffer).unwrap();
+        let altitude = position.altitude;
+        self.send(None, Arc::from(&buffer[..len]))?;
+
+        // The client only gives its pressure altitude, it is used as an approximation of its geometric altitude
+        if let Some(altitude) = altitude {
+            let len = gdl90::make_ownship_geometric_altitude_message(altitude, &mut buffer).unwrap();
+            self.send(None, Arc::from(&buffer[..len]))?;
         }
+        Ok(())
+    }
+
+
+    /// Tell the client that nothing else will be sent, once the pending messages are sent
+    /// The connection is closed when the client closes its side
+    pub fn close_write(&mut self) -> anyhow::Result<()> {
+        self.closing = true;
+        self.flush()
+    }
+
+
+    /// Refuse the client, which is informed of the reason and of the delay in seconds before connecting again
+    /// Nothing else is sent, the connection is closed when the client is dropped
+    pub fn reject(&mut self, reason: RejectReason, retry_delay: u16) -> anyhow::Result<()> {
+        let mut buffer = [0u8; 16];
+        let len = protocol::make_reject_msg(reason, retry_delay, &mut buffer);
+        self.send(None, Arc::from(&buffer[..len]))?;
+        self.close_write()?;
+
+        // Discard what the client has already sent, otherwise the connection would be reset
+        // when the socket is closed and the message could be lost
+        let mut buffer = [0u8; protocol::CLIENT_MSG_MAX_LEN as usize];
+        while matches!((&self.socket).read(&mut buffer), Ok(len) if len > 0) {}
+        Ok(())
+    }
 
+
+    /// Send the pending messages, as long as the socket accepts them
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        self.send_queue.flush(&self.socket)?;
+        if self.closing && !self.write_closed && self.send_queue.is_empty() {
+            self.socket.shutdown(Shutdown::Write)?;
+            self.write_closed = true;
+        }
         Ok(())
     }
 
 
+    /// Indicates whether the client must be notified when its socket becomes writable,
+    /// only if it changed since the last call
+    pub fn write_interest_changed(&mut self) -> Option<bool> {
+        let write_interest = !self.send_queue.is_empty();
+        if write_interest == self.write_interest {
+            None
+        }
+        else {
+            self.write_interest = write_interest;
+            Some(write_interest)
+        }
+    }
+
+
+    /// Forget the write interest, when the socket is registered again without it
+    pub fn reset_write_interest(&mut self) {
+        self.write_interest = false;
+    }
+
+
+    /// Get the number of traffic reports dropped because the client is too slow,
+    /// and the number of times its send queue was full
+    pub fn send_stats(&self) -> (u64, u64) {
+        self.send_queue.stats()
+    }
+
+
+    // Queue a message, then send what the socket accepts
+    // A traffic report is identified by its aircraft, so that the send queue can drop it if the client is too slow
+    fn send(&mut self, aircraft: Option<(AddressType, u32)>, message: Arc<[u8]>) -> anyhow::Result<()> {
+        if self.closing {
+            return Ok(());
+        }
+        self.send_queue.push(aircraft, message)?;
+        self.send_queue.flush(&self.socket)
+    }
+
+
     fn set_sock_options(socket: &TcpStream) {
         let sock = socket2::SockRef::from(socket);
 
@@ -88,42 +344,133 @@ impl Client {
     }
 
 
-    fn parse_client_position_msg(msg: &[u8]) -> anyhow::Result<Position> {
-        let mut parser = bytes_parser::BytesParser::from(msg);
+    // Chooses the protocol version and the capabilities according to the client ones,
+    // then informs the client
+    fn negotiate(&mut self, version: u8, capabilities: u32) -> anyhow::Result<()> {
+        let version = version.min(protocol::PROTOCOL_VERSION);
+        self.capabilities = capabilities & protocol::SUPPORTED_CAPABILITIES;
+        log::info!("Client {} uses protocol version {} with capabilities {:#x} (requested {:#x})",
+            self.address, version, self.capabilities, capabilities);
+
+        let mut buffer = [0u8; 16];
+        let len = protocol::make_hello_ack_msg(version, self.capabilities, &mut buffer);
+        self.send(None, Arc::from(&buffer[..len]))?;
+
+        // GDL90 is the only output format, the client is informed before being disconnected if it does not use it
+        anyhow::ensure!(self.capabilities & protocol::CAP_GDL90 != 0, "No output format supported by the client");
+        Ok(())
+    }
+
+
+    // Computes the filter from the request of the client and the limits of the configuration
+    // Returns the applied filter
+    fn apply_filter(&mut self) -> Filter {
+        let request = self.filter_request.unwrap_or_default();
+        let config = &self.config;
+
+        self.radius = if request.radius == 0 { config.radius } else { f64::from(request.radius).min(config.max_radius) };
+        self.altitude_band = if request.altitude_band == 0 { config.altitude_band } else { u32::from(request.altitude_band).min(config.max_altitude_band) };
+        self.max_targets = if request.max_targets == 0 { config.max_targets } else { usize::from(request.max_targets).min(config.max_targets) };
+        self.update_interval = Duration::from_millis(u64::from(request.update_interval).max(config.min_update_interval));
+
+        // The limits are checked by the configuration, the values can be converted to u16
+        Filter {
+            radius: self.radius.round() as u16,
+            altitude_band: self.altitude_band as u16,
+            max_targets: self.max_targets as u16,
+            update_interval: self.update_interval.as_millis() as u16,
+        }
+    }
+
+
+    fn send_filter_ack(&mut self, filter: &Filter) -> anyhow::Result<()> {
+        let mut buffer = [0u8; 16];
+        let len = protocol::make_filter_ack_msg(filter, &mut buffer);
+        self.send(None, Arc::from(&buffer[..len]))
+    }
+
+
+    // Indicates whether a nearby aircraft can be sent according to the filter of the client
+    // The targets of the client are updated accordingly
+    fn accept_target(&mut self, key: (AddressType, u32), distance: f64) -> bool {
+        let now = Instant::now();
 
-        let latitude = f64::from(parser.parse_i32()?) / 1_000_000.0;
-        anyhow::ensure!((-90.0..=90.0).contains(&latitude), "Latitude out of bounds");
+        if let Some(target) = self.targets.get_mut(&key) {
+            // Already a target, the update rate requested by the client must be respected
+            if now.duration_since(target.last_sent) < self.update_interval {
+                return false;
+            }
+            target.last_sent = now;
+            target.distance = distance;
+            return true;
+        }
+
+        // New target, if the maximum number is reached, it replaces the furthest one if it is closer
+        self.targets.retain(|_, target| now.duration_since(target.last_sent) <= TARGET_TIMEOUT);
+        if self.targets.len() >= self.max_targets {
+            let furthest = self.targets.iter()
+                .max_by(|(_, t1), (_, t2)| t1.distance.total_cmp(&t2.distance))
+                .map(|(key, target)| (*key, target.distance));
+            match furthest {
+                Some((furthest_key, furthest_distance)) if distance < furthest_distance => {
+                    self.targets.remove(&furthest_key);
+                }
+                _ => return false,
+            }
+        }
+        self.targets.insert(key, Target { last_sent: now, distance });
+        true
+    }
 
-        let longitude = f64::from(parser.parse_i32()?) / 1_000_000.0;
-        anyhow::ensure!((-180.0..=180.0).contains(&longitude), "Longitude out of bounds");
 
-        Ok(Position {
-            latitude,
-            longitude,
-        })
+    /// Indicates whether the traffic is close enough to the client to be sent to it
+    pub fn traffic_close(&self, traffic_infos: &TrafficInfos) -> bool {
+        self.traffic_distance(traffic_infos).is_some()
     }
 
 
-    fn traffic_close(&self, traffic_infos: &TrafficInfos) -> bool {
-        let mut traffic_close = false;
+    // Get the distance in km of the traffic if it is close enough to the client to be sent to it
+    fn traffic_distance(&self, traffic_infos: &TrafficInfos) -> Option<f64> {
+        // If the client's position is not known, we consider that the traffic is not close
+        let position = self.position.as_ref()?;
 
-        match &self.position {
-            None => (),         // The client's position is not known, we consider that the traffic is not close
-            Some(position) => {
-                // Traffic must be in a square centered on the customer's position to be considered close
-                const MAX_DELTA_LATITUDE: f64 = 1.0;     // In degrees
-                const MAX_DELTA_LONGITUDE: f64 = 1.0;    // In degrees
-                if ((traffic_infos.latitude - position.latitude).abs() < MAX_DELTA_LATITUDE) &&
-                   ((traffic_infos.longitude - position.longitude).abs() < MAX_DELTA_LONGITUDE) {
-                    traffic_close = true;
-                   }
+        // The client's own aircraft is never sent to it, even when it has been fused into an ICAO track
+        if self.ownship.iter().any(|&(addr_type, address)| address == traffic_infos.address && Self::same_address_type(addr_type, traffic_infos.addr_type)) {
+            return None;
+        }
+
+        // Traffic must be within the radius around the client's position to be considered close
+        let distance = geo::distance_km(position.latitude, position.longitude,
+            traffic_infos.latitude, traffic_infos.longitude);
+        if distance > self.radius {
+            return None;
+        }
+
+        // and within the altitude band if the client's altitude is known
+        if let Some(altitude) = position.altitude {
+            let altitude_difference = traffic_infos.altitude.abs_diff(altitude);
+            if altitude_difference > self.altitude_band {
+                return None;
+            }
+
+            // A traffic at the client's position is considered as its own aircraft if it has not registered it
+            if self.config.ownship_suppression &&
+                (distance <= self.config.ownship_max_distance) &&
+                (altitude_difference <= self.config.ownship_max_altitude_difference) {
+                return None;
             }
         }
 
-        traffic_close
+        Some(distance)
     }
 
 
+    // The address types are compared as in GDL90, an ICAO address being the same as any other one
+    // because the traffic table fuses the tracks of the other types with the ICAO track of the same address
+    fn same_address_type(addr_type1: AddressType, addr_type2: AddressType) -> bool {
+        (addr_type1 == AddressType::AdsbIcao) || (addr_type2 == AddressType::AdsbIcao) ||
+            (u8::from(&addr_type1) == u8::from(&addr_type2))
+    }
 }
 
 
@@ -132,3 +479,160 @@ impl AsFd for Client {
         self.socket.as_fd()
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::FusionConfig, ddb::Ddb, traffic_infos::Source, traffic_store::TrafficStore};
+    use std::{hint::black_box, io::Write, net::TcpListener};
+
+    const NB_CLIENTS: usize = 200;
+    const NB_UPDATES: usize = 5_000;
+
+
+    // Client connected through the loopback, with the socket of its application
+    fn connect_client() -> (Client, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let application = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (socket, _) = listener.accept().unwrap();
+        (Client::new(socket, &ClientConfig::default()), application)
+    }
+
+
+    // Sends a message of the application to the client, which receives it
+    // Returns the position of the client if the message contains a new one
+    fn send_message(client: &mut Client, application: &mut TcpStream, message: &[u8]) -> anyhow::Result<Option<Position>> {
+        application.write_all(&(message.len() as u16).to_be_bytes()).unwrap();
+        application.write_all(message).unwrap();
+        let nb_msg_received = client.nb_msg_received;
+        loop {
+            let position = client.recv_position()?;
+            if client.nb_msg_received > nb_msg_received {
+                return Ok(position);
+            }
+        }
+    }
+
+
+    // Receives a message of the server by the application
+    fn recv_message(application: &mut TcpStream) -> Vec<u8> {
+        let mut len = [0u8; 2];
+        application.read_exact(&mut len).unwrap();
+        let mut message = vec![0u8; u16::from_be_bytes(len) as usize];
+        application.read_exact(&mut message).unwrap();
+        message
+    }
+
+
+    fn position_msg(latitude: i32, longitude: i32) -> Vec<u8> {
+        [latitude.to_be_bytes(), longitude.to_be_bytes()].concat()
+    }
+
+
+    fn hello_msg(version: u8, capabilities: u32) -> Vec<u8> {
+        [&[0x11, version][..], &capabilities.to_be_bytes()].concat()
+    }
+
+
+    fn traffic(addr_type: AddressType, address: u32) -> TrafficInfos {
+        TrafficInfos { addr_type, address, altitude: 3000, latitude: 45.0, longitude: 5.0, ..Default::default() }
+    }
+
+
+    #[test]
+    fn ownship_fused_into_an_icao_track() {
+        let (mut client, mut application) = connect_client();
+
+        // Ownship registered with its FLARM address, then position without altitude close to the traffic
+        send_message(&mut client, &mut application, &[0x10, 6, 0xdd, 0xa5, 0xba]).unwrap();
+        assert!(send_message(&mut client, &mut application, &position_msg(45_010_000, 5_000_000)).unwrap().is_some());
+        assert!(!client.traffic_close(&traffic(AddressType::Flarm, 0xdda5ba)));
+        assert!(client.traffic_close(&traffic(AddressType::Flarm, 0xdda5bb)));
+
+        // The FLARM track is fused with the ICAO track of the ADS-B transponder of the same aircraft
+        let mut store = TrafficStore::new(&Ddb::new_shared());
+        let fusion = FusionConfig::default();
+        store.update(traffic(AddressType::Flarm, 0xdda5ba), Source::Ogn, &fusion);
+        let entry = store.update(traffic(AddressType::AdsbIcao, 0xdda5ba), Source::Adsbhub, &fusion).unwrap();
+        assert_eq!(entry.traffic.infos.addr_type, AddressType::AdsbIcao);
+        assert!(!client.traffic_close(&entry.traffic.infos));
+
+        // An aircraft with a self-assigned address is not taken for the FLARM one
+        assert!(client.traffic_close(&traffic(AddressType::Random, 0xdda5ba)));
+    }
+
+
+    #[test]
+    fn hello_negotiation() {
+        // The highest common version is used, with the requested capabilities supported by the server
+        let (mut client, mut application) = connect_client();
+        let hello = hello_msg(protocol::PROTOCOL_VERSION + 1, protocol::CAP_GDL90 | protocol::CAP_COMPRESSION);
+        assert!(send_message(&mut client, &mut application, &hello).unwrap().is_none());
+        assert_eq!(recv_message(&mut application), [0x02, protocol::PROTOCOL_VERSION, 0, 0, 0, 0x01]);
+        assert_eq!(client.capabilities, protocol::CAP_GDL90);
+        assert!(send_message(&mut client, &mut application, &position_msg(45_000_000, 5_000_000)).unwrap().is_some());
+
+        // A client without a supported output format is informed before being disconnected
+        let (mut client, mut application) = connect_client();
+        assert!(send_message(&mut client, &mut application, &hello_msg(1, protocol::CAP_COMPRESSION)).is_err());
+        assert_eq!(recv_message(&mut application), [0x02, 1, 0, 0, 0, 0]);
+    }
+
+
+    #[test]
+    fn hello_only_first() {
+        let (mut client, mut application) = connect_client();
+        send_message(&mut client, &mut application, &position_msg(45_000_000, 5_000_000)).unwrap();
+        assert!(send_message(&mut client, &mut application, &hello_msg(1, protocol::CAP_GDL90)).is_err());
+
+        // An unknown version is refused
+        let (mut client, mut application) = connect_client();
+        assert!(send_message(&mut client, &mut application, &hello_msg(0, protocol::CAP_GDL90)).is_err());
+    }
+
+
+    /// Fan-out of traffic updates to clients all close to the traffic, without the sending to the clients:
+    /// encoding of the GDL90 report for each client, as done before, against encoding once and sharing
+    /// the report between the clients
+    /// Run with `cargo test --release -- --ignored --nocapture bench_fan_out`
+    #[test]
+    #[ignore]
+    fn bench_fan_out() {
+        let traffic_infos = TrafficInfos {
+            address: 0x4ca2d6,
+            callsign: String::from("RYR123"),
+            altitude: 35_000,
+            latitude: 46.4,
+            longitude: -1.4,
+            track: Some(90),
+            ground_speed: Some(450),
+            vertical_speed: Some(0),
+            ..Default::default()
+        };
+
+        // Encoding for each client, in the buffer of the client
+        let start = Instant::now();
+        for _ in 0..NB_UPDATES {
+            for _ in 0..NB_CLIENTS {
+                let mut buffer = [0u8; 100];
+                let len = gdl90::make_traffic_report_message(black_box(&traffic_infos), &mut buffer).unwrap();
+                black_box(&buffer[..len]);
+            }
+        }
+        let per_client = start.elapsed();
+
+        // Encoding once, each client getting a reference to the shared report
+        let start = Instant::now();
+        for _ in 0..NB_UPDATES {
+            let traffic = EncodedTraffic::new(black_box(&traffic_infos).clone());
+            for _ in 0..NB_CLIENTS {
+                black_box(Arc::clone(&traffic.report));
+            }
+        }
+        let once = start.elapsed();
+
+        println!("{} updates for {} clients : {:?} when encoding for each client, {:?} when encoding once ({:.1}% saved)",
+            NB_UPDATES, NB_CLIENTS, per_client, once, 100.0 * (1.0 - once.as_secs_f64() / per_client.as_secs_f64()));
+    }
+}
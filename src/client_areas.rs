@@ -0,0 +1,232 @@
+//! Areas of interest of the connected clients, registered by the pools so that the OGN source only
+//! requests the traffic around the clients. The areas are recorded as the cells of `CELL_SIZE` degrees
+//! they cover, then merged into a limited number of boxes when the source needs them.
+//!
+
+use crate::{config::Region, geo::{self, Cell}};
+
+use std::{collections::HashMap, net::SocketAddr, sync::{Arc, Mutex}};
+
+
+// Size of the cells in degrees, in latitude and in longitude
+const CELL_SIZE: f64 = 1.0;
+
+// Number of boxes above which they are enlarged to a coarser grid, before merging them two by two
+const MAX_BOXES_TO_PAIR: usize = 64;
+
+
+pub type SharedClientAreas = Arc<Mutex<ClientAreas>>;
+
+
+#[derive(Default)]
+pub struct ClientAreas {
+    clients: HashMap<SocketAddr, Vec<Cell>>,    // Cells covered by the area of each client
+    nb_clients_by_cell: HashMap<Cell, usize>,
+}
+
+
+impl ClientAreas {
+    pub fn new_shared() -> SharedClientAreas {
+        Arc::new(Mutex::new(Self::default()))
+    }
+
+
+    /// Registers the area within the radius around the position of a client, in place of its previous one
+    pub fn set(&mut self, client: SocketAddr, latitude: f64, longitude: f64, radius_km: f64) {
+        let cells = geo::covered_cells(latitude, longitude, radius_km, CELL_SIZE);
+        if self.clients.get(&client) == Some(&cells) {
+            return;
+        }
+
+        self.remove(client);
+        for cell in &cells {
+            *self.nb_clients_by_cell.entry(*cell).or_default() += 1;
+        }
+        self.clients.insert(client, cells);
+    }
+
+
+    /// Unregisters the area of a client
+    pub fn remove(&mut self, client: SocketAddr) {
+        for cell in self.clients.remove(&client).into_iter().flatten() {
+            let nb_clients = self.nb_clients_by_cell.get_mut(&cell).unwrap();
+            *nb_clients -= 1;
+            if *nb_clients == 0 {
+                self.nb_clients_by_cell.remove(&cell);
+            }
+        }
+    }
+
+
+    /// Get the boxes of the cells covered by the areas of the clients, to be merged with merge_boxes
+    /// without keeping the areas locked
+    pub fn boxes(&self) -> Vec<Region> {
+        let nb_columns = (360.0 / CELL_SIZE).round() as i32;
+        self.nb_clients_by_cell.keys().map(|&(row, column)| {
+            // The columns begin at the Greenwich meridian
+            let column = if column >= nb_columns / 2 { column - nb_columns } else { column };
+            Region {
+                north: f64::from(row + 1) * CELL_SIZE,
+                south: f64::from(row) * CELL_SIZE,
+                east: f64::from(column + 1) * CELL_SIZE,
+                west: f64::from(column) * CELL_SIZE,
+            }
+        }).collect()
+    }
+}
+
+
+/// Merges boxes into at most max_boxes boxes covering them
+pub fn merge_boxes(mut boxes: Vec<Region>, max_boxes: usize) -> Vec<Region> {
+    // The same areas must give the same boxes, whatever their order
+    sort(&mut boxes);
+
+    // The boxes which overlap or touch each other are merged, on a coarser grid while they are too numerous
+    let mut grid_size = CELL_SIZE;
+    loop {
+        boxes = merge_overlapping(boxes);
+        if boxes.len() <= max_boxes.max(MAX_BOXES_TO_PAIR) {
+            break;
+        }
+        grid_size *= 2.0;
+        boxes = boxes.iter().map(|region| snapped(region, grid_size)).collect();
+    }
+
+    // Then the two boxes whose merge adds the smallest area are merged, until there are few enough boxes
+    while boxes.len() > max_boxes {
+        let (i, j) = (0..boxes.len())
+            .flat_map(|i| (i + 1..boxes.len()).map(move |j| (i, j)))
+            .min_by(|&(i1, j1), &(i2, j2)| added_area(&boxes[i1], &boxes[j1]).total_cmp(&added_area(&boxes[i2], &boxes[j2])))
+            .unwrap();
+        let merged = union(&boxes[i], &boxes[j]);
+        boxes.swap_remove(j);       // j > i, the box i is not moved
+        boxes.swap_remove(i);
+        boxes.push(merged);
+        boxes = merge_overlapping(boxes);
+    }
+    sort(&mut boxes);
+    boxes
+}
+
+
+// Merges the boxes which overlap or touch each other, until no box overlaps another one
+fn merge_overlapping(boxes: Vec<Region>) -> Vec<Region> {
+    let mut merged: Vec<Region> = Vec::with_capacity(boxes.len());
+    for mut region in boxes {
+        while let Some(i) = merged.iter().position(|other| overlap(&region, other)) {
+            region = union(&region, &merged.swap_remove(i));
+        }
+        merged.push(region);
+    }
+    merged
+}
+
+
+fn sort(boxes: &mut [Region]) {
+    boxes.sort_by(|region1, region2| region1.south.total_cmp(&region2.south).then(region1.west.total_cmp(&region2.west)));
+}
+
+
+fn overlap(region1: &Region, region2: &Region) -> bool {
+    region1.south <= region2.north && region2.south <= region1.north &&
+        region1.west <= region2.east && region2.west <= region1.east
+}
+
+
+fn union(region1: &Region, region2: &Region) -> Region {
+    Region {
+        north: region1.north.max(region2.north),
+        south: region1.south.min(region2.south),
+        east: region1.east.max(region2.east),
+        west: region1.west.min(region2.west),
+    }
+}
+
+
+// Area in square degrees
+fn area(region: &Region) -> f64 {
+    (region.north - region.south) * (region.east - region.west)
+}
+
+
+// Area covered by the merge of two boxes and not by the boxes themselves, in square degrees
+fn added_area(region1: &Region, region2: &Region) -> f64 {
+    area(&union(region1, region2)) - area(region1) - area(region2)
+}
+
+
+// Box enlarged to the lines of a grid of the given size in degrees
+fn snapped(region: &Region, grid_size: f64) -> Region {
+    Region {
+        north: ((region.north / grid_size).ceil() * grid_size).min(90.0),
+        south: ((region.south / grid_size).floor() * grid_size).max(-90.0),
+        east: ((region.east / grid_size).ceil() * grid_size).min(180.0),
+        west: ((region.west / grid_size).floor() * grid_size).max(-180.0),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RADIUS: f64 = 100.0;      // In km
+
+
+    fn client(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+
+    fn contains(outer: &Region, inner: &Region) -> bool {
+        outer.north >= inner.north && outer.south <= inner.south && outer.east >= inner.east && outer.west <= inner.west
+    }
+
+
+    #[test]
+    fn clients_of_the_same_area_give_one_box() {
+        let mut areas = ClientAreas::default();
+        areas.set(client(1), 45.9, 6.1, RADIUS);
+        areas.set(client(2), 46.2, 6.9, RADIUS);
+        let boxes = merge_boxes(areas.boxes(), 4);
+        assert_eq!(boxes.len(), 1);
+        assert!(contains(&boxes[0], &Region { north: 46.2, south: 45.9, east: 6.9, west: 6.1 }));
+
+        // The cells of a client are forgotten when it moves or leaves
+        areas.set(client(1), 40.4, -3.7, RADIUS);
+        assert_eq!(merge_boxes(areas.boxes(), 4).len(), 2);
+        areas.remove(client(1));
+        areas.remove(client(2));
+        assert!(areas.boxes().is_empty());
+        assert!(areas.nb_clients_by_cell.is_empty());
+    }
+
+
+    #[test]
+    fn area_across_the_antimeridian() {
+        let mut areas = ClientAreas::default();
+        areas.set(client(1), -17.7, 179.9, RADIUS);
+        let boxes = merge_boxes(areas.boxes(), 4);
+        assert_eq!(boxes.len(), 2);
+        assert!(boxes.iter().any(|region| region.east == 180.0 && region.west < 179.9));
+        assert!(boxes.iter().any(|region| region.west == -180.0 && region.east >= -179.0));
+    }
+
+
+    #[test]
+    fn boxes_limited_and_covering_all_the_clients() {
+        // Clients spread over the world, whose areas do not overlap
+        let mut areas = ClientAreas::default();
+        let positions: Vec<_> = (0..500).map(|i| (f64::from(i % 25) * 6.0 - 72.0, f64::from(i / 25) * 17.0 - 170.0)).collect();
+        for (port, (latitude, longitude)) in positions.iter().enumerate() {
+            areas.set(client(port as u16), *latitude, *longitude, RADIUS);
+        }
+        let cell_boxes = areas.boxes();
+
+        for max_boxes in [1, 3, 8] {
+            let boxes = merge_boxes(cell_boxes.clone(), max_boxes);
+            assert!(boxes.len() <= max_boxes);
+            assert!(cell_boxes.iter().all(|cell| boxes.iter().any(|region| contains(region, cell))));
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! Spatial index of the clients of a pool
+//! The earth is divided into cells of `CELL_SIZE` degrees. Each client is registered in all the
+//! cells covered by its proximity area, so that a traffic report is only tested against the
+//! clients registered in the cell of the aircraft.
+//!
+
+use crate::geo::{self, Cell};
+
+use std::collections::HashMap;
+
+
+// Size of the cells in degrees, in latitude and in longitude
+const CELL_SIZE: f64 = 1.0;
+
+
+
+#[derive(Default)]
+pub struct ClientGrid {
+    cells: HashMap<Cell, Vec<usize>>,       // Index of the clients registered in each cell
+    client_cells: Vec<Vec<Cell>>,           // Cells of each client, by client index
+}
+
+
+impl ClientGrid {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+
+    /// Registers a client in the cells covered by the area within the radius around its position,
+    /// in place of its previous ones
+    pub fn update(&mut self, client_index: usize, latitude: f64, longitude: f64, radius_km: f64) {
+        let cells = geo::covered_cells(latitude, longitude, radius_km, CELL_SIZE);
+        if self.client_cells.get(client_index) == Some(&cells) {
+            return;
+        }
+
+        self.remove(client_index);
+        for cell in &cells {
+            self.cells.entry(*cell).or_default().push(client_index);
+        }
+        self.client_cells[client_index] = cells;
+    }
+
+
+    /// Unregisters a client from all its cells
+    pub fn remove(&mut self, client_index: usize) {
+        if client_index >= self.client_cells.len() {
+            self.client_cells.resize_with(client_index + 1, Vec::new);
+        }
+
+        for cell in self.client_cells[client_index].drain(..) {
+            if let Some(clients) = self.cells.get_mut(&cell) {
+                clients.retain(|&i| i != client_index);
+                if clients.is_empty() {
+                    self.cells.remove(&cell);
+                }
+            }
+        }
+    }
+
+
+    /// Get the clients whose area may contain the position
+    pub fn clients_near(&self, latitude: f64, longitude: f64) -> &[usize] {
+        self.cells.get(&geo::cell_of(latitude, longitude, CELL_SIZE)).map_or(&[], Vec::as_slice)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    const RADIUS: f64 = 100.0;      // In km
+
+
+    // Pseudo-random positions, reproducible from one run to another
+    fn positions(nb: usize, seed: u64, latitudes: (f64, f64), longitudes: (f64, f64)) -> Vec<(f64, f64)> {
+        let mut state = seed;
+        let mut next = move || {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 11) as f64 / (1u64 << 53) as f64
+        };
+        (0..nb).map(|_| {
+            let latitude = latitudes.0 + next() * (latitudes.1 - latitudes.0);
+            let longitude = longitudes.0 + next() * (longitudes.1 - longitudes.0);
+            (latitude, (longitude + 180.0).rem_euclid(360.0) - 180.0)
+        }).collect()
+    }
+
+
+    // Clients close to a traffic, by testing all of them
+    fn close_clients(clients: &[(f64, f64)], latitude: f64, longitude: f64) -> Vec<usize> {
+        (0..clients.len())
+            .filter(|&i| geo::distance_km(clients[i].0, clients[i].1, latitude, longitude) <= RADIUS)
+            .collect()
+    }
+
+
+    // Clients close to a traffic, by testing those of its cell
+    fn close_clients_in_grid(grid: &ClientGrid, clients: &[(f64, f64)], latitude: f64, longitude: f64) -> Vec<usize> {
+        let mut close: Vec<_> = grid.clients_near(latitude, longitude).iter().copied()
+            .filter(|&i| geo::distance_km(clients[i].0, clients[i].1, latitude, longitude) <= RADIUS)
+            .collect();
+        close.sort();
+        close
+    }
+
+
+    fn grid_of(clients: &[(f64, f64)]) -> ClientGrid {
+        let mut grid = ClientGrid::new();
+        for (i, (latitude, longitude)) in clients.iter().enumerate() {
+            grid.update(i, *latitude, *longitude, RADIUS);
+        }
+        grid
+    }
+
+
+    #[test]
+    fn grid_finds_all_close_clients() {
+        // Around the antimeridian and the poles, where the cells are the smallest
+        for (latitudes, longitudes) in [((-10.0, 10.0), (170.0, 190.0)), ((80.0, 90.0), (-180.0, 180.0)), ((-90.0, -85.0), (-180.0, 180.0))] {
+            let clients = positions(500, 1, latitudes, longitudes);
+            let grid = grid_of(&clients);
+            for (latitude, longitude) in positions(500, 2, latitudes, longitudes) {
+                assert_eq!(close_clients_in_grid(&grid, &clients, latitude, longitude), close_clients(&clients, latitude, longitude));
+            }
+        }
+    }
+
+
+    #[test]
+    fn moved_and_removed_clients() {
+        let mut grid = ClientGrid::new();
+        grid.update(0, 46.4, -1.4, RADIUS);
+        grid.update(1, 46.4, -1.4, RADIUS);
+        assert_eq!(grid.clients_near(46.4, -1.4), &[0, 1]);
+
+        grid.update(0, 43.6, 1.4, RADIUS);
+        assert_eq!(grid.clients_near(46.4, -1.4), &[1]);
+        assert_eq!(grid.clients_near(43.6, 1.4), &[0]);
+
+        grid.remove(0);
+        grid.remove(1);
+        assert!(grid.clients_near(43.6, 1.4).is_empty());
+        assert!(grid.cells.is_empty());
+    }
+
+
+    /// Matching of traffic reports with clients spread over Europe, by testing all the clients
+    /// against testing those of the cell of the aircraft
+    /// Run with `cargo test --release -- --ignored --nocapture bench_client_grid`
+    #[test]
+    #[ignore]
+    fn bench_client_grid() {
+        const NB_CLIENTS: usize = 10_000;
+        const NB_TRAFFIC: usize = 10_000;
+        let europe = ((36.0, 60.0), (-10.0, 25.0));
+        let clients = positions(NB_CLIENTS, 1, europe.0, europe.1);
+        let traffic = positions(NB_TRAFFIC, 2, europe.0, europe.1);
+
+        let start = Instant::now();
+        let grid = grid_of(&clients);
+        let build = start.elapsed();
+
+        let start = Instant::now();
+        let nb_all: usize = traffic.iter().map(|(latitude, longitude)| close_clients(&clients, *latitude, *longitude).len()).sum();
+        let all = start.elapsed();
+
+        let start = Instant::now();
+        let nb_grid: usize = traffic.iter().map(|(latitude, longitude)| close_clients_in_grid(&grid, &clients, *latitude, *longitude).len()).sum();
+        let in_grid = start.elapsed();
+
+        assert_eq!(nb_all, nb_grid);
+        println!("{} traffic reports matched with {} clients ({} matches) : {:?} testing all the clients, {:?} with the grid ({:?} to build it, x{:.0} faster)",
+            NB_TRAFFIC, NB_CLIENTS, nb_all, all, in_grid, build, all.as_secs_f64() / in_grid.as_secs_f64());
+    }
+}
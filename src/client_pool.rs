@@ -1,17 +1,22 @@
-use crate::{client, internal_com};
+use crate::{client, client_areas::SharedClientAreas, client_grid::ClientGrid, config::{Config, Placement, SharedConfig}, gdl90, internal_com, placement::{self, PoolRegions, RegionChanges, RegionMap}, protocol::RejectReason, traffic_infos::Source, traffic_store::SharedTrafficStore};
 
-use nix::sys::epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags};
-use std::{net::TcpStream, os::fd::AsFd, sync::{atomic::{AtomicUsize, Ordering}, mpsc, Arc}, thread};
-
-
-// Maximum number of clients connected at the same time for the pool
-const CLIENTS_MAX_NB: usize = 200;
+use nix::sys::{epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags}, eventfd::{EfdFlags, EventFd}};
+use socket2::{Domain, Socket, Type};
+use std::{net::{SocketAddr, TcpListener}, os::fd::AsFd, sync::{atomic::{AtomicUsize, Ordering}, mpsc, Arc}, thread, time::{Duration, Instant, SystemTime}};
 
 
 // Event identifier for use in epoll data field
 
 const EVENT_TYPE_CLIENT: u32 = 1;
 const EVENT_TYPE_TRAFFIC_RECV: u32 = 2;
+const EVENT_TYPE_LISTENER: u32 = 3;
+const EVENT_TYPE_COMMAND: u32 = 4;
+
+// Maximum number of connections waiting to be accepted by a pool
+const LISTEN_BACKLOG: i32 = 1024;
+
+// Period of the GDL90 heartbeat sent to the clients
+const HEARTBEAT_PERIOD: Duration = Duration::from_secs(1);
 
 struct EventId(u64);
 
@@ -42,94 +47,319 @@ impl From<EventId> for u64 {
 }
 
 
+// Commands sent to the pool thread
+enum Command {
+    NewClient(Box<client::Client>),         // New client sent by a full pool, already counted in this one
+    MigratedClient(Box<client::Client>),    // Client moved from another pool, already counted in this one
+    StopAccepting,
+    Shutdown(Instant),      // Deadline to close the clients
+}
+
+
+// Sending of commands to a pool thread, which is woken up through an eventfd registered in its epoll
+#[derive(Clone)]
+struct CommandSender {
+    tx: mpsc::Sender<Command>,
+    wakeup: Arc<EventFd>,
+}
+
+impl CommandSender {
+    fn send(&self, command: Command) -> Result<(), mpsc::SendError<Command>> {
+        self.tx.send(command)?;
+        self.wakeup.arm().unwrap();
+        Ok(())
+    }
+}
+
+
+/// Statistics of a pool, given when the pool is stopped
+#[derive(Default, Debug)]
+pub struct PoolSummary {
+    pub nb_clients_accepted: usize,
+    pub nb_clients_refused: usize,
+    pub nb_clients_overflowed: usize,           // New clients sent to another pool because this one was full
+    pub nb_clients_migrated: usize,             // Moved to another pool serving their region
+    pub nb_traffic_received: usize,
+    pub nb_traffic_sent: usize,
+    pub nb_clients_closed_cleanly: usize,       // During the shutdown
+    pub nb_clients_closed_forcibly: usize,      // During the shutdown, when the deadline is reached
+    pub nb_traffic_dropped: u64,                // Because the clients were too slow
+    pub nb_slow_clients: usize,                 // Clients whose send queue was full at least once
+}
+
+
 pub struct ClientPool {
-    new_client_tx: mpsc::SyncSender<TcpStream>,
-    nb_clients: Arc<AtomicUsize>
+    command_tx: CommandSender,
+    thread: thread::JoinHandle<PoolSummary>,
 }
 
 
 impl ClientPool {
-    /// Creation of the client pool
-    pub fn new() -> Self {
-        // Creation of the channel to receive new clients
-        let (new_client_tx, new_client_rx) = mpsc::sync_channel(0);
-
-        // Initialization of the current number of clients
-        let nb_clients = Arc::new(AtomicUsize::new(0));
-        let nb_clients_thread = nb_clients.clone();
+    /// Creation of the client pools, each one accepting the client connections on its own listener
+    /// nb_server_clients is the current number of clients of the whole server
+    /// The areas of the clients are registered in client_areas
+    pub fn new_pools(nb_pools: usize, config: &SharedConfig, traffic_store: &SharedTrafficStore, client_areas: &SharedClientAreas,
+        nb_server_clients: Arc<AtomicUsize>) -> Vec<Self> {
+        // Creation of the channels to receive commands (clients moved from another pool, shutdown)
+        // They are not bounded so that the pools never wait for each other
+        let (command_txs, command_rxs): (Vec<_>, Vec<_>) = (0..nb_pools).map(|_| {
+            let (tx, rx) = mpsc::channel();
+            let wakeup = Arc::new(EventFd::from_flags(EfdFlags::EFD_NONBLOCK | EfdFlags::EFD_CLOEXEC).unwrap());
+            (CommandSender {tx, wakeup: wakeup.clone()}, (rx, wakeup))
+        }).unzip();
+
+        // Initialization of the current number of clients of each pool
+        let nb_clients: Vec<_> = (0..nb_pools).map(|_| Arc::new(AtomicUsize::new(0))).collect();
+
+        // With the regional placement, the pools share the regions they serve
+        let config_snapshot = config.get();
+        let placement = match config_snapshot.server.placement {
+            Placement::Balanced => None,
+            Placement::Regional => Some(Arc::new(RegionMap::new(config_snapshot.server.region_size, nb_clients.clone()))),
+        };
+
+        // Creation of the threads that will handle the client pools
+        command_rxs.into_iter().enumerate().map(|(pool_index, command_rx)| {
+            let worker = Worker::new(pool_index, config, traffic_store, client_areas, command_rx, command_txs.clone(), placement.clone(),
+                nb_clients.clone(), nb_server_clients.clone());
+            let thread = thread::spawn(move || {
+                worker.work_thread()
+            });
+
+            Self {command_tx: command_txs[pool_index].clone(), thread}
+        }).collect()
+    }
 
-        // Creation of the thread that will handle the client pool
-        thread::spawn(|| {
-            Self::work_thread(new_client_rx, nb_clients_thread);
-        });
 
-        Self {new_client_tx, nb_clients}
+    /// Asks the pool to close its listener, the connected clients are kept
+    pub fn stop_accepting(&self) {
+        self.command_tx.send(Command::StopAccepting).unwrap();
     }
 
 
-    /// Add a new client to the pool
-    pub fn add_new_client(&self, socket: TcpStream) {
-        self.new_client_tx.send(socket).unwrap();
+    /// Asks the pool to send the pending traffic and to close its clients before the deadline
+    /// Use join to wait for the end of the pool
+    pub fn shutdown(&self, deadline: Instant) {
+        self.command_tx.send(Command::Shutdown(deadline)).unwrap();
     }
 
 
-    /// Get current number of clients in the pool
-    pub fn get_nb_clients(&self) -> usize {
-        self.nb_clients.load(Ordering::Relaxed)
+    /// Waits for the end of the pool and returns its statistics
+    pub fn join(self) -> PoolSummary {
+        self.thread.join().unwrap()
     }
 
+}
 
-    fn work_thread(new_client_rx: mpsc::Receiver<TcpStream>, nb_clients: Arc<AtomicUsize>) {
-        // Clients list
-        let mut clients = Vec::new();
-        let mut free_clients = Vec::new();      // Index of free clients (None) in clients Vec
-        let mut clients_to_delete = Vec::new(); // Index of clients to delete in clients Vec
+
+// State of the pool, owned by the pool thread
+struct Worker {
+    pool_index: usize,
+    shared_config: SharedConfig,
+    config: Arc<Config>,                // Configuration currently applied
+    traffic_store: SharedTrafficStore,
+    client_areas: SharedClientAreas,     // Areas of the clients of all the pools
+    command_rx: mpsc::Receiver<Command>,
+    command_wakeup: Arc<EventFd>,       // Notification of the commands
+    pool_commands: Vec<CommandSender>,  // Commands of all the pools, to move clients between them
+    pool_nb_clients: Vec<Arc<AtomicUsize>>, // Current number of clients of all the pools
+    listener: Option<TcpListener>,      // Closed when the server stops
+    placement: Option<Arc<RegionMap>>,  // Only with the regional placement
+    pool_regions: PoolRegions,          // Regions of the clients, with the regional placement
+    epoll: Epoll,
+    traffic_recv: internal_com::Receiver,
+    clients: Vec<Option<client::Client>>,
+    free_clients: Vec<usize>,           // Index of free clients (None) in clients Vec
+    clients_to_delete: Vec<usize>,      // Index of clients to delete in clients Vec
+    client_grid: ClientGrid,            // Index of the clients by the cells covered by their area
+    nb_clients: Arc<AtomicUsize>,
+    nb_server_clients: Arc<AtomicUsize>,
+    last_heartbeat: Instant,
+    last_device_id: Instant,
+    stopping: bool,                     // The clients are no longer moved to another pool
+    summary: PoolSummary,
+}
+
+
+impl Worker {
+    #[allow(clippy::too_many_arguments)]
+    fn new(pool_index: usize, shared_config: &SharedConfig, traffic_store: &SharedTrafficStore, client_areas: &SharedClientAreas, (command_rx, command_wakeup): (mpsc::Receiver<Command>, Arc<EventFd>),
+        pool_commands: Vec<CommandSender>, placement: Option<Arc<RegionMap>>, pool_nb_clients: Vec<Arc<AtomicUsize>>, nb_server_clients: Arc<AtomicUsize>) -> Self {
+        let config = shared_config.get();
 
         // Create the epoll instance
         let epoll = Epoll::new(EpollCreateFlags::empty()).unwrap();
 
+        // Create the listener of the pool and register it in epoll, the connections are distributed
+        // by the system between the listeners of all the pools
+        let listener = Self::listen(config.listen_socket_addr());
+        epoll.add(listener.as_fd(),
+            EpollEvent::new(EpollFlags::EPOLLIN,
+                EventId::new(EVENT_TYPE_LISTENER, 0).into())).unwrap();
+
+        // Register the notification of the commands in epoll
+        epoll.add(command_wakeup.as_fd(),
+            EpollEvent::new(EpollFlags::EPOLLIN,
+                EventId::new(EVENT_TYPE_COMMAND, 0).into())).unwrap();
+
         // Create the traffic receiver and register it in epoll
-        let traffic_recv = internal_com::Receiver::new(true /* nonblocking */);
+        let traffic_recv = internal_com::Receiver::new(config.multicast_socket_addr(), true /* nonblocking */);
         epoll.add(traffic_recv.as_fd(),
         EpollEvent::new(EpollFlags::EPOLLIN,
             EventId::new(EVENT_TYPE_TRAFFIC_RECV, 0).into())).unwrap();
 
+        // Without regional placement, the pool receives the traffic of all the regions
+        if placement.is_none() {
+            for group in 0..internal_com::NB_GROUPS {
+                traffic_recv.join_group(group);
+            }
+        }
+
+        Self {
+            pool_index,
+            shared_config: shared_config.clone(),
+            config,
+            traffic_store: traffic_store.clone(),
+            client_areas: client_areas.clone(),
+            command_rx,
+            command_wakeup,
+            pool_commands,
+            nb_clients: pool_nb_clients[pool_index].clone(),
+            pool_nb_clients,
+            listener: Some(listener),
+            placement,
+            pool_regions: PoolRegions::new(),
+            epoll,
+            traffic_recv,
+            clients: Vec::new(),
+            free_clients: Vec::new(),
+            clients_to_delete: Vec::new(),
+            client_grid: ClientGrid::new(),
+            nb_server_clients,
+            last_heartbeat: Instant::now(),
+            last_device_id: Instant::now(),
+            stopping: false,
+            summary: PoolSummary::default(),
+        }
+    }
+
+
+    fn work_thread(mut self) -> PoolSummary {
         let mut epoll_events = [EpollEvent::empty(); 100];
-        loop {
+        let deadline = loop {
             // Wait for events
-            let nb_events = epoll.wait(&mut epoll_events, 100u16 /* milliseconds */).unwrap();
+            let nb_events = self.epoll.wait(&mut epoll_events, 100u16 /* milliseconds */).unwrap();
 
             // Read the events
             for epoll_event in epoll_events.iter().take(nb_events) {
-                let event_id: EventId = epoll_event.data().into();
-                match event_id.event_type() {
-                    EVENT_TYPE_CLIENT => {
-                        // Process the client event
-                        let client_index = event_id.event_number() as usize;
-                        Self::process_client_event(client_index, &epoll, &mut clients, &mut free_clients, &nb_clients);
-                    }
+                self.process_event(epoll_event.data().into(), epoll_event.events());
+            }
 
-                    EVENT_TYPE_TRAFFIC_RECV => {
-                        // Process the traffic receiver event
-                        Self::process_traffic_event(&traffic_recv, &epoll, &mut clients, &mut free_clients, &mut clients_to_delete, &nb_clients);
-                    }
+            // Check if there are new clients or if the pool must stop
+            if let Some(deadline) = self.check_commands() {
+                break deadline;
+            }
+
+            // Check if the configuration has been reloaded
+            self.check_config();
 
-                    event_type => panic!("Unknown event type : {event_type}"),
+            // Check if the clients must receive a heartbeat or the identification of the server
+            self.check_heartbeat();
+            self.check_device_id();
+
+            // Wait for the sockets of the clients with pending messages to be writable
+            self.update_write_interest();
+        };
+
+        self.shutdown(deadline);
+        self.summary
+    }
+
+
+    fn process_event(&mut self, event_id: EventId, events: EpollFlags) {
+        match event_id.event_type() {
+            EVENT_TYPE_CLIENT => {
+                // Process the client event
+                let client_index = event_id.event_number() as usize;
+                if events.contains(EpollFlags::EPOLLOUT) {
+                    self.process_client_writable(client_index);
+                }
+                if events.intersects(EpollFlags::EPOLLIN | EpollFlags::EPOLLHUP | EpollFlags::EPOLLERR) {
+                    self.process_client_event(client_index);
                 }
             }
 
-            // Check if there are new clients
-            Self::check_new_client(&new_client_rx, &epoll, &mut clients, &mut free_clients, &nb_clients);
+            EVENT_TYPE_TRAFFIC_RECV => {
+                // Process the traffic receiver event
+                self.process_traffic_event();
+            }
+
+            EVENT_TYPE_LISTENER => {
+                // Process the new connections
+                self.accept_clients();
+            }
+
+            EVENT_TYPE_COMMAND => {
+                // Reset the notification, the commands are processed at the end of the loop
+                let _ = self.command_wakeup.read();
+            }
+
+            event_type => panic!("Unknown event type : {event_type}"),
         }
     }
 
 
-    fn process_client_event(client_index: usize, epoll: &Epoll, clients: &mut [Option<client::Client>], free_clients: &mut Vec<usize>, nb_clients: &Arc<AtomicUsize>) {
-        if let Some(client) = &mut clients[client_index] {
+    // Creates a listener sharing the address with the listeners of the other pools
+    fn listen(listen_addr: SocketAddr) -> TcpListener {
+        // We use the socket2 crate because TcpListener does not allow setting the SO_REUSEPORT option
+        let sock = Socket::new(Domain::for_address(listen_addr), Type::STREAM, None).unwrap();
+        sock.set_reuse_address(true).unwrap();
+        sock.set_reuse_port(true).unwrap();
+        sock.bind(&listen_addr.into()).unwrap();
+        sock.listen(LISTEN_BACKLOG).unwrap();
+        sock.set_nonblocking(true).unwrap();
+        sock.into()
+    }
+
+
+    fn accept_clients(&mut self) {
+        // Accept all the pending connections
+        while let Some(listener) = &self.listener {
+            match listener.accept() {
+                Ok((socket, _)) => {
+                    socket.set_nonblocking(true).unwrap();  // We can't block all clients because of one blocking client
+                    self.add_client(client::Client::new(socket, &self.config.client));
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    log::warn!("Accept error : {}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+
+    fn stop_accepting(&mut self) {
+        // The pending connections are reset when the listener is closed
+        if let Some(listener) = self.listener.take() {
+            self.epoll.delete(listener.as_fd()).unwrap();
+        }
+    }
+
+
+    fn process_client_event(&mut self, client_index: usize) {
+        if let Some(client) = &mut self.clients[client_index] {
             match client.recv_position() {
                 Ok(Some(position)) => {
                     log::info!("New position received ({}, {}) from client {}",
                         position.latitude, position.longitude, client.address());
+
+                    // Send the known traffic around the new position without waiting for the sources
+                    if client.snapshot_needed() {
+                        self.send_snapshot(client_index);
+                    }
+                    self.update_client_area(client_index);
                 }
                 Ok(None) => {
                     // Nothing to do
@@ -137,20 +367,55 @@ impl ClientPool {
                 Err(e) => {
                     // Error while receiving the client position
                     log::warn!("Receive error ({}) from client {}", e, client.address());
-                    Self::delete_client(client_index, epoll, clients, free_clients, nb_clients);
+                    self.delete_client(client_index);
                 }
             }
         }
     }
 
 
-    fn process_traffic_event(traffic_recv: &internal_com::Receiver, epoll: &Epoll,
-        clients: &mut [Option<client::Client>], free_clients: &mut Vec<usize>, clients_to_delete: &mut Vec<usize>,
-        nb_clients: &Arc<AtomicUsize>) {
+    fn process_client_writable(&mut self, client_index: usize) {
+        if let Some(client) = &mut self.clients[client_index] {
+            if let Err(e) = client.flush() {
+                log::warn!("Send error ({}) to client {}", e, client.address());
+                self.delete_client(client_index);
+            }
+        }
+    }
+
+
+    fn send_snapshot(&mut self, client_index: usize) {
+        let client = self.clients[client_index].as_mut().unwrap();
+
+        // Copy the nearby traffic so as not to keep the table locked while sending
+        let max_age = Duration::from_secs(self.config.traffic.max_age);
+        let nearby_traffic: Vec<_> = self.traffic_store.lock().unwrap().iter(max_age)
+            .filter(|entry| client.traffic_close(&entry.traffic.infos))
+            .map(|entry| entry.traffic.clone())
+            .collect();
+
+        // The client filter may limit the number of aircraft sent
+        let mut nb_sent = 0;
+        for traffic in &nearby_traffic {
+            match client.send_traffic(traffic) {
+                Ok(true) => nb_sent += 1,
+                Ok(false) => (),
+                Err(e) => {
+                    log::warn!("Send error ({}) to client {}", e, client.address());
+                    self.delete_client(client_index);
+                    return;
+                }
+            }
+        }
+        self.summary.nb_traffic_sent += nb_sent;
+        log::info!("Snapshot of {} aircraft sent to client {}", nb_sent, client.address());
+    }
+
 
+    fn process_traffic_event(&mut self) {
         // Loop until there is no more traffic information to receive, to optimize the number of epoll.wait calls
         loop {
-            match traffic_recv.recv() {
+            match self.traffic_recv.recv() {
                 Err(e) => {
                     // Exit the loop if an error occurs
                     // A WouldBlock error is normal because we are in non-blocking mode
@@ -161,22 +426,29 @@ impl ClientPool {
                     }
                     break;
                 }
-    
-                Ok(infos) => {
-                    // Send the traffic information to all clients
-                    for (i, client_opt) in clients.iter().enumerate() {
-                        if let Some(client) = client_opt {
-                            if let Err(e) = client.send_traffic(&infos) {
-                                log::warn!("Send error ({}) to client {}", e, client.address());
-                                // Add the client to the delete list
-                                clients_to_delete.push(i);
+
+                Ok(traffic) => {
+                    self.summary.nb_traffic_received += 1;
+
+                    // Send the traffic information to the clients whose area may contain the aircraft
+                    // A target leaving this area is forgotten by the client after its timeout
+                    for &i in self.client_grid.clients_near(traffic.infos.latitude, traffic.infos.longitude) {
+                        if let Some(client) = &mut self.clients[i] {
+                            match client.send_traffic(&traffic) {
+                                Ok(true) => self.summary.nb_traffic_sent += 1,
+                                Ok(false) => (),
+                                Err(e) => {
+                                    log::warn!("Send error ({}) to client {}", e, client.address());
+                                    // Add the client to the delete list
+                                    self.clients_to_delete.push(i);
+                                }
                             }
                         }
                     }
-    
+
                     // Delete clients that must be deleted
-                    while let Some(i) = clients_to_delete.pop() {
-                        Self::delete_client(i, epoll, clients, free_clients, nb_clients);
+                    while let Some(i) = self.clients_to_delete.pop() {
+                        self.delete_client(i);
                     }
                 }
             }
@@ -184,65 +456,395 @@ impl ClientPool {
     }
 
 
-    fn add_client(client: client::Client, epoll: &Epoll, clients: &mut Vec<Option<client::Client>>, free_clients: &mut Vec<usize>, nb_clients: &Arc<AtomicUsize>) {
-        // If the maximum number of clients is reached, we refuse the new client
-        let current_nb_clients = nb_clients.fetch_add(1, Ordering::Relaxed);
-        if current_nb_clients >= CLIENTS_MAX_NB {
-            nb_clients.fetch_sub(1, Ordering::Relaxed);
-            log::warn!("Unable to connect new client {} : maximum number of clients ({}) for the pool is reached", client.address(), CLIENTS_MAX_NB);
+    fn add_client(&mut self, client: client::Client) {
+        // If the maximum number of clients of the server is reached, we refuse the new client
+        let max_clients = self.config.server.max_clients;
+        if !placement::reserve(&self.nb_server_clients, max_clients.unwrap_or(usize::MAX)) {
+            log::warn!("Unable to connect new client {} : maximum number of clients ({}) for the server is reached", client.address(), max_clients.unwrap());
+            self.reject_client(client, RejectReason::ServerFull);
+            return;
+        }
+
+        // The client is placed in this pool, or in the least populated pool which is not full
+        let pool_max_clients = self.config.server.pool_max_clients;
+        if placement::reserve(&self.nb_clients, pool_max_clients) {
+            self.accept_client(client);
+        }
+        else if let Some(pool) = self.reserve_other_pool() {
+            self.overflow_client(client, pool);
         }
         else {
-            log::info!("New client connected : {}, {}th in the pool", client.address(), current_nb_clients + 1);
+            self.nb_server_clients.fetch_sub(1, Ordering::Relaxed);
+            log::warn!("Unable to connect new client {} : maximum number of clients ({}) is reached in all the pools", client.address(), pool_max_clients);
+            self.reject_client(client, RejectReason::PoolsFull);
+        }
+    }
 
-            // Add the new client to the list
-            let client_index;
 
-            // If there are free clients, we reuse one
-            if let Some(i) = free_clients.pop() {
-                assert!(clients[i].is_none());
-                clients[i] = Some(client);
-                client_index = i;
-            }
-            // Otherwise we add a new client
-            else {
-                clients.push(Some(client));
-                client_index = clients.len() - 1;
-            }
-    
-            // Register the event in epoll
-            epoll.add(clients[client_index].as_ref().unwrap().as_fd(),
-                EpollEvent::new(EpollFlags::EPOLLIN,
-                    EventId::new(EVENT_TYPE_CLIENT, client_index.try_into().unwrap()).into())).unwrap();    
+    // Adds a new client, already counted in the pool, and identifies the server to it
+    fn accept_client(&mut self, client: client::Client) {
+        log::info!("New client connected : {}, {}th in the pool", client.address(), self.nb_clients.load(Ordering::Relaxed));
+        self.summary.nb_clients_accepted += 1;
+        let client_index = self.insert_client(client);
+
+        // The server identifies itself as soon as the client is connected
+        let client = self.clients[client_index].as_mut().unwrap();
+        if let Err(e) = client.send_device_id(&self.config.device) {
+            log::warn!("Send error ({}) to client {}", e, client.address());
+            self.delete_client(client_index);
+        }
+    }
+
+
+    // Counts a new client in the least populated of the other pools which are not full
+    // Returns the index of this pool
+    fn reserve_other_pool(&self) -> Option<usize> {
+        let mut pools: Vec<_> = (0..self.pool_nb_clients.len()).filter(|&pool| pool != self.pool_index).collect();
+        pools.sort_by_key(|&pool| self.pool_nb_clients[pool].load(Ordering::Relaxed));
+        pools.into_iter().find(|&pool| placement::reserve(&self.pool_nb_clients[pool], self.config.server.pool_max_clients))
+    }
+
+
+    // Sends a new client to another pool, in which it is already counted
+    fn overflow_client(&mut self, client: client::Client, pool: usize) {
+        log::info!("Pool full, new client {} sent to pool {}", client.address(), pool);
+        self.summary.nb_clients_overflowed += 1;
+        if let Err(mpsc::SendError(Command::NewClient(client))) = self.pool_commands[pool].send(Command::NewClient(Box::new(client))) {
+            // The other pool is already stopped
+            log::warn!("Unable to send client {} to pool {}, it is disconnected", client.address(), pool);
+            self.pool_nb_clients[pool].fetch_sub(1, Ordering::Relaxed);
+            self.nb_server_clients.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+
+    // Refuses a new client, which is informed of the reason and of the delay before connecting again
+    fn reject_client(&mut self, mut client: client::Client, reason: RejectReason) {
+        self.summary.nb_clients_refused += 1;
+        if let Err(e) = client.reject(reason, self.config.server.retry_delay) {
+            log::warn!("Send error ({}) to client {}", e, client.address());
         }
     }
 
 
-    fn delete_client(client_index: usize, epoll: &Epoll, clients: &mut [Option<client::Client>], free_clients: &mut Vec<usize>, nb_clients: &Arc<AtomicUsize>) {
-        let client = &mut clients[client_index];
+    // Adds a client to the list, already counted in the pool
+    // Returns the index of the client
+    fn insert_client(&mut self, mut client: client::Client) -> usize {
+        let client_index;
 
-        log::info!("Client {} is disconnected", client.as_ref().unwrap().address());
+        // If there are free clients, we reuse one
+        if let Some(i) = self.free_clients.pop() {
+            assert!(self.clients[i].is_none());
+            client_index = i;
+        }
+        // Otherwise we add a new client
+        else {
+            self.clients.push(None);
+            client_index = self.clients.len() - 1;
+        }
+
+        // Register the event in epoll, the write interest is added if needed at the end of the loop
+        client.reset_write_interest();
+        self.epoll.add(client.as_fd(),
+            EpollEvent::new(EpollFlags::EPOLLIN,
+                EventId::new(EVENT_TYPE_CLIENT, client_index.try_into().unwrap()).into())).unwrap();
 
-        // Unregister the event in epoll
-        epoll.delete(client.as_ref().unwrap().as_fd()).unwrap();
+        self.clients[client_index] = Some(client);
+        client_index
+    }
 
-        // Free the client
-        *client = None;
-        free_clients.push(client_index);
+
+    // Removes a client from the pool without closing its connection
+    fn take_client(&mut self, client_index: usize) -> client::Client {
+        let client = self.clients[client_index].take().unwrap();
+        self.free_clients.push(client_index);
+
+        // Unregister the event in epoll and the client from the grid, from the areas and from its region
+        self.epoll.delete(client.as_fd()).unwrap();
+        self.client_grid.remove(client_index);
+        self.client_areas.lock().unwrap().remove(client.address());
+        if self.placement.is_some() {
+            let changes = self.pool_regions.set_client(client_index, None);
+            self.apply_region_changes(changes);
+        }
 
         // Decrement the number of clients
-        nb_clients.fetch_sub(1, Ordering::Relaxed);
+        self.nb_clients.fetch_sub(1, Ordering::Relaxed);
+        client
+    }
+
+
+    fn delete_client(&mut self, client_index: usize) {
+        let client = self.take_client(client_index);
+        self.nb_server_clients.fetch_sub(1, Ordering::Relaxed);
+
+        log::info!("Client {} is disconnected", client.address());
+
+        // Statistics of the messages which could not be sent in time
+        let (nb_dropped, nb_full) = client.send_stats();
+        if nb_dropped > 0 {
+            log::info!("Client {} was too slow : {} traffic reports dropped, send queue full {} times",
+                client.address(), nb_dropped, nb_full);
+        }
+        self.summary.nb_traffic_dropped += nb_dropped;
+        if nb_full > 0 {
+            self.summary.nb_slow_clients += 1;
+        }
     }
 
 
-    fn check_new_client(new_client_rx: &mpsc::Receiver<TcpStream>, epoll: &Epoll,
-        clients: &mut Vec<Option<client::Client>>, free_clients: &mut Vec<usize>, nb_clients: &Arc<AtomicUsize>) {
+    // Updates the cells of a client after a change of its position or of its radius
+    // With the regional placement, the client is moved to another pool if this one does not serve its region
+    fn update_client_area(&mut self, client_index: usize) {
+        let Some(client) = &self.clients[client_index] else {
+            return;
+        };
+        let Some(position) = client.position() else {
+            return;
+        };
+        let (latitude, longitude, radius) = (position.latitude, position.longitude, client.radius());
+        self.client_grid.update(client_index, latitude, longitude, radius);
+        self.client_areas.lock().unwrap().set(client.address(), latitude, longitude, radius);
+
+        let Some(placement) = self.placement.clone() else {
+            return;
+        };
+        let region = placement.region_of(latitude, longitude);
+        let pool = if self.stopping {
+            self.pool_index
+        }
+        else {
+            placement.select_pool(region, self.pool_index, self.config.server.pool_max_clients)
+        };
+        if pool != self.pool_index {
+            self.migrate_client(client_index, pool);
+        }
+        else {
+            let groups = placement.covered_groups(latitude, longitude, radius);
+            let changes = self.pool_regions.set_client(client_index, Some((region, groups)));
+            self.apply_region_changes(changes);
+        }
+    }
 
-        // While there are new clients, we add them to the pool
-        while let Ok(socket) = new_client_rx.try_recv() {
-            socket.set_nonblocking(true).unwrap();  // We can't block all clients because of one blocking client
-            Self::add_client(client::Client::new(socket), epoll, clients, free_clients, nb_clients);
+
+    // Moves a client to another pool
+    fn migrate_client(&mut self, client_index: usize, pool: usize) {
+        let client = self.take_client(client_index);
+        log::info!("Client {} moved to pool {}", client.address(), pool);
+        self.summary.nb_clients_migrated += 1;
+
+        // The client has already been counted in its new pool by the placement
+        if let Err(mpsc::SendError(Command::MigratedClient(client))) = self.pool_commands[pool].send(Command::MigratedClient(Box::new(client))) {
+            // The other pool is already stopped
+            log::warn!("Unable to move client {} to pool {}, it is disconnected", client.address(), pool);
+            self.pool_nb_clients[pool].fetch_sub(1, Ordering::Relaxed);
+            self.nb_server_clients.fetch_sub(1, Ordering::Relaxed);
         }
     }
 
 
-}
\ No newline at end of file
+    // Subscribes to the traffic needed by the clients of the pool and releases the regions without clients
+    fn apply_region_changes(&self, changes: RegionChanges) {
+        if let Some(region) = changes.released_region {
+            self.placement.as_ref().unwrap().release(region, self.pool_index);
+        }
+        for group in placement::groups_of(changes.joined_groups) {
+            self.traffic_recv.join_group(group);
+        }
+        for group in placement::groups_of(changes.left_groups) {
+            self.traffic_recv.leave_group(group);
+        }
+    }
+
+
+    /// Processes the pending commands
+    /// Returns the deadline to close the clients if the pool must stop
+    fn check_commands(&mut self) -> Option<Instant> {
+        // While there are commands, we process them
+        while let Ok(command) = self.command_rx.try_recv() {
+            match command {
+                Command::NewClient(client) => self.accept_client(*client),
+                Command::MigratedClient(client) => {
+                    let client_index = self.insert_client(*client);
+                    self.update_client_area(client_index);
+                }
+                Command::StopAccepting => self.stop_accepting(),
+                Command::Shutdown(deadline) => return Some(deadline),
+            }
+        }
+        None
+    }
+
+
+    fn check_config(&mut self) {
+        let config = self.shared_config.get();
+        if !Arc::ptr_eq(&config, &self.config) {
+            // Apply the new configuration to all the clients, the pool limits are read when they are used
+            for (i, client_opt) in self.clients.iter_mut().enumerate() {
+                if let Some(client) = client_opt {
+                    if let Err(e) = client.set_config(&config.client) {
+                        log::warn!("Send error ({}) to client {}", e, client.address());
+                        self.clients_to_delete.push(i);
+                    }
+                }
+            }
+            while let Some(i) = self.clients_to_delete.pop() {
+                self.delete_client(i);
+            }
+            self.config = config;
+
+            // The radius of the clients may have changed
+            for client_index in 0..self.clients.len() {
+                self.update_client_area(client_index);
+            }
+        }
+    }
+
+
+    fn check_heartbeat(&mut self) {
+        if self.last_heartbeat.elapsed() < HEARTBEAT_PERIOD {
+            return;
+        }
+        self.last_heartbeat = Instant::now();
+
+        // The server is able to provide traffic if at least one of its sources works,
+        // maintenance is required if one of them does not work
+        let enabled_sources = [(Source::Ogn, self.config.ogn.enabled), (Source::Adsbhub, self.config.adsbhub.enabled),
+            (Source::Sbs, self.config.sbs.enabled)];
+        let source_timeout = Duration::from_secs(self.config.traffic.source_timeout);
+        let mut nb_sources_alive = 0;
+        let mut nb_sources_down = 0;
+        {
+            let traffic_store = self.traffic_store.lock().unwrap();
+            for (source, _) in enabled_sources.iter().filter(|(_, enabled)| *enabled) {
+                if traffic_store.source_alive(*source, source_timeout) {
+                    nb_sources_alive += 1;
+                }
+                else {
+                    nb_sources_down += 1;
+                }
+            }
+        }
+
+        let status = gdl90::HeartbeatStatus {
+            position_valid: false,          // Set for each client
+            maintenance_required: nb_sources_down > 0,
+            initialized: nb_sources_alive > 0,
+            time_of_day: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).ok()
+                .map(|since_epoch| (since_epoch.as_secs() % 86_400) as u32),
+            nb_messages: 0,                 // Set for each client
+        };
+
+        for (i, client_opt) in self.clients.iter_mut().enumerate() {
+            if let Some(client) = client_opt {
+                if let Err(e) = client.send_heartbeat(&status) {
+                    log::warn!("Send error ({}) to client {}", e, client.address());
+                    self.clients_to_delete.push(i);
+                }
+            }
+        }
+        while let Some(i) = self.clients_to_delete.pop() {
+            self.delete_client(i);
+        }
+    }
+
+
+    fn check_device_id(&mut self) {
+        if self.last_device_id.elapsed() < Duration::from_secs(self.config.device.id_interval) {
+            return;
+        }
+        self.last_device_id = Instant::now();
+
+        for (i, client_opt) in self.clients.iter_mut().enumerate() {
+            if let Some(client) = client_opt {
+                if let Err(e) = client.send_device_id(&self.config.device) {
+                    log::warn!("Send error ({}) to client {}", e, client.address());
+                    self.clients_to_delete.push(i);
+                }
+            }
+        }
+        while let Some(i) = self.clients_to_delete.pop() {
+            self.delete_client(i);
+        }
+    }
+
+
+    fn update_write_interest(&mut self) {
+        for (i, client_opt) in self.clients.iter_mut().enumerate() {
+            if let Some(client) = client_opt {
+                if let Some(write_interest) = client.write_interest_changed() {
+                    let flags = if write_interest { EpollFlags::EPOLLIN | EpollFlags::EPOLLOUT } else { EpollFlags::EPOLLIN };
+                    self.epoll.modify(client.as_fd(),
+                        &mut EpollEvent::new(flags, EventId::new(EVENT_TYPE_CLIENT, i.try_into().unwrap()).into())).unwrap();
+                }
+            }
+        }
+    }
+
+
+    fn shutdown(&mut self, deadline: Instant) {
+        self.stopping = true;
+        self.stop_accepting();
+
+        // Send the traffic information already received to the clients
+        self.process_traffic_event();
+
+        // Tell all clients that we will not send anything else, they are deleted when they close their side
+        for (i, client_opt) in self.clients.iter_mut().enumerate() {
+            if let Some(client) = client_opt {
+                if let Err(e) = client.close_write() {
+                    log::warn!("Close error ({}) for client {}", e, client.address());
+                    self.clients_to_delete.push(i);
+                }
+            }
+        }
+        while let Some(i) = self.clients_to_delete.pop() {
+            self.delete_client(i);
+        }
+
+        // Wait for the clients to close their connection until the deadline
+        let mut epoll_events = [EpollEvent::empty(); 100];
+        while self.nb_clients.load(Ordering::Relaxed) > 0 {
+            // The clients sent or moved from another pool in the meantime are closed too
+            while let Ok(command) = self.command_rx.try_recv() {
+                if let Command::NewClient(client) | Command::MigratedClient(client) = command {
+                    let client_index = self.insert_client(*client);
+                    if let Err(e) = self.clients[client_index].as_mut().unwrap().close_write() {
+                        log::warn!("Close error ({}) for client {}", e, self.clients[client_index].as_ref().unwrap().address());
+                        self.delete_client(client_index);
+                    }
+                }
+            }
+
+            // The pending messages are still sent before the connections are closed
+            self.update_write_interest();
+
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            let timeout = u16::try_from((deadline - now).as_millis()).unwrap_or(u16::MAX).max(1);
+            let nb_events = self.epoll.wait(&mut epoll_events, timeout).unwrap();
+            for epoll_event in epoll_events.iter().take(nb_events) {
+                let event_id: EventId = epoll_event.data().into();
+                if event_id.event_type() == EVENT_TYPE_CLIENT {
+                    let nb_clients = self.nb_clients.load(Ordering::Relaxed);
+                    self.process_event(event_id, epoll_event.events());
+                    self.summary.nb_clients_closed_cleanly += nb_clients - self.nb_clients.load(Ordering::Relaxed);
+                }
+                else if event_id.event_type() == EVENT_TYPE_COMMAND {
+                    self.process_event(event_id, epoll_event.events());
+                }
+            }
+        }
+
+        // The remaining clients are closed without waiting any longer
+        for client_index in 0..self.clients.len() {
+            if self.clients[client_index].is_some() {
+                self.delete_client(client_index);
+                self.summary.nb_clients_closed_forcibly += 1;
+            }
+        }
+    }
+
+}
@@ -0,0 +1,941 @@
+//! Server configuration
+//! The configuration is read from an optional TOML file, then each value can be overridden
+//! from the command line. All values are checked at startup.
+//!
+
+use crate::{internal_com, traffic_infos::Source};
+
+use anyhow::Context;
+use clap::Parser;
+use serde::Deserialize;
+use std::{net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4}, path::PathBuf, sync::{Arc, RwLock}, thread};
+
+
+/// Complete configuration of the server
+#[derive(Default, Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub internal_com: InternalComConfig,
+    pub client: ClientConfig,
+    pub device: DeviceConfig,
+    pub traffic: TrafficConfig,
+    pub fusion: FusionConfig,
+    pub ogn: OgnConfig,
+    pub ddb: DdbConfig,
+    pub adsbhub: AdsbhubConfig,
+    pub sbs: SbsConfig,
+}
+
+
+/// Listening of client connections and distribution of clients in pools
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen_address: IpAddr,
+    pub listen_port: u16,
+    pub nb_pools: Option<usize>,            // Number of CPUs if not defined
+    pub pool_max_clients: usize,            // Maximum number of clients connected at the same time for one pool
+    pub max_clients: Option<usize>,         // Maximum number of clients connected at the same time for the server
+    pub retry_delay: u16,                   // Delay in seconds suggested to the refused clients before connecting again
+    pub shutdown_timeout: u64,              // Maximum time in seconds to close the clients when the server stops
+    pub placement: Placement,               // Distribution of the clients in the pools
+    pub region_size: f64,                   // In degrees, size of the regions for the regional placement, must divide 360
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            listen_port: 1664,
+            nb_pools: None,
+            pool_max_clients: 200,
+            max_clients: None,
+            retry_delay: 60,
+            shutdown_timeout: 5,
+            placement: Placement::Balanced,
+            region_size: 10.0,
+        }
+    }
+}
+
+
+/// Distribution of the clients in the pools
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum Placement {
+    Balanced,               // In the least populated pool, each pool receives all the traffic
+    Regional,               // With the clients of the same region, each pool only receives the traffic around its clients
+}
+
+
+/// Multicast bus used to transmit traffic information from sources to pools
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InternalComConfig {
+    pub multicast_address: Ipv4Addr,       // First of the consecutive groups of the bus
+    pub multicast_port: u16,
+}
+
+impl Default for InternalComConfig {
+    fn default() -> Self {
+        Self {
+            multicast_address: Ipv4Addr::new(224, 0, 0, 64),
+            multicast_port: 1665,
+        }
+    }
+}
+
+
+/// Processing of clients
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    // Traffic must be within a horizontal radius and a vertical band around the client's position to be considered close
+    pub radius: f64,                        // In km
+    pub altitude_band: u32,                 // In ft, above and below the client, only if the client sends its altitude
+    // Limits of the filter parameters which can be requested by the clients
+    pub max_radius: f64,                    // In km
+    pub max_altitude_band: u32,             // In ft
+    pub max_targets: usize,                 // Maximum number of aircraft sent to a client at the same time
+    pub min_update_interval: u64,           // In ms, minimum time between two reports of the same aircraft
+    // Besides the addresses registered by the client, its own aircraft can be recognized by its position,
+    // only if the client sends its altitude
+    pub ownship_suppression: bool,
+    pub ownship_max_distance: f64,          // In km
+    pub ownship_max_altitude_difference: u32,   // In ft
+    // Messages waiting to be sent to a slow client
+    pub send_queue_len: usize,              // Maximum number of messages, traffic reports included
+    pub drop_policy: DropPolicy,            // Traffic reports dropped when the queue is full
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            radius: 100.0,
+            altitude_band: 10_000,
+            max_radius: 250.0,
+            max_altitude_band: 30_000,
+            max_targets: 200,
+            min_update_interval: 0,
+            ownship_suppression: false,
+            ownship_max_distance: 0.1,
+            ownship_max_altitude_difference: 200,
+            send_queue_len: 256,
+            drop_policy: DropPolicy::LatestPerAircraft,
+        }
+    }
+}
+
+
+/// Traffic reports dropped when the send queue of a client is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum DropPolicy {
+    Oldest,                 // The oldest queued report
+    Newest,                 // The new report
+    LatestPerAircraft,      // The queued report of the same aircraft, replaced by the new one, otherwise the oldest
+}
+
+
+/// Identification of the server by the applications, with the ForeFlight GDL90 extension
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceConfig {
+    pub name: String,                       // Up to 8 bytes
+    pub long_name: String,                  // Up to 16 bytes
+    pub serial_number: Option<u64>,         // Not given if not defined
+    pub capabilities: u32,                  // Capabilities mask defined by ForeFlight
+    pub id_interval: u64,                   // In seconds, between two identifications
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        Self {
+            name: String::from("SkyReach"),
+            long_name: String::from("SkyReacher"),
+            serial_number: None,
+            capabilities: 0,
+            id_interval: 5,
+        }
+    }
+}
+
+
+/// Table of the known traffic
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrafficConfig {
+    pub max_age: u64,                       // In seconds, an aircraft not seen for longer is forgotten
+    pub source_timeout: u64,                // In seconds, a source without report for longer is considered down
+}
+
+impl Default for TrafficConfig {
+    fn default() -> Self {
+        Self {
+            max_age: 60,
+            source_timeout: 30,
+        }
+    }
+}
+
+
+/// Fusion of the same aircraft reported by several sources
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FusionConfig {
+    pub enabled: bool,
+    pub priority: Vec<Source>,              // Sources from the most to the least accurate
+    pub stale_after: u64,                   // In seconds, a source not heard for longer is only used if there is no other
+    // Two reports with the same address are from the same aircraft if they are closer than:
+    pub max_distance: f64,                  // In km
+    pub max_altitude_difference: u32,       // In ft
+}
+
+impl Default for FusionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            priority: vec![Source::Sbs, Source::Adsbhub, Source::Ogn],
+            stale_after: 10,
+            max_distance: 5.0,
+            max_altitude_difference: 1000,
+        }
+    }
+}
+
+
+/// Reception of OGN traffic
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OgnConfig {
+    pub enabled: bool,
+    pub mode: OgnMode,
+    pub region: Region,                     // Requested when no client has sent its position, or without dynamic region
+    pub dynamic_region: bool,               // Region computed from the areas of the connected clients
+    pub max_boxes: usize,                   // Maximum number of boxes covering the areas of the clients
+    pub worldwide_fallback: bool,           // The whole world is requested instead of the region when no client has sent its position
+    pub region_update_interval: u64,        // In seconds, minimum interval between two changes of the APRS-IS filter
+    pub aprs_address: String,               // host:port of the APRS-IS server
+    pub aprs_callsign: String,              // Login on the APRS-IS server, without passcode (read only)
+    pub poll_interval: u64,                 // In seconds, for the polling of the web map
+}
+
+impl Default for OgnConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            mode: OgnMode::Aprs,
+            // France
+            region: Region {
+                north: 51.3,
+                south: 42.1,
+                east: 8.4,
+                west: -5.1,
+            },
+            dynamic_region: true,
+            max_boxes: 4,
+            worldwide_fallback: false,
+            region_update_interval: 30,
+            aprs_address: String::from("aprs.glidernet.org:14580"),
+            aprs_callsign: String::from("SRRUST"),
+            poll_interval: 5,
+        }
+    }
+}
+
+
+/// Way of receiving the OGN traffic
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum OgnMode {
+    Aprs,                   // Stream of the APRS-IS network, with a filter on the region
+    Lxml,                   // Polling of the web map of live.glidernet.org
+}
+
+
+/// Geographic area delimited by latitudes and longitudes in degrees
+/// A west longitude greater than the east one gives a region crossing the antimeridian
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Region {
+    pub north: f64,
+    pub south: f64,
+    pub east: f64,
+    pub west: f64,
+}
+
+
+/// OGN device database, giving the registration of the devices and the wishes of their owners
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DdbConfig {
+    pub enabled: bool,
+    pub file: PathBuf,                      // Local copy of the database
+    pub url: String,                        // Download of the database in JSON format, the file is only read again if empty
+    pub refresh_interval: u64,              // In seconds
+}
+
+impl Default for DdbConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            file: PathBuf::from("ogn-ddb.json"),
+            url: String::from("https://ddb.glidernet.org/download/?j=1&t=1"),
+            refresh_interval: 86400,
+        }
+    }
+}
+
+
+/// Reception of ADSBHub traffic
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdsbhubConfig {
+    pub enabled: bool,
+    pub address: String,                    // host:port
+}
+
+impl Default for AdsbhubConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            address: String::from("data.adsbhub.org:5002"),
+        }
+    }
+}
+
+
+/// Reception of the traffic of SBS-1 BaseStation feeds, like the local receivers running dump1090
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SbsConfig {
+    pub enabled: bool,
+    pub feeds: Vec<String>,                 // host:port of each feed
+}
+
+
+/// Configuration shared between the threads, which can be replaced while the server is running
+/// The threads get the current configuration each time they need it, to take into account a reload
+#[derive(Clone)]
+pub struct SharedConfig {
+    current: Arc<RwLock<Arc<Config>>>,
+    args: Arc<Args>,                        // Command line parsed at startup, applied again at each reload
+}
+
+impl SharedConfig {
+    pub fn new(config: Config, args: Args) -> Self {
+        Self {
+            current: Arc::new(RwLock::new(Arc::new(config))),
+            args: Arc::new(args),
+        }
+    }
+
+    /// Get the current configuration
+    pub fn get(&self) -> Arc<Config> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Reloads the configuration file, with the command line of the startup overriding it as before
+    /// The command line is not parsed again, so that a reload cannot exit the process
+    /// The settings which cannot change while running keep their current value and are reported in the log
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let mut new_config = Config::from_args(&self.args)?;
+
+        let mut current_config = self.current.write().unwrap();
+        for setting in new_config.keep_restart_settings(&current_config) {
+            log::warn!("{} has changed, a restart is required to apply it", setting);
+        }
+        *current_config = Arc::new(new_config);
+
+        Ok(())
+    }
+}
+
+
+/// Command line options, each one overrides the value of the configuration file
+#[derive(Debug, Clone, Parser)]
+#[command(version, about = "SkyReacher, aircraft position server")]
+pub struct Args {
+    /// Configuration file in TOML format
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Address on which client connections are accepted
+    #[arg(long)]
+    listen_address: Option<IpAddr>,
+
+    /// Port on which client connections are accepted
+    #[arg(long)]
+    listen_port: Option<u16>,
+
+    /// Number of client pools (number of CPUs by default)
+    #[arg(long)]
+    nb_pools: Option<usize>,
+
+    /// Maximum number of clients for one pool
+    #[arg(long)]
+    pool_max_clients: Option<usize>,
+
+    /// Maximum number of clients for the server
+    #[arg(long)]
+    max_clients: Option<usize>,
+
+    /// Delay in seconds suggested to the refused clients before connecting again
+    #[arg(long)]
+    retry_delay: Option<u16>,
+
+    /// Maximum time in seconds to close the clients when the server stops
+    #[arg(long)]
+    shutdown_timeout: Option<u64>,
+
+    /// Distribution of the clients in the pools
+    #[arg(long, value_enum)]
+    placement: Option<Placement>,
+
+    /// Size in degrees of the regions for the regional placement
+    #[arg(long)]
+    region_size: Option<f64>,
+
+    /// Multicast group used between sources and pools
+    #[arg(long)]
+    multicast_address: Option<Ipv4Addr>,
+
+    /// Multicast port used between sources and pools
+    #[arg(long)]
+    multicast_port: Option<u16>,
+
+    /// Maximum distance in km between a client and a nearby traffic
+    #[arg(long)]
+    radius: Option<f64>,
+
+    /// Maximum altitude difference in ft between a client and a nearby traffic
+    #[arg(long)]
+    altitude_band: Option<u32>,
+
+    /// Maximum radius in km that a client can request
+    #[arg(long)]
+    max_radius: Option<f64>,
+
+    /// Maximum altitude band in ft that a client can request
+    #[arg(long)]
+    max_altitude_band: Option<u32>,
+
+    /// Maximum number of aircraft sent to a client at the same time
+    #[arg(long)]
+    max_targets: Option<usize>,
+
+    /// Minimum time in ms between two reports of the same aircraft to a client
+    #[arg(long)]
+    min_update_interval: Option<u64>,
+
+    /// Enable or disable the suppression of the traffic at the position of a client
+    #[arg(long)]
+    ownship_suppression: Option<bool>,
+
+    /// Maximum distance in km between a client and a traffic considered as its own aircraft
+    #[arg(long)]
+    ownship_max_distance: Option<f64>,
+
+    /// Maximum altitude difference in ft between a client and a traffic considered as its own aircraft
+    #[arg(long)]
+    ownship_max_altitude_difference: Option<u32>,
+
+    /// Maximum number of messages waiting to be sent to a client
+    #[arg(long)]
+    send_queue_len: Option<usize>,
+
+    /// Traffic reports dropped when the send queue of a client is full
+    #[arg(long, value_enum)]
+    drop_policy: Option<DropPolicy>,
+
+    /// Name of the server shown by the applications (up to 8 bytes)
+    #[arg(long)]
+    device_name: Option<String>,
+
+    /// Long name of the server shown by the applications (up to 16 bytes)
+    #[arg(long)]
+    device_long_name: Option<String>,
+
+    /// Serial number of the server shown by the applications
+    #[arg(long)]
+    device_serial_number: Option<u64>,
+
+    /// Capabilities mask of the ForeFlight identification message
+    #[arg(long)]
+    device_capabilities: Option<u32>,
+
+    /// Time in seconds between two identifications of the server to the clients
+    #[arg(long)]
+    device_id_interval: Option<u64>,
+
+    /// Time in seconds after which an aircraft not seen is forgotten
+    #[arg(long)]
+    traffic_max_age: Option<u64>,
+
+    /// Time in seconds after which a source without report is considered down
+    #[arg(long)]
+    source_timeout: Option<u64>,
+
+    /// Enable or disable the fusion of the same aircraft reported by several sources
+    #[arg(long)]
+    fusion_enabled: Option<bool>,
+
+    /// Sources from the most to the least accurate, for the fusion
+    #[arg(long, value_delimiter = ',')]
+    fusion_priority: Option<Vec<Source>>,
+
+    /// Enable or disable the OGN source
+    #[arg(long)]
+    ogn_enabled: Option<bool>,
+
+    /// Reception of the OGN traffic
+    #[arg(long, value_enum)]
+    ogn_mode: Option<OgnMode>,
+
+    /// Compute the region requested from OGN from the areas of the connected clients
+    #[arg(long)]
+    ogn_dynamic_region: Option<bool>,
+
+    /// Maximum number of boxes requested from OGN, covering the areas of the clients
+    #[arg(long)]
+    ogn_max_boxes: Option<usize>,
+
+    /// Request the whole world from OGN instead of the region when no client has sent its position
+    #[arg(long)]
+    ogn_worldwide_fallback: Option<bool>,
+
+    /// Minimum interval in seconds between two changes of the OGN APRS-IS filter
+    #[arg(long)]
+    ogn_region_update_interval: Option<u64>,
+
+    /// OGN APRS-IS server (host:port)
+    #[arg(long)]
+    ogn_aprs_address: Option<String>,
+
+    /// Callsign used to log in to the OGN APRS-IS server
+    #[arg(long)]
+    ogn_aprs_callsign: Option<String>,
+
+    /// Interval in seconds between two OGN requests, when polling the web map
+    #[arg(long)]
+    ogn_poll_interval: Option<u64>,
+
+    /// Region requested from OGN, in degrees
+    #[arg(long, value_names = ["NORTH", "SOUTH", "EAST", "WEST"], num_args = 4, allow_negative_numbers = true)]
+    ogn_region: Option<Vec<f64>>,
+
+    /// Enable or disable the OGN device database
+    #[arg(long)]
+    ddb_enabled: Option<bool>,
+
+    /// Local copy of the OGN device database
+    #[arg(long)]
+    ddb_file: Option<PathBuf>,
+
+    /// Download address of the OGN device database, in JSON format
+    #[arg(long)]
+    ddb_url: Option<String>,
+
+    /// Interval in seconds between two refreshes of the OGN device database
+    #[arg(long)]
+    ddb_refresh_interval: Option<u64>,
+
+    /// Enable or disable the ADSBHub source
+    #[arg(long)]
+    adsbhub_enabled: Option<bool>,
+
+    /// ADSBHub server (host:port)
+    #[arg(long)]
+    adsbhub_address: Option<String>,
+
+    /// Enable or disable the SBS feeds
+    #[arg(long)]
+    sbs_enabled: Option<bool>,
+
+    /// SBS feeds (host:port), separated by commas
+    #[arg(long, value_delimiter = ',')]
+    sbs_feeds: Option<Vec<String>>,
+}
+
+
+impl Config {
+    /// Loads the configuration from the command line and the configuration file
+    /// Returns the command line too, to apply it again when the configuration is reloaded
+    pub fn load() -> anyhow::Result<(Self, Args)> {
+        let args = Args::parse();
+        let config = Self::from_args(&args)?;
+        Ok((config, args))
+    }
+
+
+    // Loads the configuration file given by the command line, then applies the command line options
+    fn from_args(args: &Args) -> anyhow::Result<Self> {
+        let mut config = match &args.config {
+            None => Self::default(),
+            Some(path) => Self::from_file(path)?,
+        };
+        config.apply_args(args.clone());
+        config.check()?;
+
+        Ok(config)
+    }
+
+
+    /// Get the number of pools to create
+    pub fn nb_pools(&self) -> usize {
+        self.server.nb_pools.unwrap_or_else(|| thread::available_parallelism().unwrap().get())
+    }
+
+
+    /// Get the address on which client connections are accepted
+    pub fn listen_socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.server.listen_address, self.server.listen_port)
+    }
+
+
+    /// Get the multicast address and port used between sources and pools
+    pub fn multicast_socket_addr(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.internal_com.multicast_address, self.internal_com.multicast_port)
+    }
+
+
+    /// Settings which can only be applied by restarting the server are set back to their current value
+    /// Returns the names of the settings that have been changed
+    fn keep_restart_settings(&mut self, current: &Config) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+
+        fn keep<T: PartialEq + Clone>(name: &'static str, new: &mut T, current: &T, changed: &mut Vec<&'static str>) {
+            if new != current {
+                changed.push(name);
+                *new = current.clone();
+            }
+        }
+
+        keep("server.listen_address", &mut self.server.listen_address, &current.server.listen_address, &mut changed);
+        keep("server.listen_port", &mut self.server.listen_port, &current.server.listen_port, &mut changed);
+        keep("server.nb_pools", &mut self.server.nb_pools, &current.server.nb_pools, &mut changed);
+        keep("server.placement", &mut self.server.placement, &current.server.placement, &mut changed);
+        keep("server.region_size", &mut self.server.region_size, &current.server.region_size, &mut changed);
+        keep("internal_com.multicast_address", &mut self.internal_com.multicast_address, &current.internal_com.multicast_address, &mut changed);
+        keep("internal_com.multicast_port", &mut self.internal_com.multicast_port, &current.internal_com.multicast_port, &mut changed);
+        keep("ogn.enabled", &mut self.ogn.enabled, &current.ogn.enabled, &mut changed);
+        keep("ogn.mode", &mut self.ogn.mode, &current.ogn.mode, &mut changed);
+        keep("ddb.enabled", &mut self.ddb.enabled, &current.ddb.enabled, &mut changed);
+        keep("adsbhub.enabled", &mut self.adsbhub.enabled, &current.adsbhub.enabled, &mut changed);
+        keep("sbs.enabled", &mut self.sbs.enabled, &current.sbs.enabled, &mut changed);
+        keep("sbs.feeds", &mut self.sbs.feeds, &current.sbs.feeds, &mut changed);
+
+        changed
+    }
+
+
+    fn from_file(path: &PathBuf) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read configuration file {}", path.display()))?;
+        let config = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse configuration file {}", path.display()))?;
+        Ok(config)
+    }
+
+
+    fn apply_args(&mut self, args: Args) {
+        if let Some(v) = args.listen_address { self.server.listen_address = v; }
+        if let Some(v) = args.listen_port { self.server.listen_port = v; }
+        if let Some(v) = args.nb_pools { self.server.nb_pools = Some(v); }
+        if let Some(v) = args.pool_max_clients { self.server.pool_max_clients = v; }
+        if let Some(v) = args.max_clients { self.server.max_clients = Some(v); }
+        if let Some(v) = args.retry_delay { self.server.retry_delay = v; }
+        if let Some(v) = args.shutdown_timeout { self.server.shutdown_timeout = v; }
+        if let Some(v) = args.placement { self.server.placement = v; }
+        if let Some(v) = args.region_size { self.server.region_size = v; }
+        if let Some(v) = args.multicast_address { self.internal_com.multicast_address = v; }
+        if let Some(v) = args.multicast_port { self.internal_com.multicast_port = v; }
+        if let Some(v) = args.radius { self.client.radius = v; }
+        if let Some(v) = args.altitude_band { self.client.altitude_band = v; }
+        if let Some(v) = args.max_radius { self.client.max_radius = v; }
+        if let Some(v) = args.max_altitude_band { self.client.max_altitude_band = v; }
+        if let Some(v) = args.max_targets { self.client.max_targets = v; }
+        if let Some(v) = args.min_update_interval { self.client.min_update_interval = v; }
+        if let Some(v) = args.ownship_suppression { self.client.ownship_suppression = v; }
+        if let Some(v) = args.ownship_max_distance { self.client.ownship_max_distance = v; }
+        if let Some(v) = args.ownship_max_altitude_difference { self.client.ownship_max_altitude_difference = v; }
+        if let Some(v) = args.send_queue_len { self.client.send_queue_len = v; }
+        if let Some(v) = args.drop_policy { self.client.drop_policy = v; }
+        if let Some(v) = args.device_name { self.device.name = v; }
+        if let Some(v) = args.device_long_name { self.device.long_name = v; }
+        if let Some(v) = args.device_serial_number { self.device.serial_number = Some(v); }
+        if let Some(v) = args.device_capabilities { self.device.capabilities = v; }
+        if let Some(v) = args.device_id_interval { self.device.id_interval = v; }
+        if let Some(v) = args.traffic_max_age { self.traffic.max_age = v; }
+        if let Some(v) = args.source_timeout { self.traffic.source_timeout = v; }
+        if let Some(v) = args.fusion_enabled { self.fusion.enabled = v; }
+        if let Some(v) = args.fusion_priority { self.fusion.priority = v; }
+        if let Some(v) = args.ogn_enabled { self.ogn.enabled = v; }
+        if let Some(v) = args.ogn_mode { self.ogn.mode = v; }
+        if let Some(v) = args.ogn_dynamic_region { self.ogn.dynamic_region = v; }
+        if let Some(v) = args.ogn_max_boxes { self.ogn.max_boxes = v; }
+        if let Some(v) = args.ogn_worldwide_fallback { self.ogn.worldwide_fallback = v; }
+        if let Some(v) = args.ogn_region_update_interval { self.ogn.region_update_interval = v; }
+        if let Some(v) = args.ogn_aprs_address { self.ogn.aprs_address = v; }
+        if let Some(v) = args.ogn_aprs_callsign { self.ogn.aprs_callsign = v; }
+        if let Some(v) = args.ogn_poll_interval { self.ogn.poll_interval = v; }
+        if let Some(v) = args.ogn_region {
+            // clap guarantees that there are exactly 4 values
+            self.ogn.region = Region { north: v[0], south: v[1], east: v[2], west: v[3] };
+        }
+        if let Some(v) = args.ddb_enabled { self.ddb.enabled = v; }
+        if let Some(v) = args.ddb_file { self.ddb.file = v; }
+        if let Some(v) = args.ddb_url { self.ddb.url = v; }
+        if let Some(v) = args.ddb_refresh_interval { self.ddb.refresh_interval = v; }
+        if let Some(v) = args.adsbhub_enabled { self.adsbhub.enabled = v; }
+        if let Some(v) = args.adsbhub_address { self.adsbhub.address = v; }
+        if let Some(v) = args.sbs_enabled { self.sbs.enabled = v; }
+        if let Some(v) = args.sbs_feeds { self.sbs.feeds = v; }
+    }
+
+
+    fn check(&self) -> anyhow::Result<()> {
+        // Server
+        anyhow::ensure!(self.server.listen_port != 0, "server.listen_port must not be 0");
+        if let Some(nb_pools) = self.server.nb_pools {
+            anyhow::ensure!(nb_pools >= 1, "server.nb_pools must be at least 1");
+        }
+        anyhow::ensure!(self.server.pool_max_clients >= 1, "server.pool_max_clients must be at least 1");
+        anyhow::ensure!(u32::try_from(self.server.pool_max_clients).is_ok(), "server.pool_max_clients is too large");
+        if let Some(max_clients) = self.server.max_clients {
+            anyhow::ensure!(max_clients >= 1, "server.max_clients must be at least 1");
+        }
+        anyhow::ensure!(self.server.region_size >= 1.0 && self.server.region_size <= 90.0 &&
+            (360.0 / self.server.region_size).fract() == 0.0, "server.region_size must be in [1, 90] and divide 360");
+
+        // Internal communication
+        anyhow::ensure!(self.internal_com.multicast_address.is_multicast(),
+            "internal_com.multicast_address ({}) is not a multicast address", self.internal_com.multicast_address);
+        anyhow::ensure!(usize::from(self.internal_com.multicast_address.octets()[3]) + internal_com::NB_GROUPS <= 256,
+            "internal_com.multicast_address ({}) must be followed by {} other addresses in its /24 network",
+            self.internal_com.multicast_address, internal_com::NB_GROUPS - 1);
+        anyhow::ensure!(self.internal_com.multicast_port != 0, "internal_com.multicast_port must not be 0");
+
+        // Client
+        anyhow::ensure!(self.client.max_radius > 0.0 && self.client.max_radius <= 20_000.0,
+            "client.max_radius must be in ]0, 20000]");
+        anyhow::ensure!(self.client.radius > 0.0 && self.client.radius <= self.client.max_radius,
+            "client.radius must be in ]0, client.max_radius]");
+        anyhow::ensure!(self.client.max_altitude_band <= u32::from(u16::MAX), "client.max_altitude_band must be at most {}", u16::MAX);
+        anyhow::ensure!(self.client.altitude_band <= self.client.max_altitude_band,
+            "client.altitude_band must not be greater than client.max_altitude_band");
+        anyhow::ensure!((1..=usize::from(u16::MAX)).contains(&self.client.max_targets), "client.max_targets must be in [1, {}]", u16::MAX);
+        anyhow::ensure!(self.client.min_update_interval <= u64::from(u16::MAX), "client.min_update_interval must be at most {} ms", u16::MAX);
+        anyhow::ensure!(self.client.ownship_max_distance >= 0.0, "client.ownship_max_distance must not be negative");
+        anyhow::ensure!(self.client.send_queue_len >= 1, "client.send_queue_len must be at least 1");
+
+        // Device
+        anyhow::ensure!(self.device.name.len() <= 8, "device.name must be at most 8 bytes long");
+        anyhow::ensure!(self.device.long_name.len() <= 16, "device.long_name must be at most 16 bytes long");
+        anyhow::ensure!(self.device.serial_number != Some(u64::MAX), "device.serial_number {} is reserved", u64::MAX);
+        anyhow::ensure!(self.device.id_interval >= 1, "device.id_interval must be at least 1 second");
+
+        // Traffic
+        anyhow::ensure!(self.traffic.max_age >= 1, "traffic.max_age must be at least 1 second");
+        anyhow::ensure!(self.traffic.source_timeout >= 1, "traffic.source_timeout must be at least 1 second");
+
+        // Fusion
+        for (i, source) in self.fusion.priority.iter().enumerate() {
+            anyhow::ensure!(!self.fusion.priority[..i].contains(source), "Source {} is duplicated in fusion.priority", source);
+        }
+        anyhow::ensure!(self.fusion.stale_after >= 1, "fusion.stale_after must be at least 1 second");
+        anyhow::ensure!(self.fusion.max_distance > 0.0, "fusion.max_distance must be positive");
+
+        // OGN
+        anyhow::ensure!(self.ogn.poll_interval >= 1, "ogn.poll_interval must be at least 1 second");
+        self.ogn.region.check().context("Invalid ogn.region")?;
+        anyhow::ensure!((1..=16).contains(&self.ogn.max_boxes), "ogn.max_boxes must be in [1, 16]");
+        anyhow::ensure!(self.ogn.region_update_interval >= 1, "ogn.region_update_interval must be at least 1 second");
+        Self::check_host_port(&self.ogn.aprs_address).context("Invalid ogn.aprs_address")?;
+        anyhow::ensure!((3..=9).contains(&self.ogn.aprs_callsign.len()) &&
+            self.ogn.aprs_callsign.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'),
+            "ogn.aprs_callsign must have 3 to 9 letters, digits or '-'");
+
+        // OGN device database
+        anyhow::ensure!(self.ddb.refresh_interval >= 60, "ddb.refresh_interval must be at least 60 seconds");
+
+        // ADSBHub
+        Self::check_host_port(&self.adsbhub.address).context("Invalid adsbhub.address")?;
+
+        // SBS feeds
+        anyhow::ensure!(!self.sbs.enabled || !self.sbs.feeds.is_empty(), "sbs.feeds must not be empty when the SBS feeds are enabled");
+        for feed in &self.sbs.feeds {
+            Self::check_host_port(feed).with_context(|| format!("Invalid sbs.feeds entry '{}'", feed))?;
+        }
+
+        Ok(())
+    }
+
+
+    fn check_host_port(address: &str) -> anyhow::Result<()> {
+        let (host, port) = address.rsplit_once(':').context("Expected format is host:port")?;
+        anyhow::ensure!(!host.is_empty(), "Host is empty");
+        let port: u16 = port.parse().context("Invalid port")?;
+        anyhow::ensure!(port != 0, "Port must not be 0");
+        Ok(())
+    }
+
+}
+
+
+impl Region {
+    /// Region covering the whole world
+    pub const WORLD: Region = Region { north: 90.0, south: -90.0, east: 180.0, west: -180.0 };
+
+
+    fn check(&self) -> anyhow::Result<()> {
+        anyhow::ensure!((-90.0..=90.0).contains(&self.north), "North latitude out of bounds");
+        anyhow::ensure!((-90.0..=90.0).contains(&self.south), "South latitude out of bounds");
+        anyhow::ensure!((-180.0..=180.0).contains(&self.east), "East longitude out of bounds");
+        anyhow::ensure!((-180.0..=180.0).contains(&self.west), "West longitude out of bounds");
+        anyhow::ensure!(self.north > self.south, "North latitude must be greater than south latitude");
+        anyhow::ensure!(self.east != self.west, "East and west longitudes must be different");
+        Ok(())
+    }
+
+
+    /// Get the region as boxes whose east longitude is greater than the west one,
+    /// split in two at the antimeridian if the region crosses it
+    pub fn split_at_antimeridian(&self) -> Vec<Region> {
+        if self.east > self.west {
+            vec![self.clone()]
+        }
+        else {
+            vec![
+                Region { east: 180.0, ..self.clone() },
+                Region { west: -180.0, ..self.clone() },
+            ]
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Configuration file of a test, removed at the end of the test
+    struct TestFile(PathBuf);
+
+    impl TestFile {
+        fn new(name: &str, content: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("srrust-{}-{}.toml", std::process::id(), name));
+            std::fs::write(&path, content).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TestFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+
+    fn args(options: &[&str]) -> Args {
+        Args::try_parse_from(["srrust"].iter().chain(options)).unwrap()
+    }
+
+
+    // Indicates whether a change of the default configuration is refused
+    fn refused(change: impl FnOnce(&mut Config)) -> bool {
+        let mut config = Config::default();
+        change(&mut config);
+        config.check().is_err()
+    }
+
+
+    #[test]
+    fn values_checked() {
+        assert!(Config::default().check().is_ok());
+
+        // Ports and addresses
+        assert!(refused(|config| config.server.listen_port = 0));
+        assert!(refused(|config| config.internal_com.multicast_port = 0));
+        assert!(refused(|config| config.internal_com.multicast_address = Ipv4Addr::new(10, 0, 0, 1)));
+        assert!(refused(|config| config.internal_com.multicast_address = Ipv4Addr::new(239, 1, 1, 241)));
+        assert!(refused(|config| config.adsbhub.address = String::from("data.adsbhub.org")));
+        assert!(refused(|config| config.ogn.aprs_address = String::from(":14580")));
+        assert!(refused(|config| config.sbs.feeds = vec![String::from("localhost:0")]));
+        assert!(refused(|config| config.sbs.enabled = true));
+
+        // Regions, a region crossing the antimeridian being accepted
+        assert!(refused(|config| config.ogn.region = Region { north: 40.0, south: 45.0, east: 10.0, west: 0.0 }));
+        assert!(refused(|config| config.ogn.region = Region { north: 91.0, south: 45.0, east: 10.0, west: 0.0 }));
+        assert!(refused(|config| config.ogn.region = Region { north: 50.0, south: 45.0, east: 10.0, west: -181.0 }));
+        assert!(refused(|config| config.ogn.region = Region { north: 50.0, south: 45.0, east: 10.0, west: 10.0 }));
+        assert!(!refused(|config| config.ogn.region = Region { north: -10.0, south: -25.0, east: -170.0, west: 170.0 }));
+        assert!(refused(|config| config.server.region_size = 7.0));
+        assert!(refused(|config| config.server.region_size = 0.5));
+
+        // Intervals
+        assert!(refused(|config| config.device.id_interval = 0));
+        assert!(refused(|config| config.traffic.max_age = 0));
+        assert!(refused(|config| config.traffic.source_timeout = 0));
+        assert!(refused(|config| config.fusion.stale_after = 0));
+        assert!(refused(|config| config.ogn.poll_interval = 0));
+        assert!(refused(|config| config.ogn.region_update_interval = 0));
+        assert!(refused(|config| config.ddb.refresh_interval = 59));
+        assert!(refused(|config| config.client.min_update_interval = 70_000));
+
+        // APRS-IS callsign
+        assert!(refused(|config| config.ogn.aprs_callsign = String::from("SR")));
+        assert!(refused(|config| config.ogn.aprs_callsign = String::from("SRRUST-123")));
+        assert!(refused(|config| config.ogn.aprs_callsign = String::from("SR RUST")));
+        assert!(refused(|config| config.ogn.aprs_callsign = String::from("SRRUSTé")));
+        assert!(!refused(|config| config.ogn.aprs_callsign = String::from("SRRUST-1")));
+    }
+
+
+    #[test]
+    fn command_line_overriding_the_file() {
+        let file = TestFile::new("override", "[server]\nlisten_port = 2000\n[client]\nradius = 30.0\n[ogn]\naprs_callsign = \"FILE\"\n");
+        let path = file.0.to_str().unwrap();
+
+        let config = Config::from_args(&args(&["-c", path, "--listen-port", "3000", "--ogn-region", "-10", "-25", "-170", "170"])).unwrap();
+        assert_eq!(config.server.listen_port, 3000);
+        assert_eq!(config.client.radius, 30.0);
+        assert_eq!(config.ogn.aprs_callsign, "FILE");
+        assert_eq!((config.ogn.region.north, config.ogn.region.south, config.ogn.region.east, config.ogn.region.west), (-10.0, -25.0, -170.0, 170.0));
+
+        // The values of the command line are checked too
+        assert!(Config::from_args(&args(&["-c", path, "--radius", "0"])).is_err());
+        assert!(Config::from_args(&args(&["-c", "/nonexistent/srrust.toml"])).is_err());
+    }
+
+
+    #[test]
+    fn restart_settings_kept_on_reload() {
+        let file = TestFile::new("reload", "[server]\nlisten_port = 2000\nnb_pools = 2\n[client]\nradius = 30.0\n");
+        let args = args(&["-c", file.0.to_str().unwrap(), "--altitude-band", "3000"]);
+        let config = SharedConfig::new(Config::from_args(&args).unwrap(), args);
+
+        std::fs::write(&file.0, "[server]\nlisten_port = 2001\nnb_pools = 4\n[client]\nradius = 40.0\naltitude_band = 4000\n").unwrap();
+        config.reload().unwrap();
+        let reloaded = config.get();
+        assert_eq!((reloaded.server.listen_port, reloaded.server.nb_pools), (2000, Some(2)));
+        assert_eq!(reloaded.client.radius, 40.0);
+        assert_eq!(reloaded.client.altitude_band, 3000);
+
+        // An invalid file keeps the current configuration
+        std::fs::write(&file.0, "[client]\nradius = -1.0\n").unwrap();
+        assert!(config.reload().is_err());
+        assert_eq!(config.get().client.radius, 40.0);
+
+        let mut new_config = Config::default();
+        assert_eq!(new_config.keep_restart_settings(&Config::default()), Vec::<&str>::new());
+        new_config.sbs.feeds = vec![String::from("localhost:30003")];
+        new_config.ogn.mode = OgnMode::Lxml;
+        assert_eq!(new_config.keep_restart_settings(&Config::default()), ["ogn.mode", "sbs.feeds"]);
+        assert!(new_config.sbs.feeds.is_empty());
+    }
+}
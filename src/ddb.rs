@@ -0,0 +1,314 @@
+//! OGN device database, in which the owners of the devices give the registration of their aircraft
+//! and whether they accept to be tracked and identified.
+//! The database is downloaded in JSON format into a local file, refreshed periodically, then loaded
+//! in memory to be applied to the OGN traffic. See <https://ddb.glidernet.org>.
+//!
+
+use crate::{config::{DdbConfig, SharedConfig}, stop_flag::StopFlag, traffic_infos::{AddressType, TrafficInfos}};
+
+use anyhow::Context;
+use serde::Deserialize;
+use std::{collections::HashMap, fs, io, path::{Path, PathBuf}, sync::{Arc, RwLock}, thread, time::{Duration, Instant, SystemTime}};
+
+
+// Interval between two checks of the local file and of its age
+const CHECK_PERIOD: Duration = Duration::from_secs(60);
+
+// Delay before downloading again after a failure
+const DOWNLOAD_RETRY_DELAY: Duration = Duration::from_secs(600);
+
+// Maximum duration of the download
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(120);
+
+
+pub type SharedDdb = Arc<RwLock<Ddb>>;
+
+
+// Content of the JSON file of the database
+#[derive(Deserialize)]
+struct DdbFile {
+    devices: Vec<DdbDevice>,
+}
+
+#[derive(Deserialize)]
+struct DdbDevice {
+    device_type: String,                // "F" FLARM, "O" OGN tracker, "I" ICAO
+    device_id: String,                  // Address in hexadecimal
+    #[serde(default)]
+    aircraft_model: String,
+    #[serde(default)]
+    registration: String,
+    #[serde(default)]
+    cn: String,                         // Competition number
+    #[serde(default)]
+    tracked: String,                    // "Y" or "N"
+    #[serde(default)]
+    identified: String,                 // "Y" or "N"
+}
+
+
+/// Information of a device of the database
+#[derive(Debug, Clone, PartialEq)]
+pub struct Device {
+    pub registration: String,
+    pub competition_number: String,
+    pub aircraft_model: String,
+    pub tracked: bool,                  // The owner accepts that the aircraft is tracked
+    pub identified: bool,               // The owner accepts that the aircraft is identified
+}
+
+
+#[derive(Default)]
+pub struct Ddb {
+    devices: HashMap<(AddressType, u32), Device>,
+}
+
+
+impl Ddb {
+    pub fn new_shared() -> SharedDdb {
+        Arc::new(RwLock::new(Self::default()))
+    }
+
+
+    /// Parses the database in JSON format
+    /// The devices with an unknown type or an invalid address are ignored
+    pub fn parse(json: &str) -> anyhow::Result<Self> {
+        let file: DdbFile = serde_json::from_str(json).context("Invalid OGN DDB")?;
+        let devices = file.devices.into_iter().filter_map(|device| {
+            let addr_type = match device.device_type.as_str() {
+                "F" => AddressType::Flarm,
+                "O" => AddressType::Ogn,
+                "I" => AddressType::AdsbIcao,
+                _ => return None,
+            };
+            let address = u32::from_str_radix(&device.device_id, 16).ok().filter(|&address| address <= 0xffffff)?;
+            Some(((addr_type, address), Device {
+                registration: device.registration,
+                competition_number: device.cn,
+                aircraft_model: device.aircraft_model,
+                // Only an explicit refusal is taken into account
+                tracked: device.tracked != "N",
+                identified: device.identified != "N",
+            }))
+        }).collect();
+        Ok(Self { devices })
+    }
+
+
+    /// Get the number of devices of the database
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+
+    /// Get the information of a device
+    pub fn get(&self, addr_type: AddressType, address: u32) -> Option<&Device> {
+        self.devices.get(&(addr_type, address))
+    }
+
+
+    /// Indicates whether the owner of a device accepts that it is identified, true for an unknown device
+    pub fn identified(&self, addr_type: AddressType, address: u32) -> bool {
+        self.get(addr_type, address).is_none_or(|device| device.identified)
+    }
+
+
+    /// Applies the wishes of the owner of the device to the traffic and completes it with the information of the device
+    /// Returns false if the traffic must not be published
+    pub fn apply(&self, infos: &mut TrafficInfos) -> bool {
+        let Some(device) = self.get(infos.addr_type, infos.address) else {
+            return true;
+        };
+        if !device.tracked {
+            return false;
+        }
+        if !device.identified {
+            // Nothing must allow to identify the aircraft
+            infos.callsign.clear();
+            return true;
+        }
+
+        infos.registration.clone_from(&device.registration);
+        infos.competition_number.clone_from(&device.competition_number);
+        infos.aircraft_model.clone_from(&device.aircraft_model);
+
+        // The competition number is the most useful to glider pilots
+        if !device.competition_number.is_empty() {
+            infos.callsign.clone_from(&device.competition_number);
+        }
+        else if !device.registration.is_empty() {
+            infos.callsign.clone_from(&device.registration);
+        }
+        true
+    }
+}
+
+
+/// Refresh of the local file of the database and loading of its content
+pub struct DdbRefresh {
+    config: SharedConfig,
+    ddb: SharedDdb,
+    loaded_modified: Option<SystemTime>,    // Modification time of the loaded file
+    last_failure: Option<Instant>,          // Time of the last failed download
+}
+
+impl DdbRefresh {
+    /// Starts the periodic refresh of the database
+    /// The local file is loaded before, so that the wishes of the owners are known as soon as the traffic is received
+    pub fn start(config: &SharedConfig, ddb: &SharedDdb, stop_flag: StopFlag) -> thread::JoinHandle<()> {
+        let mut refresh = Self::new(config, ddb);
+        if let Err(e) = refresh.load(&config.get().ddb) {
+            log::warn!("{:#}", e);
+        }
+        thread::spawn(move || {
+            refresh.work_thread(&stop_flag);
+        })
+    }
+
+
+    fn new(config: &SharedConfig, ddb: &SharedDdb) -> DdbRefresh {
+        DdbRefresh {
+            config: config.clone(),
+            ddb: ddb.clone(),
+            loaded_modified: None,
+            last_failure: None,
+        }
+    }
+
+
+    fn work_thread(&mut self, stop_flag: &StopFlag) {
+        loop {
+            // The configuration is read at each check to take into account a reload
+            let config = self.config.get();
+            if let Err(e) = self.refresh(&config.ddb) {
+                log::warn!("{:#}", e);
+            }
+            if !stop_flag.sleep(CHECK_PERIOD) {
+                break;
+            }
+        }
+        log::info!("OGN DDB refresh stopped");
+    }
+
+
+    // Downloads the database if the local file is missing or too old, then loads the file
+    fn refresh(&mut self, config: &DdbConfig) -> anyhow::Result<()> {
+        let age = fs::metadata(&config.file).and_then(|metadata| metadata.modified())
+            .map(|modified| SystemTime::now().duration_since(modified).unwrap_or_default())
+            .ok();
+        let outdated = age.is_none_or(|age| age >= Duration::from_secs(config.refresh_interval));
+        let retry_allowed = self.last_failure.is_none_or(|failure| failure.elapsed() >= DOWNLOAD_RETRY_DELAY);
+        if outdated && !config.url.is_empty() && retry_allowed {
+            match Self::download(&config.url, &config.file) {
+                Ok(()) => self.last_failure = None,
+                Err(e) => {
+                    self.last_failure = Some(Instant::now());
+                    // The previous file is still loaded if there is one
+                    log::warn!("{:#}", e);
+                }
+            }
+        }
+
+        self.load(config)
+    }
+
+
+    // Loads the local file if it has changed since the last loading, it can also be replaced by another program
+    fn load(&mut self, config: &DdbConfig) -> anyhow::Result<()> {
+        let Ok(modified) = fs::metadata(&config.file).and_then(|metadata| metadata.modified()) else {
+            return Ok(());
+        };
+        if self.loaded_modified != Some(modified) {
+            let json = fs::read_to_string(&config.file).with_context(|| format!("Failed to read {}", config.file.display()))?;
+            self.loaded_modified = Some(modified);
+            let ddb = Ddb::parse(&json)?;
+            log::info!("OGN DDB loaded : {} devices", ddb.len());
+            *self.ddb.write().unwrap() = ddb;
+        }
+        Ok(())
+    }
+
+
+    // Downloads the database into a temporary file which then replaces the local file,
+    // so that an interrupted download does not leave an incomplete file
+    fn download(url: &str, file: &Path) -> anyhow::Result<()> {
+        let mut temp_file = PathBuf::from(file);
+        temp_file.as_mut_os_string().push(".tmp");
+
+        let response = ureq::get(url)
+            .timeout(DOWNLOAD_TIMEOUT)
+            .call()
+            .context("Failed to download the OGN DDB")?;
+        let mut output = fs::File::create(&temp_file).with_context(|| format!("Failed to create {}", temp_file.display()))?;
+        io::copy(&mut response.into_reader(), &mut output).context("Failed to download the OGN DDB")?;
+        fs::rename(&temp_file, file).with_context(|| format!("Failed to replace {}", file.display()))?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JSON: &str = r#"{"devices":[
+        {"device_type":"F","device_id":"DDA5BA","aircraft_model":"LS-4","registration":"F-CHBA","cn":"BA","tracked":"Y","identified":"Y","aircraft_type":"1"},
+        {"device_type":"O","device_id":"2A3F61","aircraft_model":"DG-800","registration":"D-KDGX","cn":"","tracked":"Y","identified":"Y","aircraft_type":"1"},
+        {"device_type":"F","device_id":"DD1234","aircraft_model":"Duo Discus","registration":"F-CDUO","cn":"DU","tracked":"N","identified":"Y","aircraft_type":"1"},
+        {"device_type":"I","device_id":"3950A1","aircraft_model":"Robin DR400","registration":"F-GABC","cn":"","tracked":"Y","identified":"N","aircraft_type":"2"},
+        {"device_type":"X","device_id":"123456","aircraft_model":"","registration":"","cn":"","tracked":"Y","identified":"Y"},
+        {"device_type":"F","device_id":"ZZZZZZ","aircraft_model":"","registration":"","cn":"","tracked":"Y","identified":"Y"}
+    ]}"#;
+
+
+    fn traffic(addr_type: AddressType, address: u32, callsign: &str) -> TrafficInfos {
+        TrafficInfos { addr_type, address, callsign: String::from(callsign), ..Default::default() }
+    }
+
+
+    #[test]
+    fn parse_devices() {
+        let ddb = Ddb::parse(JSON).unwrap();
+        assert_eq!(ddb.len(), 4);
+        assert_eq!(ddb.get(AddressType::Flarm, 0xdda5ba), Some(&Device {
+            registration: String::from("F-CHBA"),
+            competition_number: String::from("BA"),
+            aircraft_model: String::from("LS-4"),
+            tracked: true,
+            identified: true,
+        }));
+        assert!(ddb.get(AddressType::Ogn, 0x2a3f61).is_some());
+        assert!(ddb.get(AddressType::Flarm, 0x2a3f61).is_none());
+        assert!(!ddb.get(AddressType::AdsbIcao, 0x3950a1).unwrap().identified);
+        assert!(Ddb::parse("{\"devices\":[{\"device_type\":\"F\"}]}").is_err());
+        assert!(Ddb::parse("<html>").is_err());
+    }
+
+
+    #[test]
+    fn apply_wishes_and_information() {
+        let ddb = Ddb::parse(JSON).unwrap();
+
+        // Competition number preferred to the registration for the callsign
+        let mut infos = traffic(AddressType::Flarm, 0xdda5ba, "");
+        assert!(ddb.apply(&mut infos));
+        assert_eq!((infos.callsign.as_str(), infos.registration.as_str(), infos.competition_number.as_str(), infos.aircraft_model.as_str()),
+            ("BA", "F-CHBA", "BA", "LS-4"));
+        let mut infos = traffic(AddressType::Ogn, 0x2a3f61, "");
+        assert!(ddb.apply(&mut infos));
+        assert_eq!(infos.callsign, "D-KDGX");
+
+        // No-track devices are not published, no-ident ones are published without identification
+        assert!(!ddb.apply(&mut traffic(AddressType::Flarm, 0xdd1234, "DU")));
+        let mut infos = traffic(AddressType::AdsbIcao, 0x3950a1, "F-GABC");
+        assert!(ddb.apply(&mut infos));
+        assert_eq!(infos.callsign, "");
+        assert_eq!(infos.registration, "");
+        assert_eq!(infos.aircraft_model, "");
+
+        // Unknown devices are published as received
+        let mut infos = traffic(AddressType::Random, 0xdda5ba, "XYZ");
+        assert!(ddb.apply(&mut infos));
+        assert_eq!(infos.callsign, "XYZ");
+    }
+}
@@ -1,21 +1,153 @@
-//! Datagram over Stream: sending and receiving datagrams over a blocking TCP socket.
+//! Datagram over Stream: sending and receiving datagrams over a non-blocking TCP socket.
 //! A header representing the size of the datagram in 16-bit big endian is
 //! inserted in front of each datagram to allow its reception in stream mode
 //! 
 
-use std::{io::{Read, Write}, net::TcpStream};
+use crate::config::DropPolicy;
 
+use std::{collections::VecDeque, io::{IoSlice, Read, Write}, net::TcpStream, sync::Arc};
 
-/// Send a datagram
-pub fn send(mut sock: &TcpStream, buf: &[u8]) -> anyhow::Result<()> {
-    // Sending the header containing the size of the buffer in big endian
-    let buf_len_bytes = u16::try_from(buf.len())?.to_be_bytes();
-    sock.write_all(&buf_len_bytes)?;
 
-    // Sending the buffer
-    sock.write_all(buf)?;
+// Size of the header of a datagram
+const HEADER_LEN: usize = 2;
+
+
+/// Queue of the datagrams waiting to be sent, when the socket cannot accept them immediately
+/// The datagrams with a key are dropped when the queue is full, according to the drop policy,
+/// to leave room for the other ones. A queue full of datagrams without key is an error.
+pub struct SendQueue<K: PartialEq> {
+    queue: VecDeque<(Option<K>, Arc<[u8]>)>,
+    offset: usize,                  // Bytes of the first datagram, header included, already sent
+    max_len: usize,                 // Maximum number of datagrams
+    nb_keyed: usize,                // Number of queued datagrams with a key
+    drop_policy: DropPolicy,
+    nb_dropped: u64,                // Datagrams dropped or replaced by a more recent one
+    nb_full: u64,                   // Times a datagram was queued while the queue was full
+}
+
+impl<K: PartialEq> SendQueue<K> {
+    pub fn new(max_len: usize, drop_policy: DropPolicy) -> Self {
+        Self {
+            queue: VecDeque::new(),
+            offset: 0,
+            max_len,
+            nb_keyed: 0,
+            drop_policy,
+            nb_dropped: 0,
+            nb_full: 0,
+        }
+    }
+
+
+    /// Changes the limit and the policy, they apply to the next datagrams
+    pub fn set_limits(&mut self, max_len: usize, drop_policy: DropPolicy) {
+        self.max_len = max_len;
+        self.drop_policy = drop_policy;
+    }
+
+
+    /// Adds a datagram at the end of the queue
+    pub fn push(&mut self, key: Option<K>, datagram: Arc<[u8]>) -> anyhow::Result<()> {
+        anyhow::ensure!(u16::try_from(datagram.len()).is_ok(), "Datagram too long ({} bytes)", datagram.len());
+
+        // The first datagram cannot be changed once its sending has begun
+        let first_changeable = usize::from(self.offset > 0);
+
+        if key.is_some() {
+            // A more recent datagram with the same key replaces the queued one
+            if self.drop_policy == DropPolicy::LatestPerAircraft {
+                if let Some(queued) = self.queue.iter_mut().skip(first_changeable).find(|(queued_key, _)| *queued_key == key) {
+                    queued.1 = datagram;
+                    self.nb_dropped += 1;
+                    return Ok(());
+                }
+            }
+
+            // Otherwise a datagram is dropped if the queue is full
+            if self.queue.len() >= self.max_len {
+                self.nb_full += 1;
+                self.nb_dropped += 1;
+                if (self.drop_policy == DropPolicy::Newest) || !self.drop_oldest_keyed(first_changeable) {
+                    return Ok(());
+                }
+            }
+        }
+        else if self.queue.len() >= self.max_len {
+            // A datagram without key takes the place of the oldest one with a key
+            self.nb_full += 1;
+            anyhow::ensure!(self.drop_oldest_keyed(first_changeable), "Send queue full");
+            self.nb_dropped += 1;
+        }
+
+        if key.is_some() {
+            self.nb_keyed += 1;
+        }
+        self.queue.push_back((key, datagram));
+        Ok(())
+    }
+
+
+    // Drops the oldest datagram with a key which is not being sent
+    // Returns false if there is no such datagram
+    fn drop_oldest_keyed(&mut self, first_changeable: usize) -> bool {
+        if self.nb_keyed == 0 {
+            return false;
+        }
+        match self.queue.iter().skip(first_changeable).position(|(queued_key, _)| queued_key.is_some()) {
+            Some(i) => {
+                self.queue.remove(first_changeable + i);
+                self.nb_keyed -= 1;
+                true
+            }
+            None => false,      // Only the datagram being sent
+        }
+    }
+
+
+    /// Sends as many datagrams as possible without blocking
+    pub fn flush(&mut self, mut sock: &TcpStream) -> anyhow::Result<()> {
+        while let Some((_, datagram)) = self.queue.front() {
+            // The header and the datagram are sent together, from where the previous sending stopped
+            let header = (datagram.len() as u16).to_be_bytes();
+            let slices = if self.offset < HEADER_LEN {
+                [IoSlice::new(&header[self.offset..]), IoSlice::new(datagram)]
+            }
+            else {
+                [IoSlice::new(&datagram[(self.offset - HEADER_LEN)..]), IoSlice::new(&[])]
+            };
+
+            match sock.write_vectored(&slices) {
+                Ok(0) => anyhow::bail!("Connection closed by remote"),
+                Ok(nb) => {
+                    self.offset += nb;
+                    if self.offset >= HEADER_LEN + datagram.len() {
+                        // The datagram has been completely sent
+                        if let Some((Some(_), _)) = self.queue.pop_front() {
+                            self.nb_keyed -= 1;
+                        }
+                        self.offset = 0;
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,   // The socket buffer is full
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+
+    /// Indicates whether all the datagrams have been sent
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+
+    /// Get the number of datagrams dropped and the number of times the queue was full
+    pub fn stats(&self) -> (u64, u64) {
+        (self.nb_dropped, self.nb_full)
+    }
 
-    Ok(())
 }
 
 
@@ -94,3 +226,72 @@ impl RecvDgram {
     }
 
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datagram(value: u8) -> Arc<[u8]> {
+        Arc::from(&[value][..])
+    }
+
+
+    fn contents(queue: &SendQueue<u32>) -> Vec<(Option<u32>, u8)> {
+        queue.queue.iter().map(|(key, datagram)| (*key, datagram[0])).collect()
+    }
+
+
+    #[test]
+    fn keyed_datagrams_dropped_when_full() {
+        let mut queue = SendQueue::new(3, DropPolicy::Oldest);
+        for i in 0..5 {
+            queue.push(Some(u32::from(i)), datagram(i)).unwrap();
+        }
+        assert_eq!(contents(&queue), [(Some(2), 2), (Some(3), 3), (Some(4), 4)]);
+
+        queue.set_limits(3, DropPolicy::Newest);
+        queue.push(Some(5), datagram(5)).unwrap();
+        assert_eq!(contents(&queue), [(Some(2), 2), (Some(3), 3), (Some(4), 4)]);
+
+        // The more recent report of an aircraft replaces the queued one
+        queue.set_limits(3, DropPolicy::LatestPerAircraft);
+        queue.push(Some(3), datagram(6)).unwrap();
+        assert_eq!(contents(&queue), [(Some(2), 2), (Some(3), 6), (Some(4), 4)]);
+        assert_eq!(queue.stats(), (4, 3));
+    }
+
+
+    #[test]
+    fn datagrams_without_key_bounded() {
+        let mut queue = SendQueue::new(3, DropPolicy::Newest);
+        queue.push(Some(1), datagram(1)).unwrap();
+        queue.push(None, datagram(2)).unwrap();
+        queue.push(Some(3), datagram(3)).unwrap();
+
+        // The reports make room for the other datagrams, whatever the drop policy
+        queue.push(None, datagram(4)).unwrap();
+        assert_eq!(contents(&queue), [(None, 2), (Some(3), 3), (None, 4)]);
+        queue.push(None, datagram(5)).unwrap();
+        assert_eq!(contents(&queue), [(None, 2), (None, 4), (None, 5)]);
+        assert_eq!(queue.nb_keyed, 0);
+
+        // A report is dropped, a datagram without key cannot be queued any more
+        queue.push(Some(6), datagram(6)).unwrap();
+        assert!(queue.push(None, datagram(7)).is_err());
+        assert_eq!(queue.queue.len(), 3);
+    }
+
+
+    #[test]
+    fn datagram_being_sent_kept() {
+        let mut queue = SendQueue::new(2, DropPolicy::Oldest);
+        queue.push(Some(1), datagram(1)).unwrap();
+        queue.push(Some(2), datagram(2)).unwrap();
+        queue.offset = 1;
+        queue.push(Some(3), datagram(3)).unwrap();
+        assert_eq!(contents(&queue), [(Some(1), 1), (Some(3), 3)]);
+        queue.push(Some(1), datagram(4)).unwrap();
+        assert_eq!(contents(&queue), [(Some(1), 1), (Some(1), 4)]);
+    }
+}
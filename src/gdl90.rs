@@ -1,5 +1,6 @@
-//! GDL90 message formatting
+//! GDL90 message formatting and decoding
 //! See <https://www.faa.gov/sites/faa.gov/files/air_traffic/technology/adsb/archival/GDL90_Public_ICD_RevA.PDF>
+//! The decoder is used to check the messages sent by the server.
 //! 
 
 use crate::traffic_infos::{TrafficInfos, AddressType};
@@ -13,6 +14,39 @@ const FLAG_BYTE: u8 = 0x7e;
 const CONTROL_ESCAPE_CHAR: u8 = 0x7d;
 
 
+// HEARTBEAT message
+
+const HEARTBEAT_MESSAGE_ID: u8 = 0;
+const HEARTBEAT_LEN: usize = 6;
+
+const HEARTBEAT_STATUS_BYTE_1_OFFSET: usize = 0;
+const HEARTBEAT_STATUS_BYTE_2_OFFSET: usize = 1;
+const HEARTBEAT_TIMESTAMP_OFFSET: usize = 2;
+const HEARTBEAT_MESSAGE_COUNTS_OFFSET: usize = 4;
+
+const HEARTBEAT_GPS_POS_VALID: u8 = 0x80;
+const HEARTBEAT_MAINT_REQD: u8 = 0x40;
+const HEARTBEAT_UAT_INITIALIZED: u8 = 0x01;
+const HEARTBEAT_TIMESTAMP_MSB: u8 = 0x80;
+const HEARTBEAT_UTC_OK: u8 = 0x01;
+
+
+// OWNSHIP REPORT message, same format as the TRAFFIC REPORT message
+
+const OWNSHIP_REPORT_MESSAGE_ID: u8 = 10;
+
+
+// OWNSHIP GEOMETRIC ALTITUDE message
+
+const OWNSHIP_GEOMETRIC_ALTITUDE_MESSAGE_ID: u8 = 11;
+const OWNSHIP_GEOMETRIC_ALTITUDE_LEN: usize = 4;
+
+const OWNSHIP_GEOMETRIC_ALTITUDE_ALTITUDE_OFFSET: usize = 0;
+const OWNSHIP_GEOMETRIC_ALTITUDE_VERTICAL_METRICS_OFFSET: usize = 2;
+
+const OWNSHIP_GEOMETRIC_ALTITUDE_VFOM_NOT_AVAILABLE: u16 = 0x7fff;
+
+
 // TRAFFIC REPORT message
 
 const TRAFFIC_REPORT_MESSAGE_ID: u8 = 20;
@@ -23,10 +57,32 @@ const TRAFFIC_REPORT_LATITUDE_OFFSET: usize = 4;
 const TRAFFIC_REPORT_LONGITUDE_OFFSET: usize = 7;
 const TRAFFIC_REPORT_ALTITUDE_OFFSET: usize = 10;
 const TRAFFIC_REPORT_MISC_INDICATOR_OFFSET: usize = 11;
+const TRAFFIC_REPORT_NAVIGATION_OFFSET: usize = 12;
 const TRAFFIC_REPORT_HORIZONTAL_VELOCITY_OFFSET: usize = 13;
 const TRAFFIC_REPORT_VERTICAL_VELOCITY_OFFSET: usize = 14;
 const TRAFFIC_REPORT_TRACK_OFFSET: usize = 16;
+const TRAFFIC_REPORT_EMITTER_CATEGORY_OFFSET: usize = 17;
 const TRAFFIC_REPORT_CALLSIGN_OFFSET: usize = 18;
+const TRAFFIC_REPORT_CALLSIGN_LEN: usize = 8;
+
+
+// FOREFLIGHT ID message, see <https://www.foreflight.com/connect/spec/>
+
+const FOREFLIGHT_MESSAGE_ID: u8 = 0x65;
+const FOREFLIGHT_ID_SUB_ID: u8 = 0;
+const FOREFLIGHT_ID_LEN: usize = 38;
+const FOREFLIGHT_ID_VERSION: u8 = 1;
+
+const FOREFLIGHT_ID_SUB_ID_OFFSET: usize = 0;
+const FOREFLIGHT_ID_VERSION_OFFSET: usize = 1;
+const FOREFLIGHT_ID_SERIAL_NUMBER_OFFSET: usize = 2;
+const FOREFLIGHT_ID_NAME_OFFSET: usize = 10;
+const FOREFLIGHT_ID_NAME_LEN: usize = 8;
+const FOREFLIGHT_ID_LONG_NAME_OFFSET: usize = 18;
+const FOREFLIGHT_ID_LONG_NAME_LEN: usize = 16;
+const FOREFLIGHT_ID_CAPABILITIES_OFFSET: usize = 34;
+
+const FOREFLIGHT_ID_SERIAL_NUMBER_INVALID: u64 = u64::MAX;
 
 
 // CRC table
@@ -66,9 +122,161 @@ const CRC_ARRAY: [u16; 256] = [
 ];
 
 
+/// Status of the device given by the HEARTBEAT message
+#[derive(Debug, PartialEq)]
+pub struct HeartbeatStatus {
+    pub position_valid: bool,           // Position of the ownship known
+    pub maintenance_required: bool,     // A part of the device does not work
+    pub initialized: bool,              // The device is able to provide traffic
+    pub time_of_day: Option<u32>,       // Seconds since 0000Z, None if the UTC time is not known
+    pub nb_messages: u16,               // Number of messages sent during the previous second
+}
+
+
+/// Formats a HEARTBEAT message in a provided buffer
+/// Returns the used size of the buffer
+pub fn make_heartbeat_message(status: &HeartbeatStatus, buffer: &mut [u8]) -> anyhow::Result<usize> {
+    let mut buf = [0u8; HEAD_LEN + HEARTBEAT_LEN + TAIL_LEN];
+
+    // Status bits
+    {
+        let mut status_byte_1 = 0u8;
+        if status.position_valid {
+            status_byte_1 |= HEARTBEAT_GPS_POS_VALID;
+        }
+        if status.maintenance_required {
+            status_byte_1 |= HEARTBEAT_MAINT_REQD;
+        }
+        if status.initialized {
+            status_byte_1 |= HEARTBEAT_UAT_INITIALIZED;
+        }
+        buf[HEAD_LEN + HEARTBEAT_STATUS_BYTE_1_OFFSET] = status_byte_1;
+    }
+
+    // Time stamp on 17 bits, its most significant bit is in the second status byte
+    {
+        let (timestamp, utc_ok) = match status.time_of_day {
+            None => (0, 0),
+            Some(time_of_day) => (time_of_day % 86_400, HEARTBEAT_UTC_OK),
+        };
+        let timestamp_msb = if timestamp > 0xffff { HEARTBEAT_TIMESTAMP_MSB } else { 0 };
+        buf[HEAD_LEN + HEARTBEAT_STATUS_BYTE_2_OFFSET] = timestamp_msb | utc_ok;
+        let offset = HEAD_LEN + HEARTBEAT_TIMESTAMP_OFFSET;
+        buf[offset] = (timestamp & 0xff) as u8;             // LSB first
+        buf[offset + 1] = ((timestamp >> 8) & 0xff) as u8;
+    }
+
+    // Message counts, no uplink message, basic and long messages on 10 bits
+    {
+        let nb_messages = status.nb_messages.min(0x3ff);
+        let offset = HEAD_LEN + HEARTBEAT_MESSAGE_COUNTS_OFFSET;
+        buf[offset] = ((nb_messages >> 8) & 0x03) as u8;
+        buf[offset + 1] = (nb_messages & 0xff) as u8;
+    }
+
+    // Filling header and tail fields
+    fill_header_and_tail(HEARTBEAT_MESSAGE_ID, &mut buf);
+
+    // Application of byte-stuffing
+    byte_stuff(&buf, buffer)
+}
+
+
+/// Formats a FOREFLIGHT ID message in a provided buffer
+/// The names are truncated to their maximum length (8 and 16 bytes)
+/// Returns the used size of the buffer
+pub fn make_foreflight_id_message(serial_number: Option<u64>, name: &str, long_name: &str, capabilities: u32, buffer: &mut [u8]) -> anyhow::Result<usize> {
+    let mut buf = [0u8; HEAD_LEN + FOREFLIGHT_ID_LEN + TAIL_LEN];
+
+    // Sub-ID and version
+    buf[HEAD_LEN + FOREFLIGHT_ID_SUB_ID_OFFSET] = FOREFLIGHT_ID_SUB_ID;
+    buf[HEAD_LEN + FOREFLIGHT_ID_VERSION_OFFSET] = FOREFLIGHT_ID_VERSION;
+
+    // Serial number on 64 bits
+    {
+        let offset = HEAD_LEN + FOREFLIGHT_ID_SERIAL_NUMBER_OFFSET;
+        let serial_number = serial_number.unwrap_or(FOREFLIGHT_ID_SERIAL_NUMBER_INVALID);
+        buf[offset..(offset + 8)].copy_from_slice(&serial_number.to_be_bytes());
+    }
+
+    // Names in UTF-8, padded with null bytes, truncated without cutting a character
+    {
+        let offset = HEAD_LEN + FOREFLIGHT_ID_NAME_OFFSET;
+        let len = name.floor_char_boundary(FOREFLIGHT_ID_NAME_LEN);
+        buf[offset..(offset + len)].copy_from_slice(&name.as_bytes()[..len]);
+
+        let offset = HEAD_LEN + FOREFLIGHT_ID_LONG_NAME_OFFSET;
+        let len = long_name.floor_char_boundary(FOREFLIGHT_ID_LONG_NAME_LEN);
+        buf[offset..(offset + len)].copy_from_slice(&long_name.as_bytes()[..len]);
+    }
+
+    // Capabilities mask on 32 bits
+    {
+        let offset = HEAD_LEN + FOREFLIGHT_ID_CAPABILITIES_OFFSET;
+        buf[offset..(offset + 4)].copy_from_slice(&capabilities.to_be_bytes());
+    }
+
+    // Filling header and tail fields
+    fill_header_and_tail(FOREFLIGHT_MESSAGE_ID, &mut buf);
+
+    // Application of byte-stuffing
+    byte_stuff(&buf, buffer)
+}
+
+
 /// Formats a TRAFFIC REPORT message in a provided buffer
 /// Returns the used size of the buffer
 pub fn make_traffic_report_message(infos: &TrafficInfos, buffer: &mut [u8]) -> anyhow::Result<usize> {
+    make_report_message(TRAFFIC_REPORT_MESSAGE_ID, infos, Some(infos.altitude), buffer)
+}
+
+
+/// Formats an OWNSHIP REPORT message in a provided buffer, from the position of the ownship
+/// and its pressure altitude in ft if known
+/// Returns the used size of the buffer
+pub fn make_ownship_report_message(addr_type: AddressType, address: u32, latitude: f64, longitude: f64, altitude: Option<i32>, buffer: &mut [u8]) -> anyhow::Result<usize> {
+    let infos = TrafficInfos {
+        addr_type,
+        address,
+        latitude,
+        longitude,
+        ..Default::default()
+    };
+    make_report_message(OWNSHIP_REPORT_MESSAGE_ID, &infos, altitude, buffer)
+}
+
+
+/// Formats an OWNSHIP GEOMETRIC ALTITUDE message in a provided buffer, from the altitude in ft
+/// Returns the used size of the buffer
+pub fn make_ownship_geometric_altitude_message(altitude: i32, buffer: &mut [u8]) -> anyhow::Result<usize> {
+    let mut buf = [0u8; HEAD_LEN + OWNSHIP_GEOMETRIC_ALTITUDE_LEN + TAIL_LEN];
+
+    // Altitude on 16 signed bits, in 5 ft units
+    {
+        let altitude = (altitude / 5).clamp(i32::from(i16::MIN), i32::from(i16::MAX));
+        let offset = HEAD_LEN + OWNSHIP_GEOMETRIC_ALTITUDE_ALTITUDE_OFFSET;
+        buf[offset] = ((altitude >> 8) & 0xff) as u8;
+        buf[offset + 1] = (altitude & 0xff) as u8;
+    }
+
+    // Vertical metrics, no vertical warning and accuracy not available
+    {
+        let offset = HEAD_LEN + OWNSHIP_GEOMETRIC_ALTITUDE_VERTICAL_METRICS_OFFSET;
+        buf[offset] = ((OWNSHIP_GEOMETRIC_ALTITUDE_VFOM_NOT_AVAILABLE >> 8) & 0xff) as u8;
+        buf[offset + 1] = (OWNSHIP_GEOMETRIC_ALTITUDE_VFOM_NOT_AVAILABLE & 0xff) as u8;
+    }
+
+    // Filling header and tail fields
+    fill_header_and_tail(OWNSHIP_GEOMETRIC_ALTITUDE_MESSAGE_ID, &mut buf);
+
+    // Application of byte-stuffing
+    byte_stuff(&buf, buffer)
+}
+
+
+// Formats a TRAFFIC REPORT or an OWNSHIP REPORT message in a provided buffer
+// The altitude replaces the one of infos, None if it is not known
+fn make_report_message(message_id: u8, infos: &TrafficInfos, altitude: Option<i32>, buffer: &mut [u8]) -> anyhow::Result<usize> {
     let mut buf = [0u8; HEAD_LEN + TRAFFIC_REPORT_LEN + TAIL_LEN];
 
     // Address
@@ -100,12 +308,18 @@ pub fn make_traffic_report_message(infos: &TrafficInfos, buffer: &mut [u8]) -> a
         buf[offset + 2] = (longitude & 0xff) as u8;
     }
 
-    // Altitude on 12 bits, 1000 ft offset
+    // Altitude on 12 bits, 1000 ft offset, 0xfff if not known
     {
-        let mut altitude = ((if infos.altitude < -1000 { -1000 } else {infos.altitude}) + 1000) / 25;
-        if altitude > 0xffe {
-            altitude = 0xffe;
-        }
+        let altitude = match altitude {
+            None => 0xfff,
+            Some(altitude) => {
+                let mut altitude = ((if altitude < -1000 { -1000 } else { altitude }) + 1000) / 25;
+                if altitude > 0xffe {
+                    altitude = 0xffe;
+                }
+                altitude
+            }
+        };
         let offset = HEAD_LEN + TRAFFIC_REPORT_ALTITUDE_OFFSET;
         buf[offset] = ((altitude >> 4) & 0xff) as u8;
         buf[offset + 1] |= ((altitude << 4) & 0xf0) as u8;
@@ -167,14 +381,14 @@ pub fn make_traffic_report_message(infos: &TrafficInfos, buffer: &mut [u8]) -> a
     {
         let offset = HEAD_LEN + TRAFFIC_REPORT_CALLSIGN_OFFSET;
         let size_callsign = infos.callsign.chars().count();
-        for i in 0usize..8 {
+        for i in 0usize..TRAFFIC_REPORT_CALLSIGN_LEN {
             let c_to_append = if i < size_callsign { infos.callsign.chars().nth(i).unwrap() } else { ' ' };
-            buf[offset + 1] = if c_to_append.is_ascii() { c_to_append as u8 } else { b'?' };
+            buf[offset + i] = if c_to_append.is_ascii() { c_to_append as u8 } else { b'?' };
         }
     }
 
     // Filling header and tail fields
-    fill_header_and_tail(TRAFFIC_REPORT_MESSAGE_ID, &mut buf);
+    fill_header_and_tail(message_id, &mut buf);
 
     // Application of byte-stuffing
     byte_stuff(&buf, buffer)
@@ -185,7 +399,22 @@ impl From<&AddressType> for u8 {
     fn from(value: &AddressType) -> Self {
         match value {
             AddressType::AdsbIcao => 0,
-            AddressType::Ogn => 6
+            AddressType::Random => 1,       // ADS-B with self-assigned address
+            AddressType::Flarm | AddressType::Ogn => 6
+        }
+    }
+}
+
+
+impl TryFrom<u8> for AddressType {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(AddressType::AdsbIcao),
+            1 => Ok(AddressType::Random),
+            6 => Ok(AddressType::Flarm),
+            _ => Err(anyhow::anyhow!("Unknown address type {}", value)),
         }
     }
 }
@@ -243,3 +472,410 @@ fn byte_stuff(message: &[u8], buffer: &mut [u8]) -> anyhow::Result<usize> {
 
     Ok(cur_len)
 }
+
+
+fn byte_unstuff(message: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut unstuffed = Vec::with_capacity(message.len());
+
+    let mut bytes = message.iter();
+    while let Some(&val) = bytes.next() {
+        if val == CONTROL_ESCAPE_CHAR {
+            // The next byte is the escaped one
+            let &escaped = bytes.next().ok_or_else(|| anyhow::anyhow!("Control escape character at the end of the message"))?;
+            unstuffed.push(escaped ^ 0x20);
+        }
+        else {
+            anyhow::ensure!(val != FLAG_BYTE, "Flag byte inside the message");
+            unstuffed.push(val);
+        }
+    }
+
+    Ok(unstuffed)
+}
+
+
+/// Report of a TRAFFIC REPORT or an OWNSHIP REPORT message
+#[cfg_attr(not(test), allow(dead_code))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    pub traffic_alert: bool,
+    pub addr_type: u8,
+    pub address: u32,                   // on 24 bits
+    pub latitude: f64,                  // in degrees
+    pub longitude: f64,                 // in degrees
+    pub altitude: Option<i32>,          // in ft, None if not known
+    pub misc_indicator: u8,
+    pub nic: u8,
+    pub nacp: u8,
+    pub ground_speed: Option<u32>,      // in kt, None if not known
+    pub vertical_speed: Option<i32>,    // in fpm, None if not known
+    pub track: Option<f64>,             // in degrees, None if not valid
+    pub emitter_category: u8,
+    pub callsign: String,               // without the trailing spaces
+}
+
+
+/// Decoded GDL90 message
+#[cfg_attr(not(test), allow(dead_code))]
+#[derive(Debug, PartialEq)]
+pub enum Message {
+    Heartbeat(HeartbeatStatus),
+    OwnshipReport(Report),
+    OwnshipGeometricAltitude { altitude: i32, vertical_warning: bool, vfom: Option<u16> },
+    TrafficReport(Report),
+    Other { message_id: u8, data: Vec<u8> },
+}
+
+
+/// Decodes a GDL90 message, from its start flag to its end flag included
+/// The byte-stuffing is removed and the CRC is checked
+#[cfg_attr(not(test), allow(dead_code))]
+pub fn decode_message(message: &[u8]) -> anyhow::Result<Message> {
+    anyhow::ensure!(message.len() >= HEAD_LEN + TAIL_LEN, "Message too short");
+    anyhow::ensure!((message[0] == FLAG_BYTE) && (message[message.len() - 1] == FLAG_BYTE), "Missing flag byte");
+
+    // Message id, message data and CRC, without byte-stuffing
+    let unstuffed = byte_unstuff(&message[1..(message.len() - 1)])?;
+    anyhow::ensure!(unstuffed.len() >= 3, "Message too short");
+    let (content, crc) = unstuffed.split_at(unstuffed.len() - 2);
+    let crc = u16::from(crc[0]) | (u16::from(crc[1]) << 8);     // LSB first
+    anyhow::ensure!(crc == compute_crc(content), "Invalid CRC");
+
+    let message_id = content[0];
+    let data = &content[1..];
+    let check_len = |len: usize| {
+        anyhow::ensure!(data.len() == len, "Invalid length {} for message {}", data.len(), message_id);
+        Ok(())
+    };
+    match message_id {
+        HEARTBEAT_MESSAGE_ID => {
+            check_len(HEARTBEAT_LEN)?;
+            Ok(Message::Heartbeat(decode_heartbeat(data)))
+        }
+        OWNSHIP_REPORT_MESSAGE_ID => {
+            check_len(TRAFFIC_REPORT_LEN)?;
+            Ok(Message::OwnshipReport(decode_report(data)))
+        }
+        OWNSHIP_GEOMETRIC_ALTITUDE_MESSAGE_ID => {
+            check_len(OWNSHIP_GEOMETRIC_ALTITUDE_LEN)?;
+            let altitude = i32::from(i16::from_be_bytes([data[0], data[1]])) * 5;
+            let vertical_metrics = u16::from_be_bytes([data[2], data[3]]);
+            let vfom = vertical_metrics & 0x7fff;
+            Ok(Message::OwnshipGeometricAltitude {
+                altitude,
+                vertical_warning: (vertical_metrics & 0x8000) != 0,
+                vfom: if vfom == OWNSHIP_GEOMETRIC_ALTITUDE_VFOM_NOT_AVAILABLE { None } else { Some(vfom) },
+            })
+        }
+        TRAFFIC_REPORT_MESSAGE_ID => {
+            check_len(TRAFFIC_REPORT_LEN)?;
+            Ok(Message::TrafficReport(decode_report(data)))
+        }
+        _ => Ok(Message::Other { message_id, data: data.to_vec() }),
+    }
+}
+
+
+fn decode_heartbeat(data: &[u8]) -> HeartbeatStatus {
+    let status_byte_1 = data[HEARTBEAT_STATUS_BYTE_1_OFFSET];
+    let status_byte_2 = data[HEARTBEAT_STATUS_BYTE_2_OFFSET];
+
+    let mut timestamp = u32::from(data[HEARTBEAT_TIMESTAMP_OFFSET]) | (u32::from(data[HEARTBEAT_TIMESTAMP_OFFSET + 1]) << 8);
+    if (status_byte_2 & HEARTBEAT_TIMESTAMP_MSB) != 0 {
+        timestamp |= 0x1_0000;
+    }
+
+    HeartbeatStatus {
+        position_valid: (status_byte_1 & HEARTBEAT_GPS_POS_VALID) != 0,
+        maintenance_required: (status_byte_1 & HEARTBEAT_MAINT_REQD) != 0,
+        initialized: (status_byte_1 & HEARTBEAT_UAT_INITIALIZED) != 0,
+        time_of_day: if (status_byte_2 & HEARTBEAT_UTC_OK) != 0 { Some(timestamp) } else { None },
+        nb_messages: (u16::from(data[HEARTBEAT_MESSAGE_COUNTS_OFFSET] & 0x03) << 8) | u16::from(data[HEARTBEAT_MESSAGE_COUNTS_OFFSET + 1]),
+    }
+}
+
+
+fn decode_report(data: &[u8]) -> Report {
+    // Signed value on 24 bits
+    let read_i24 = |offset: usize| (i32::from_be_bytes([data[offset], data[offset + 1], data[offset + 2], 0])) >> 8;
+
+    let altitude = (u32::from(data[TRAFFIC_REPORT_ALTITUDE_OFFSET]) << 4) | (u32::from(data[TRAFFIC_REPORT_ALTITUDE_OFFSET + 1]) >> 4);
+    let misc_indicator = data[TRAFFIC_REPORT_MISC_INDICATOR_OFFSET] & 0x0f;
+    let ground_speed = (u32::from(data[TRAFFIC_REPORT_HORIZONTAL_VELOCITY_OFFSET]) << 4) | (u32::from(data[TRAFFIC_REPORT_HORIZONTAL_VELOCITY_OFFSET + 1]) >> 4);
+    // Signed value on 12 bits
+    let vertical_speed = (u16::from(data[TRAFFIC_REPORT_VERTICAL_VELOCITY_OFFSET] & 0x0f) << 8) | u16::from(data[TRAFFIC_REPORT_VERTICAL_VELOCITY_OFFSET + 1]);
+    let vertical_speed = i32::from(((vertical_speed << 4) as i16) >> 4);
+    let callsign = &data[TRAFFIC_REPORT_CALLSIGN_OFFSET..(TRAFFIC_REPORT_CALLSIGN_OFFSET + TRAFFIC_REPORT_CALLSIGN_LEN)];
+
+    Report {
+        traffic_alert: (data[TRAFFIC_REPORT_ADDRESS_OFFSET] >> 4) == 1,
+        addr_type: data[TRAFFIC_REPORT_ADDRESS_OFFSET] & 0x0f,
+        address: u32::from_be_bytes([0, data[TRAFFIC_REPORT_ADDRESS_OFFSET + 1], data[TRAFFIC_REPORT_ADDRESS_OFFSET + 2], data[TRAFFIC_REPORT_ADDRESS_OFFSET + 3]]),
+        latitude: f64::from(read_i24(TRAFFIC_REPORT_LATITUDE_OFFSET)) * 180.0 / f64::from(0x0080_0000),
+        longitude: f64::from(read_i24(TRAFFIC_REPORT_LONGITUDE_OFFSET)) * 180.0 / f64::from(0x0080_0000),
+        altitude: if altitude == 0xfff { None } else { Some((altitude as i32) * 25 - 1000) },
+        misc_indicator,
+        nic: data[TRAFFIC_REPORT_NAVIGATION_OFFSET] >> 4,
+        nacp: data[TRAFFIC_REPORT_NAVIGATION_OFFSET] & 0x0f,
+        ground_speed: if ground_speed == 0xfff { None } else { Some(ground_speed) },
+        vertical_speed: if vertical_speed == -0x800 { None } else { Some(vertical_speed * 64) },
+        track: if (misc_indicator & 0x03) == 0 { None } else { Some(f64::from(data[TRAFFIC_REPORT_TRACK_OFFSET]) * 360.0 / 256.0) },
+        emitter_category: data[TRAFFIC_REPORT_EMITTER_CATEGORY_OFFSET],
+        callsign: String::from_utf8_lossy(callsign).trim_end().to_string(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Encodes a TRAFFIC REPORT message and decodes it
+    fn traffic_round_trip(infos: &TrafficInfos) -> Report {
+        let mut buffer = [0u8; 100];
+        let len = make_traffic_report_message(infos, &mut buffer).unwrap();
+        match decode_message(&buffer[..len]).unwrap() {
+            Message::TrafficReport(report) => report,
+            message => panic!("Unexpected message {:?}", message),
+        }
+    }
+
+
+    // Traffic of the TRAFFIC REPORT example of the ICD
+    fn icd_traffic() -> TrafficInfos {
+        TrafficInfos {
+            addr_type: AddressType::AdsbIcao,
+            address: 0xab4549,
+            callsign: String::from("N825V"),
+            altitude: 5000,
+            latitude: 44.90708,
+            longitude: -122.99488,
+            track: Some(45),
+            ground_speed: Some(123),
+            vertical_speed: Some(64),
+            turn_rate: None,
+            ..Default::default()
+        }
+    }
+
+
+    #[test]
+    fn crc_of_icd_example() {
+        assert_eq!(compute_crc(&[0x00, 0x81, 0x41, 0xdb, 0xd0, 0x08, 0x02]), 0x8bb3);
+    }
+
+
+    #[test]
+    fn decode_icd_heartbeat() {
+        let message = [0x7e, 0x00, 0x81, 0x41, 0xdb, 0xd0, 0x08, 0x02, 0xb3, 0x8b, 0x7e];
+        assert_eq!(decode_message(&message).unwrap(), Message::Heartbeat(HeartbeatStatus {
+            position_valid: true,
+            maintenance_required: false,
+            initialized: true,
+            time_of_day: Some(0xd0db),
+            nb_messages: 2,
+        }));
+    }
+
+
+    #[test]
+    fn decode_icd_traffic_report() {
+        let data = [0x14, 0x00, 0xab, 0x45, 0x49, 0x1f, 0xef, 0x15, 0xa8, 0x89, 0x78, 0x0f, 0x09, 0xa9, 0x07, 0xb0,
+            0x01, 0x20, 0x01, 0x4e, 0x38, 0x32, 0x35, 0x56, 0x20, 0x20, 0x20, 0x00];
+        let mut message = vec![FLAG_BYTE];
+        message.extend_from_slice(&data);
+        message.extend_from_slice(&compute_crc(&data).to_le_bytes());
+        message.push(FLAG_BYTE);
+
+        let Message::TrafficReport(report) = decode_message(&message).unwrap() else { panic!("Not a traffic report") };
+        assert!(!report.traffic_alert);
+        assert_eq!(report.addr_type, 0);
+        assert_eq!(report.address, 0xab4549);
+        assert!((report.latitude - 44.90708).abs() < 0.0001);
+        assert!((report.longitude + 122.99488).abs() < 0.0001);
+        assert_eq!(report.altitude, Some(5000));
+        assert_eq!(report.misc_indicator, 9);
+        assert_eq!((report.nic, report.nacp), (10, 9));
+        assert_eq!(report.ground_speed, Some(123));
+        assert_eq!(report.vertical_speed, Some(64));
+        assert_eq!(report.track, Some(45.0));
+        assert_eq!(report.emitter_category, 1);
+        assert_eq!(report.callsign, "N825V");
+    }
+
+
+    #[test]
+    fn traffic_report_round_trip() {
+        let report = traffic_round_trip(&icd_traffic());
+        assert_eq!(report.addr_type, 0);
+        assert_eq!(report.address, 0xab4549);
+        assert!((report.latitude - 44.90708).abs() < 0.0001);
+        assert!((report.longitude + 122.99488).abs() < 0.0001);
+        assert_eq!(report.altitude, Some(5000));
+        assert_eq!(report.misc_indicator, 9);       // Airborne, true track
+        assert_eq!(report.ground_speed, Some(123));
+        assert_eq!(report.vertical_speed, Some(64));
+        assert_eq!(report.track, Some(45.0));
+        assert_eq!(report.callsign, "N825V");
+    }
+
+
+    #[test]
+    fn traffic_report_encodes_icd_example() {
+        let mut buffer = [0u8; 100];
+        let len = make_traffic_report_message(&icd_traffic(), &mut buffer).unwrap();
+        let content = byte_unstuff(&buffer[1..(len - 1)]).unwrap();
+
+        // Same bytes as the ICD example, except the navigation integrity and accuracy and the emitter category which are not known
+        assert_eq!(&content[..13], &[0x14, 0x00, 0xab, 0x45, 0x49, 0x1f, 0xef, 0x15, 0xa8, 0x89, 0x78, 0x0f, 0x09]);
+        assert_eq!(&content[14..18], &[0x07, 0xb0, 0x01, 0x20]);
+        assert_eq!(&content[19..28], &[0x4e, 0x38, 0x32, 0x35, 0x56, 0x20, 0x20, 0x20, 0x00]);
+    }
+
+
+    #[test]
+    fn traffic_report_ranges() {
+        // Unknown values
+        let infos = TrafficInfos { track: None, ground_speed: None, vertical_speed: None, ..icd_traffic() };
+        let report = traffic_round_trip(&infos);
+        assert_eq!(report.track, None);
+        assert_eq!(report.misc_indicator, 8);
+        assert_eq!(report.ground_speed, None);
+        assert_eq!(report.vertical_speed, None);
+
+        // Minimum values
+        let infos = TrafficInfos { latitude: -90.0, longitude: -180.0, altitude: -5000, ground_speed: Some(-10),
+            vertical_speed: Some(-100_000), track: Some(0), ..icd_traffic() };
+        let report = traffic_round_trip(&infos);
+        assert_eq!(report.latitude, -90.0);
+        assert_eq!(report.longitude, -180.0);
+        assert_eq!(report.altitude, Some(-1000));
+        assert_eq!(report.ground_speed, Some(0));
+        assert_eq!(report.vertical_speed, Some(-32640));
+        assert_eq!(report.track, Some(0.0));
+
+        // Maximum values
+        let infos = TrafficInfos { latitude: 90.0, longitude: 180.0, altitude: 200_000, ground_speed: Some(10_000),
+            vertical_speed: Some(100_000), track: Some(359), ..icd_traffic() };
+        let report = traffic_round_trip(&infos);
+        assert!(report.latitude < 90.0 && report.latitude > 89.9999);
+        assert!(report.longitude < 180.0 && report.longitude > 179.9999);
+        assert_eq!(report.altitude, Some(101_350));
+        assert_eq!(report.ground_speed, Some(0xffe));
+        assert_eq!(report.vertical_speed, Some(32640));
+        assert!((report.track.unwrap() - 358.59375).abs() < 0.0001);
+    }
+
+
+    #[test]
+    fn traffic_report_callsign() {
+        let report = traffic_round_trip(&TrafficInfos { callsign: String::from("ABCDEFGHIJ"), ..icd_traffic() });
+        assert_eq!(report.callsign, "ABCDEFGH");
+
+        let report = traffic_round_trip(&TrafficInfos { callsign: String::from("F-é"), ..icd_traffic() });
+        assert_eq!(report.callsign, "F-?");
+
+        let report = traffic_round_trip(&TrafficInfos { callsign: String::new(), ..icd_traffic() });
+        assert_eq!(report.callsign, "");
+    }
+
+
+    #[test]
+    fn byte_stuffing_round_trip() {
+        // Address with the flag byte and the control escape character
+        let report = traffic_round_trip(&TrafficInfos { addr_type: AddressType::Ogn, address: 0x7e7d7e, ..icd_traffic() });
+        assert_eq!(report.addr_type, 6);
+        assert_eq!(report.address, 0x7e7d7e);
+    }
+
+
+    #[test]
+    fn address_types() {
+        for (addr_type, value) in [(AddressType::AdsbIcao, 0), (AddressType::Random, 1), (AddressType::Flarm, 6), (AddressType::Ogn, 6)] {
+            let report = traffic_round_trip(&TrafficInfos { addr_type, ..icd_traffic() });
+            assert_eq!(report.addr_type, value);
+        }
+        assert_eq!(AddressType::try_from(6).unwrap(), AddressType::Flarm);
+        assert!(AddressType::try_from(2).is_err());
+    }
+
+
+    #[test]
+    fn invalid_messages() {
+        let mut buffer = [0u8; 100];
+        let len = make_traffic_report_message(&icd_traffic(), &mut buffer).unwrap();
+
+        // Corrupted data
+        let mut message = buffer[..len].to_vec();
+        message[5] ^= 0x01;
+        assert!(decode_message(&message).is_err());
+
+        // Missing end flag
+        assert!(decode_message(&buffer[..(len - 1)]).is_err());
+
+        // Truncated message
+        let mut message = buffer[..(len - 4)].to_vec();
+        message.push(FLAG_BYTE);
+        assert!(decode_message(&message).is_err());
+    }
+
+
+    #[test]
+    fn heartbeat_round_trip() {
+        let status = HeartbeatStatus {
+            position_valid: true,
+            maintenance_required: true,
+            initialized: false,
+            time_of_day: Some(86_399),          // Time stamp on 17 bits
+            nb_messages: 1023,
+        };
+        let mut buffer = [0u8; 100];
+        let len = make_heartbeat_message(&status, &mut buffer).unwrap();
+        assert_eq!(decode_message(&buffer[..len]).unwrap(), Message::Heartbeat(status));
+
+        // UTC time not known and message count saturated
+        let status = HeartbeatStatus { time_of_day: None, nb_messages: 5000, position_valid: false, maintenance_required: false, initialized: true };
+        let len = make_heartbeat_message(&status, &mut buffer).unwrap();
+        assert_eq!(decode_message(&buffer[..len]).unwrap(), Message::Heartbeat(HeartbeatStatus { nb_messages: 1023, ..status }));
+    }
+
+
+    #[test]
+    fn ownship_round_trip() {
+        let mut buffer = [0u8; 100];
+        let len = make_ownship_report_message(AddressType::AdsbIcao, 0x4ca2d6, 46.4, -1.4, None, &mut buffer).unwrap();
+        let Message::OwnshipReport(report) = decode_message(&buffer[..len]).unwrap() else { panic!("Not an ownship report") };
+        assert_eq!(report.address, 0x4ca2d6);
+        assert!((report.latitude - 46.4).abs() < 0.0001);
+        assert!((report.longitude + 1.4).abs() < 0.0001);
+        assert_eq!(report.altitude, None);
+
+        let len = make_ownship_geometric_altitude_message(-1235, &mut buffer).unwrap();
+        assert_eq!(decode_message(&buffer[..len]).unwrap(),
+            Message::OwnshipGeometricAltitude { altitude: -1235, vertical_warning: false, vfom: None });
+    }
+
+
+    #[test]
+    fn foreflight_id() {
+        let mut buffer = [0u8; 100];
+        let len = make_foreflight_id_message(Some(42), "SkyReach", "SkyReacher", 1, &mut buffer).unwrap();
+        let Message::Other { message_id, data } = decode_message(&buffer[..len]).unwrap() else { panic!("Unexpected message") };
+        assert_eq!(message_id, FOREFLIGHT_MESSAGE_ID);
+        assert_eq!(data.len(), FOREFLIGHT_ID_LEN);
+        assert_eq!(&data[..2], &[FOREFLIGHT_ID_SUB_ID, FOREFLIGHT_ID_VERSION]);
+        assert_eq!(&data[2..10], &42u64.to_be_bytes());
+        assert_eq!(&data[10..18], b"SkyReach");
+        assert_eq!(&data[18..34], b"SkyReacher\0\0\0\0\0\0");
+        assert_eq!(&data[34..], &[0, 0, 0, 1]);
+
+        // Names truncated at a character boundary
+        let len = make_foreflight_id_message(None, "Planeuré", "Serveur de trafü", 0, &mut buffer).unwrap();
+        let Message::Other { data, .. } = decode_message(&buffer[..len]).unwrap() else { panic!("Unexpected message") };
+        assert_eq!(&data[10..18], b"Planeur\0");
+        assert_eq!(&data[18..34], b"Serveur de traf\0");
+        let len = make_foreflight_id_message(None, "Vol-à-1", "", 0, &mut buffer).unwrap();
+        let Message::Other { data, .. } = decode_message(&buffer[..len]).unwrap() else { panic!("Unexpected message") };
+        assert_eq!(&data[10..18], "Vol-à-1".as_bytes());
+    }
+}
@@ -0,0 +1,166 @@
+//! Geographic computations on the WGS84 sphere approximation
+//!
+
+// Mean radius of the earth
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+
+/// Row and column of a cell, when the earth is divided into cells of the same size in degrees
+/// The size of the cells must divide 360 so that the columns wrap around the antimeridian
+pub type Cell = (i32, i32);
+
+
+/// Great-circle distance in km between two positions given in degrees (haversine formula)
+pub fn distance_km(latitude1: f64, longitude1: f64, latitude2: f64, longitude2: f64) -> f64 {
+    let phi1 = latitude1.to_radians();
+    let phi2 = latitude2.to_radians();
+    let delta_phi = (latitude2 - latitude1).to_radians();
+    let delta_lambda = (longitude2 - longitude1).to_radians();
+
+    let a = (delta_phi / 2.0).sin().powi(2) + phi1.cos() * phi2.cos() * (delta_lambda / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().min(1.0).asin()
+}
+
+
+/// Get the half-extents in degrees, in latitude and in longitude, of the area within a distance
+/// around a position of the given latitude
+/// The longitude extent is None when the area contains a pole, it then covers all the longitudes
+pub fn area_extent(latitude: f64, radius_km: f64) -> (f64, Option<f64>) {
+    let angular_radius = radius_km / EARTH_RADIUS_KM;
+    let latitude_extent = angular_radius.to_degrees();
+    if latitude.abs() + latitude_extent >= 90.0 {
+        return (latitude_extent, None);
+    }
+
+    // Longitude of the points where the meridians are tangent to the circle
+    let longitude_extent = (angular_radius.sin() / latitude.to_radians().cos()).asin().to_degrees();
+    (latitude_extent, Some(longitude_extent))
+}
+
+
+/// Get the cell containing a position, when the earth is divided into cells of the given size in degrees
+pub fn cell_of(latitude: f64, longitude: f64, cell_size: f64) -> Cell {
+    ((latitude / cell_size).floor() as i32, ((longitude / cell_size).floor() as i32).rem_euclid(nb_longitude_cells(cell_size)))
+}
+
+
+/// Get the cells covered by the area within a distance around a position
+pub fn covered_cells(latitude: f64, longitude: f64, radius_km: f64, cell_size: f64) -> Vec<Cell> {
+    let nb_longitude_cells = nb_longitude_cells(cell_size);
+    let (latitude_extent, longitude_extent) = area_extent(latitude, radius_km);
+    let first_row = ((latitude - latitude_extent).max(-90.0) / cell_size).floor() as i32;
+    let last_row = ((latitude + latitude_extent).min(90.0) / cell_size).floor() as i32;
+
+    // The area covers all the longitudes if it contains a pole
+    let (first_column, last_column) = match longitude_extent {
+        Some(extent) if extent < 180.0 => (
+            ((longitude - extent) / cell_size).floor() as i32,
+            ((longitude + extent) / cell_size).floor() as i32),
+        _ => (0, nb_longitude_cells - 1),
+    };
+    let last_column = last_column.min(first_column + nb_longitude_cells - 1);
+
+    let mut cells = Vec::new();
+    for row in first_row..=last_row {
+        for column in first_column..=last_column {
+            cells.push((row, column.rem_euclid(nb_longitude_cells)));
+        }
+    }
+    cells
+}
+
+
+// Number of cells around a parallel
+fn nb_longitude_cells(cell_size: f64) -> i32 {
+    (360.0 / cell_size).round() as i32
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(value: f64, expected: f64, tolerance: f64) {
+        assert!((value - expected).abs() <= tolerance, "{} instead of {}", value, expected);
+    }
+
+
+    #[test]
+    fn known_distances() {
+        // One degree of a great circle, a quarter of the meridian and half the equator
+        assert_near(distance_km(45.0, 0.0, 46.0, 0.0), 111.195, 0.001);
+        assert_near(distance_km(0.0, 0.0, 90.0, 0.0), 10_007.543, 0.001);
+        assert_near(distance_km(0.0, 0.0, 0.0, 180.0), 20_015.087, 0.001);
+        assert_eq!(distance_km(44.9, 6.1, 44.9, 6.1), 0.0);
+
+        // London to Paris, and Paris-Charles de Gaulle to New York-JFK
+        assert_near(distance_km(51.5074, -0.1278, 48.8566, 2.3522), 343.56, 0.01);
+        assert_near(distance_km(49.0097, 2.5479, 40.6413, -73.7781), 5833.48, 0.01);
+        assert_near(distance_km(40.6413, -73.7781, 49.0097, 2.5479), 5833.48, 0.01);
+    }
+
+
+    #[test]
+    fn distances_across_the_antimeridian_and_near_the_poles() {
+        assert_near(distance_km(0.0, 179.5, 0.0, -179.5), 111.195, 0.001);
+        assert_near(distance_km(-16.5, 179.9, -16.5, -179.9), 21.323, 0.001);
+        assert_near(distance_km(10.0, 180.0, 10.0, -180.0), 0.0, 1e-9);
+
+        // Short distances between meridians far from each other
+        assert_near(distance_km(89.9, 0.0, 89.9, 180.0), 22.239, 0.001);
+        assert_near(distance_km(89.9, 0.0, 89.9, 90.0), 15.725, 0.001);
+        assert_near(distance_km(-89.9, -45.0, -90.0, 120.0), 11.119, 0.001);
+    }
+
+
+    #[test]
+    fn extent_of_an_area() {
+        let (latitude_extent, longitude_extent) = area_extent(0.0, 111.195);
+        assert_near(latitude_extent, 1.0, 0.0001);
+        assert_near(longitude_extent.unwrap(), 1.0, 0.0001);
+
+        // The meridians get closer to each other towards the poles
+        let (latitude_extent, longitude_extent) = area_extent(-60.0, 111.195);
+        assert_near(latitude_extent, 1.0, 0.0001);
+        assert_near(longitude_extent.unwrap(), 2.0, 0.001);
+
+        // All the longitudes around a pole
+        assert!(area_extent(89.8, 50.0).1.is_none());
+        assert!(area_extent(-89.8, 50.0).1.is_none());
+    }
+
+
+    #[test]
+    fn cells_across_the_antimeridian() {
+        // Columns from 0 at the Greenwich meridian eastward, both sides of the antimeridian are in the last and first columns
+        assert_eq!(cell_of(-16.5, 179.9, 1.0), (-17, 179));
+        assert_eq!(cell_of(-16.5, -179.9, 1.0), (-17, 180));
+        assert_eq!(cell_of(-16.5, 180.0, 1.0), cell_of(-16.5, -180.0, 1.0));
+
+        // An area straddling the antimeridian covers the cells on both sides, wherever its center is
+        for longitude in [179.9, -179.9] {
+            let mut cells = covered_cells(-16.5, longitude, 50.0, 1.0);
+            cells.sort();
+            assert_eq!(cells, [(-17, 179), (-17, 180)]);
+        }
+        let cells = covered_cells(0.0, 180.0, 50.0, 10.0);
+        assert_eq!(cells, [(-1, 17), (-1, 18), (0, 17), (0, 18)]);
+
+        // An area larger than the cells does not cover the same column twice
+        let cells = covered_cells(80.0, 179.0, 1000.0, 10.0);
+        let mut columns: Vec<i32> = cells.iter().filter(|cell| cell.0 == 8).map(|cell| cell.1).collect();
+        columns.dedup();
+        assert_eq!(columns.len(), cells.iter().filter(|cell| cell.0 == 8).count());
+    }
+
+
+    #[test]
+    fn cells_around_the_poles() {
+        // All the longitudes, up to the row of the pole
+        let cells = covered_cells(89.8, 10.0, 50.0, 1.0);
+        assert_eq!(cells.len(), 2 * 360);
+        assert!(cells.contains(&cell_of(89.9, -170.0, 1.0)));
+        assert!(cells.contains(&cell_of(90.0, 0.0, 1.0)));
+        assert_eq!(covered_cells(-89.8, 10.0, 50.0, 10.0).len(), 36);
+    }
+}
@@ -1,28 +1,55 @@
-use crate::traffic_infos::TrafficInfos;
+//! Multicast bus between the sources and the pools
+//! The traffic of each region is sent on one of `NB_GROUPS` consecutive multicast groups, neighbouring
+//! regions being on different groups, so that a pool can only receive the traffic around its clients.
+//!
+
+use crate::{geo::{self, Cell}, traffic_infos::EncodedTraffic};
 
 use socket2::{Socket, Domain, Type};
-use std::{net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket}, os::fd::AsFd};
+use std::{net::{Ipv4Addr, SocketAddrV4, UdpSocket}, os::fd::AsFd};
+
+
+/// Number of multicast groups of the bus
+pub const NB_GROUPS: usize = 16;
+
+// Regions sharing a group are at least GROUPS_STRIDE regions away, in latitude or in longitude
+const GROUPS_STRIDE: i32 = 4;
+
+// Maximum size of a serialized traffic information, with its encoded report
+const MAX_MESSAGE_LEN: usize = 512;
 
 
-// Multicast address and port to use
-const MULTICAST_ADDR_V4: Ipv4Addr = Ipv4Addr::new(224,0,0,64);
-const MULTICAST_PORT: u16 = 1665;
+/// Get the group on which the traffic of a region is sent
+pub fn region_group(region: Cell) -> usize {
+    (region.0.rem_euclid(GROUPS_STRIDE) * GROUPS_STRIDE + region.1.rem_euclid(GROUPS_STRIDE)) as usize
+}
+
+
+// Get the multicast address of a group
+fn group_address(multicast_addr: SocketAddrV4, group: usize) -> Ipv4Addr {
+    Ipv4Addr::from(u32::from(*multicast_addr.ip()) + group as u32)
+}
 
 
 /// Receiving traffic information from sources
+/// Only the traffic of the joined groups is received
 pub struct Receiver {
     socket: UdpSocket,
+    multicast_addr: SocketAddrV4,
 }
 
 impl Receiver {
-    pub fn new(nonblocking: bool) -> Self {
+    pub fn new(multicast_addr: SocketAddrV4, nonblocking: bool) -> Self {
         // We use the socket2 crate because UdpSocket does not allow setting the SO_REUSEPORT option
         // necessary to have several receivers listening on the same multicast port
         let sock = Socket::new(Domain::IPV4, Type::DGRAM, None).unwrap();
         sock.set_reuse_port(true).unwrap();
 
-        // Bind the socket to the listening multicast address and port
-        sock.bind(&SocketAddrV4::new(MULTICAST_ADDR_V4, MULTICAST_PORT).into()).unwrap();
+        // Only the groups joined by this socket are received, not those joined by the other receivers
+        sock.set_multicast_all_v4(false).unwrap();
+
+        // Bind the socket to the multicast port, for all the groups
+        sock.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, multicast_addr.port()).into()).unwrap();
 
         // Now we can convert to UdpSocket
         let socket: UdpSocket = sock.into();
@@ -30,24 +57,31 @@ impl Receiver {
         // We set the socket to non-blocking mode if asked
         socket.set_nonblocking(nonblocking).unwrap();
 
-        // We subscribe to the local multicast address
-        socket.join_multicast_v4(&MULTICAST_ADDR_V4, &Ipv4Addr::LOCALHOST).unwrap();
+        Self {socket, multicast_addr}
+    }
 
-        // We expect to receive local frames but no filtering on the remote port
-        socket.connect(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap();
+    /// Subscription to the traffic of a group
+    pub fn join_group(&self, group: usize) {
+        self.socket.join_multicast_v4(&group_address(self.multicast_addr, group), &Ipv4Addr::LOCALHOST).unwrap();
+    }
 
-        Self {socket}
+    /// End of the subscription to the traffic of a group
+    pub fn leave_group(&self, group: usize) {
+        self.socket.leave_multicast_v4(&group_address(self.multicast_addr, group), &Ipv4Addr::LOCALHOST).unwrap();
     }
 
     /// Reading of traffic information from sources
-    pub fn recv(&self) -> anyhow::Result<TrafficInfos> {
+    pub fn recv(&self) -> anyhow::Result<EncodedTraffic> {
         // Reading on multicast socket
-        let mut buf = [0; 100];
-        let recv_size = self.socket.recv(&mut buf)?;
+        let mut buf = [0; MAX_MESSAGE_LEN];
+        let (recv_size, from) = self.socket.recv_from(&mut buf)?;
+
+        // We expect to receive local frames only
+        anyhow::ensure!(from.ip().is_loopback(), "Frame received from {}", from);
 
         // Deserialization to reconstruct traffic information
-        let traffic_infos: TrafficInfos = bincode::deserialize(&buf[..recv_size])?;
-        Ok(traffic_infos)
+        let traffic: EncodedTraffic = bincode::deserialize(&buf[..recv_size])?;
+        Ok(traffic)
     }
 
 }
@@ -60,28 +94,77 @@ impl AsFd for Receiver {
 
 
 /// Transmission of traffic information to all clients
+/// The traffic is sent on the group of its region, of region_size degrees
 pub struct Sender {
     socket: UdpSocket,
+    multicast_addr: SocketAddrV4,
+    region_size: f64,
 }
 
 impl Sender {
-    pub fn new() -> Self {
+    pub fn new(multicast_addr: SocketAddrV4, region_size: f64) -> Self {
         // Bind the socket to the local address without imposing a transmission port
-        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let sock = Socket::new(Domain::IPV4, Type::DGRAM, None).unwrap();
+        sock.bind(&SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0).into()).unwrap();
 
-        // We will send the frames to the address and the multicast port
-        socket.connect(SocketAddr::from((MULTICAST_ADDR_V4, MULTICAST_PORT))).unwrap();
+        // The frames are sent on the loopback interface, on which the receivers join the groups
+        sock.set_multicast_if_v4(&Ipv4Addr::LOCALHOST).unwrap();
+        let socket: UdpSocket = sock.into();
 
-        Sender {socket}
+        Sender {socket, multicast_addr, region_size}
     }
 
     /// Sending information on traffic to all clients
-    pub fn send(&self, traffic_infos: &TrafficInfos) {
+    pub fn send(&self, traffic: &EncodedTraffic) {
         // Serialization of traffic information in a buffer to be able to send it
-        let buf = bincode::serialize(traffic_infos).unwrap();
-
-        // Sending the buffer on the multicast socket
-        self.socket.send(&buf).unwrap();
+        let buf = bincode::serialize(traffic).unwrap();
+        if buf.len() > MAX_MESSAGE_LEN {
+            // It would be truncated by the receivers
+            log::warn!("Traffic {:06X} not sent, its message is too long ({} bytes)", traffic.infos.address, buf.len());
+            return;
+        }
+
+        // Sending the buffer on the multicast group of the region of the traffic
+        let region = geo::cell_of(traffic.infos.latitude, traffic.infos.longitude, self.region_size);
+        let group = group_address(self.multicast_addr, region_group(region));
+        self.socket.send_to(&buf, SocketAddrV4::new(group, self.multicast_addr.port())).unwrap();
     }
 
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::traffic_infos::{AddressType, TrafficInfos, MAX_TEXT_LEN};
+
+    #[test]
+    fn traffic_with_the_longest_fields() {
+        // Text fields longer than allowed, with characters of several bytes, and values to be byte-stuffed in the report
+        let text = "é".repeat(MAX_TEXT_LEN);
+        let infos = TrafficInfos {
+            addr_type: AddressType::Random,
+            address: 0x7e7d7e,
+            callsign: text.clone(),
+            altitude: 0x7e7d,
+            latitude: -89.999_999,
+            longitude: -179.999_999,
+            track: Some(359),
+            ground_speed: Some(0x7e7),
+            vertical_speed: Some(-32_000),
+            turn_rate: Some(-180.0),
+            registration: text.clone(),
+            competition_number: text.clone(),
+            aircraft_model: text,
+        };
+        let traffic = EncodedTraffic::new(infos);
+        assert_eq!(traffic.infos.registration.len(), MAX_TEXT_LEN);
+        assert_eq!(traffic.infos.aircraft_model.chars().count(), MAX_TEXT_LEN / 2);
+
+        let buf = bincode::serialize(&traffic).unwrap();
+        assert!(buf.len() <= MAX_MESSAGE_LEN, "{} bytes", buf.len());
+        let received: EncodedTraffic = bincode::deserialize(&buf).unwrap();
+        assert_eq!(received.infos.callsign, traffic.infos.callsign);
+        assert_eq!(received.report, traffic.report);
+    }
+}
@@ -1,17 +1,41 @@
+use client_areas::ClientAreas;
+use config::{Config, OgnMode, SharedConfig};
+use ddb::{Ddb, DdbRefresh};
 use server::Server;
+use signals::Signals;
 use src_ogn::SrcOgn;
+use src_ogn_aprs::SrcOgnAprs;
 use src_adsbhub::SrcAdsbhub;
-use std::io::Write;
+use src_sbs::SrcSbs;
+use stop_flag::StopFlag;
+use traffic_store::TrafficStore;
+use std::{io::Write, thread, time::{Duration, Instant}};
 
+mod config;
+mod signals;
+mod stop_flag;
 mod traffic_infos;
+mod traffic_store;
 mod dgramostream;
 mod gdl90;
+mod geo;
+mod client_grid;
+mod client_areas;
+mod ddb;
+mod placement;
 mod internal_com;
+mod protocol;
 mod server;
 mod client_pool;
 mod client;
 mod src_ogn;
+mod src_ogn_aprs;
 mod src_adsbhub;
+mod src_sbs;
+
+
+// Maximum time to wait for the sources to stop, they check the stop request at least every second
+const SOURCES_STOP_TIMEOUT: Duration = Duration::from_secs(2);
 
 fn main() {
     // Init and customization of the trace system
@@ -39,12 +63,76 @@ fn main() {
 
     log::info!("Start {} v{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
 
+    // Loading of the configuration, the server cannot start with an invalid configuration
+    let (config, args) = match Config::load() {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            log::error!("Invalid configuration : {:#}", e);
+            std::process::exit(1);
+        }
+    };
+    let config = SharedConfig::new(config, args);
+
+    // Signals must be blocked before the creation of the threads
+    let signals = Signals::new();
+
+    // OGN device database, kept empty when disabled
+    let ddb = Ddb::new_shared();
+
+    // Table of the known traffic, filled by the sources, hiding the identity of the aircraft as given by the database
+    let traffic_store = TrafficStore::new_shared(&ddb);
+
+    // Areas of the clients, around which the OGN traffic is requested
+    let client_areas = ClientAreas::new_shared();
+
+    // Flag to stop the sources
+    let stop_flag = StopFlag::new();
+    let mut sources = Vec::new();
+
+    // Refresh of the OGN device database
+    if config.get().ddb.enabled {
+        sources.push(DdbRefresh::start(&config, &ddb, stop_flag.clone()));
+    }
+
     // Launch of reception of OGN traffic
-    SrcOgn::start_receive();
+    if config.get().ogn.enabled {
+        sources.push(match config.get().ogn.mode {
+            OgnMode::Aprs => SrcOgnAprs::start_receive(&config, &traffic_store, &client_areas, &ddb, stop_flag.clone()),
+            OgnMode::Lxml => SrcOgn::start_receive(&config, &traffic_store, &client_areas, &ddb, stop_flag.clone()),
+        });
+    }
 
     // Launch of reception of ADSBHub traffic
-    SrcAdsbhub::start_receive();
+    if config.get().adsbhub.enabled {
+        sources.push(SrcAdsbhub::start_receive(&config, &traffic_store, stop_flag.clone()));
+    }
+
+    // Launch of reception of the SBS feeds, one thread for each of them
+    if config.get().sbs.enabled {
+        for feed in &config.get().sbs.feeds {
+            sources.push(SrcSbs::start_receive(&config, &traffic_store, feed, stop_flag.clone()));
+        }
+    }
+
+    // Listening and processing client connections (blocking until a stop signal)
+    let server = Server::new(&config, &traffic_store, &client_areas);
+    server.run(&signals);
+
+    // Graceful shutdown: the sources are stopped first so that the pools can send the last traffic received
+    // A source blocked in a request is not waited for longer than its own delay, so that the clients keep
+    // the whole shutdown timeout to receive the pending traffic
+    let sources_deadline = Instant::now() + SOURCES_STOP_TIMEOUT;
+    stop_flag.stop();
+    for source in &sources {
+        while !source.is_finished() && (Instant::now() < sources_deadline) {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+    if sources.iter().any(|source| !source.is_finished()) {
+        log::warn!("Some sources did not stop before the deadline");
+    }
+    let deadline = Instant::now() + Duration::from_secs(config.get().server.shutdown_timeout);
+    server.shutdown(deadline);
 
-    // Listening and processing client connections (blocking)
-    Server::new().listen_connections();
+    log::info!("Stop {}", env!("CARGO_PKG_NAME"));
 }
@@ -0,0 +1,323 @@
+//! Regional placement of the clients in the pools
+//! The world is divided into regions of `region_size` degrees. The clients of a region are grouped
+//! in the same pool, which only subscribes to the traffic of the regions covered by the areas of
+//! its clients. When a pool serving a region has too many clients compared to the others, the new
+//! clients of the region are placed in the least populated pool, which then serves the region too.
+//!
+
+use crate::{geo::{self, Cell}, internal_com};
+
+use std::{collections::HashMap, sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex}};
+
+
+// Margin of clients above the least populated pool, relative to the average number of clients per pool,
+// before a region is also served by the least populated pool
+const MAX_IMBALANCE: f64 = 0.25;
+
+
+/// Regions served by each pool, shared by all the pools
+pub struct RegionMap {
+    region_size: f64,
+    nb_clients: Vec<Arc<AtomicUsize>>,      // Current number of clients of each pool
+    regions: Mutex<HashMap<Cell, Vec<usize>>>,  // Pools serving each region
+}
+
+impl RegionMap {
+    pub fn new(region_size: f64, nb_clients: Vec<Arc<AtomicUsize>>) -> Self {
+        Self {
+            region_size,
+            nb_clients,
+            regions: Mutex::new(HashMap::new()),
+        }
+    }
+
+
+    /// Get the region of a position
+    pub fn region_of(&self, latitude: f64, longitude: f64) -> Cell {
+        geo::cell_of(latitude, longitude, self.region_size)
+    }
+
+
+    /// Get the traffic groups of the regions covered by the area within the radius around a position,
+    /// as a bit mask
+    pub fn covered_groups(&self, latitude: f64, longitude: f64, radius_km: f64) -> u32 {
+        geo::covered_cells(latitude, longitude, radius_km, self.region_size).into_iter()
+            .fold(0, |groups, region| groups | (1 << internal_com::region_group(region)))
+    }
+
+
+    /// Get the pool in which a client of the region must be placed, the client being counted in it
+    /// if it is not its current pool
+    /// The current pool of the client is kept if it serves the region, or if all the other pools are full
+    pub fn select_pool(&self, region: Cell, current_pool: usize, pool_max_clients: usize) -> usize {
+        let mut regions = self.regions.lock().unwrap();
+        let serving_pools = regions.entry(region).or_default();
+        if serving_pools.contains(&current_pool) {
+            return current_pool;
+        }
+
+        // The pools serving the region come first, from the least populated, if they are not too populated
+        // compared to the least populated pool, which then serves the region too
+        let nb_clients = |pool: &usize| self.nb_clients[*pool].load(Ordering::Relaxed);
+        let average = self.nb_clients.iter().map(|nb| nb.load(Ordering::Relaxed)).sum::<usize>() as f64 / self.nb_clients.len() as f64;
+        let max_nb_clients = (0..self.nb_clients.len()).map(|pool| nb_clients(&pool)).min().unwrap() + (average * MAX_IMBALANCE).ceil() as usize;
+        let mut serving_candidates: Vec<usize> = serving_pools.iter().copied().filter(|pool| nb_clients(pool) <= max_nb_clients).collect();
+        serving_candidates.sort_by_key(nb_clients);
+        let mut all_pools: Vec<usize> = (0..self.nb_clients.len()).collect();
+        all_pools.sort_by_key(nb_clients);
+
+        // The client is counted at once in its new pool, so that a pool is never filled beyond its maximum
+        let pool = serving_candidates.into_iter().chain(all_pools)
+            .find(|&pool| (pool == current_pool) || reserve(&self.nb_clients[pool], pool_max_clients))
+            .unwrap_or(current_pool);
+        if !serving_pools.contains(&pool) {
+            serving_pools.push(pool);
+        }
+        pool
+    }
+
+
+    /// Indicates that a pool no longer has clients in the region
+    pub fn release(&self, region: Cell, pool: usize) {
+        let mut regions = self.regions.lock().unwrap();
+        if let Some(serving_pools) = regions.get_mut(&region) {
+            serving_pools.retain(|&serving_pool| serving_pool != pool);
+            if serving_pools.is_empty() {
+                regions.remove(&region);
+            }
+        }
+    }
+}
+
+
+/// Counts one more client if the maximum is not reached
+/// Returns false if the maximum is reached
+pub fn reserve(nb_clients: &AtomicUsize, max_clients: usize) -> bool {
+    nb_clients.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |nb| (nb < max_clients).then_some(nb + 1)).is_ok()
+}
+
+
+/// Changes of the regions and traffic groups of a pool after the update of one of its clients
+#[derive(Default)]
+pub struct RegionChanges {
+    pub released_region: Option<Cell>,      // Region without client any more
+    pub joined_groups: u32,                 // Bit mask of the groups to join
+    pub left_groups: u32,                   // Bit mask of the groups to leave
+}
+
+
+/// Regions of the clients of a pool and traffic groups needed by them
+#[derive(Default)]
+pub struct PoolRegions {
+    clients: Vec<Option<(Cell, u32)>>,      // Region and groups of each client, by client index
+    nb_clients_by_region: HashMap<Cell, usize>,
+    nb_clients_by_group: [usize; internal_com::NB_GROUPS],
+}
+
+impl PoolRegions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+
+    /// Sets the region of a client and the groups of the traffic around it, None when it leaves the pool
+    pub fn set_client(&mut self, client_index: usize, regions: Option<(Cell, u32)>) -> RegionChanges {
+        if client_index >= self.clients.len() {
+            self.clients.resize(client_index + 1, None);
+        }
+        let previous = std::mem::replace(&mut self.clients[client_index], regions);
+        let mut changes = RegionChanges::default();
+
+        if let Some((region, groups)) = previous {
+            let nb_clients = self.nb_clients_by_region.get_mut(&region).unwrap();
+            *nb_clients -= 1;
+            if *nb_clients == 0 {
+                self.nb_clients_by_region.remove(&region);
+                changes.released_region = Some(region);
+            }
+            for group in groups_of(groups) {
+                self.nb_clients_by_group[group] -= 1;
+                if self.nb_clients_by_group[group] == 0 {
+                    changes.left_groups |= 1 << group;
+                }
+            }
+        }
+
+        if let Some((region, groups)) = regions {
+            *self.nb_clients_by_region.entry(region).or_default() += 1;
+            if changes.released_region == Some(region) {
+                changes.released_region = None;
+            }
+            for group in groups_of(groups) {
+                self.nb_clients_by_group[group] += 1;
+                if self.nb_clients_by_group[group] == 1 {
+                    changes.joined_groups |= 1 << group;
+                }
+            }
+        }
+
+        // A group left then joined again is kept
+        let kept_groups = changes.left_groups & changes.joined_groups;
+        changes.left_groups &= !kept_groups;
+        changes.joined_groups &= !kept_groups;
+        changes
+    }
+}
+
+
+/// Get the groups of a bit mask
+pub fn groups_of(groups: u32) -> impl Iterator<Item = usize> {
+    (0..internal_com::NB_GROUPS).filter(move |group| groups & (1 << group) != 0)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REGION_SIZE: f64 = 10.0;
+    const POOL_MAX_CLIENTS: usize = 10;
+
+
+    fn region_map(nb_pools: usize) -> (RegionMap, Vec<Arc<AtomicUsize>>) {
+        let nb_clients: Vec<_> = (0..nb_pools).map(|_| Arc::new(AtomicUsize::new(0))).collect();
+        (RegionMap::new(REGION_SIZE, nb_clients.clone()), nb_clients)
+    }
+
+
+    fn set_nb_clients(nb_clients: &[Arc<AtomicUsize>], values: &[usize]) {
+        for (nb, value) in nb_clients.iter().zip(values) {
+            nb.store(*value, Ordering::Relaxed);
+        }
+    }
+
+
+    #[test]
+    fn region_lookup_at_boundaries() {
+        let (map, _) = region_map(1);
+        assert_eq!(map.region_of(0.0, 0.0), (0, 0));
+        assert_eq!(map.region_of(-0.001, -0.001), (-1, 35));
+        assert_eq!(map.region_of(44.999, 9.999), (4, 0));
+        assert_eq!(map.region_of(45.0, 10.0), (4, 1));
+
+        // Both sides of the antimeridian are in the same column
+        assert_eq!(map.region_of(-17.5, 180.0), map.region_of(-17.5, -180.0));
+        assert_eq!(map.region_of(-17.5, 179.999), (-2, 17));
+        assert_eq!(map.region_of(-17.5, -179.999), (-2, 18));
+
+        // A position near the corner of 4 regions needs their 4 groups, far from it only the group of its region
+        let groups = map.covered_groups(50.0, 10.0, 50.0);
+        let corner_regions = [(4, 0), (4, 1), (5, 0), (5, 1)];
+        assert_eq!(groups, corner_regions.iter().fold(0, |groups, &region| groups | (1 << internal_com::region_group(region))));
+        assert_eq!(map.covered_groups(45.0, 5.0, 50.0), 1 << internal_com::region_group((4, 0)));
+    }
+
+
+    #[test]
+    fn least_populated_pool_serving_the_region() {
+        let (map, nb_clients) = region_map(3);
+        let region = (4, 0);
+
+        // The first client of a region is placed in the least populated pool
+        set_nb_clients(&nb_clients, &[4, 1, 3]);
+        assert_eq!(map.select_pool(region, 0, POOL_MAX_CLIENTS), 1);
+
+        // The pool serving the region is kept while it is not too populated compared to the others
+        set_nb_clients(&nb_clients, &[3, 4, 3]);
+        assert_eq!(map.select_pool(region, 2, POOL_MAX_CLIENTS), 1);
+
+        // Then the least populated pool serves the region too
+        set_nb_clients(&nb_clients, &[3, 7, 2]);
+        assert_eq!(map.select_pool(region, 0, POOL_MAX_CLIENTS), 2);
+
+        // The least populated of the serving pools is chosen, a current pool serving the region is kept
+        set_nb_clients(&nb_clients, &[3, 7, 5]);
+        assert_eq!(map.select_pool(region, 0, POOL_MAX_CLIENTS), 2);
+        assert_eq!(map.select_pool(region, 1, POOL_MAX_CLIENTS), 1);
+
+        // A full serving pool is avoided
+        set_nb_clients(&nb_clients, &[6, 7, 10]);
+        assert_eq!(map.select_pool(region, 0, POOL_MAX_CLIENTS), 1);
+    }
+
+
+    #[test]
+    fn full_pools_never_chosen() {
+        let (map, nb_clients) = region_map(3);
+        let region = (4, 0);
+        let nb_clients_of = || nb_clients.iter().map(|nb| nb.load(Ordering::Relaxed)).collect::<Vec<_>>();
+
+        // The client is counted in its new pool
+        set_nb_clients(&nb_clients, &[9, 8, 9]);
+        assert_eq!(map.select_pool(region, 0, POOL_MAX_CLIENTS), 1);
+        assert_eq!(nb_clients_of(), [9, 9, 9]);
+
+        // The serving pool is full, the least populated of the others which is not full is chosen
+        set_nb_clients(&nb_clients, &[8, 10, 9]);
+        assert_eq!(map.select_pool(region, 2, POOL_MAX_CLIENTS), 0);
+        assert_eq!(nb_clients_of(), [9, 10, 9]);
+
+        // When all the pools are full, the client stays in its pool, which serves the region too
+        let region = (5, 0);
+        set_nb_clients(&nb_clients, &[10, 10, 10]);
+        assert_eq!(map.select_pool(region, 1, POOL_MAX_CLIENTS), 1);
+        assert_eq!(map.select_pool(region, 2, POOL_MAX_CLIENTS), 2);
+        assert_eq!(nb_clients_of(), [10, 10, 10]);
+        assert_eq!(map.regions.lock().unwrap()[&region], [1, 2]);
+    }
+
+
+    #[test]
+    fn region_released_by_its_last_client() {
+        let (map, nb_clients) = region_map(2);
+        let region = (4, 0);
+        let groups = map.covered_groups(45.0, 5.0, 50.0);
+        let mut pool_regions = PoolRegions::new();
+
+        let changes = pool_regions.set_client(0, Some((region, groups)));
+        assert_eq!((changes.released_region, changes.joined_groups, changes.left_groups), (None, groups, 0));
+        let changes = pool_regions.set_client(3, Some((region, groups)));
+        assert_eq!((changes.released_region, changes.joined_groups, changes.left_groups), (None, 0, 0));
+
+        let changes = pool_regions.set_client(0, None);
+        assert_eq!((changes.released_region, changes.joined_groups, changes.left_groups), (None, 0, 0));
+        let changes = pool_regions.set_client(3, None);
+        assert_eq!((changes.released_region, changes.joined_groups, changes.left_groups), (Some(region), 0, groups));
+
+        // Once released, the pool is no longer chosen for the region
+        set_nb_clients(&nb_clients, &[0, 5]);
+        assert_eq!(map.select_pool(region, 0, POOL_MAX_CLIENTS), 0);
+        set_nb_clients(&nb_clients, &[5, 0]);
+        map.release(region, 0);
+        map.release(region, 0);
+        assert!(map.regions.lock().unwrap().is_empty());
+        assert_eq!(map.select_pool(region, 0, POOL_MAX_CLIENTS), 1);
+    }
+
+
+    #[test]
+    fn migration_across_a_region_boundary() {
+        let (map, _) = region_map(1);
+        let mut pool_regions = PoolRegions::new();
+
+        // A client near the boundary of two regions needs the groups of both
+        let (latitude, longitude) = (45.0, 9.9);
+        let groups_before = map.covered_groups(latitude, longitude, 50.0);
+        pool_regions.set_client(0, Some((map.region_of(latitude, longitude), groups_before)));
+
+        // It crosses the boundary: its previous region is released, the groups still needed are kept
+        let (latitude, longitude) = (45.0, 10.1);
+        let groups_after = map.covered_groups(latitude, longitude, 50.0);
+        let changes = pool_regions.set_client(0, Some((map.region_of(latitude, longitude), groups_after)));
+        assert_eq!(changes.released_region, Some((4, 0)));
+        assert_eq!(changes.joined_groups, groups_after & !groups_before);
+        assert_eq!(changes.left_groups, groups_before & !groups_after);
+        assert_eq!(pool_regions.nb_clients_by_region.keys().copied().collect::<Vec<_>>(), [(4, 1)]);
+
+        // Coming back does not count it twice
+        let changes = pool_regions.set_client(0, Some(((4, 0), groups_before)));
+        assert_eq!(changes.released_region, Some((4, 1)));
+        assert_eq!(pool_regions.nb_clients_by_region.get(&(4, 0)), Some(&1));
+        assert_eq!(pool_regions.nb_clients_by_group.iter().sum::<usize>(), groups_before.count_ones() as usize);
+    }
+}
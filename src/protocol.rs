@@ -0,0 +1,326 @@
+//! Messages exchanged with the clients, apart from the GDL90 messages
+//! Each message is a datagram (see dgramostream), all the fields are in big endian.
+//!
+//! The clients which do not begin with a `HELLO` message only send position messages (legacy
+//! clients) and receive the GDL90 messages.
+//!
+//! Client to server, position message whose length depends on the client version:
+//! - 8 bytes: latitude and longitude (i32, in millionths of degree)
+//! - 12 bytes: + altitude (i32, in ft with QNH of 1013 hPa, `ALTITUDE_UNKNOWN` if not known)
+//! - 20 bytes: + filter request: radius (u16, in km), altitude band (u16, in ft),
+//!   maximum number of targets (u16) and minimum interval between two reports of the same
+//!   aircraft (u16, in ms). A value of 0 asks for the server default.
+//!
+//! Client to server, the other messages begin with their type, which cannot be confused with the
+//! first byte of a latitude (0xFA to 0x05):
+//! - `OWNSHIP`: addresses of the client's own aircraft, never sent back to it. Up to
+//!   `MAX_OWNSHIP_ADDRESSES` entries of 4 bytes: address type (u8, as in GDL90 traffic reports)
+//!   and address (24 bits). An empty list clears the registration.
+//! - `HELLO`: optional, before any other message. Highest protocol version supported by the
+//!   client (u8) and requested capabilities (u32, `CAP_*` bits).
+//!
+//! Server to client: the GDL90 messages begin with the flag byte 0x7E, the other messages begin
+//! with their type:
+//! - `FILTER_ACK`: filter really applied by the server, in response to a filter request, same
+//!   fields as the request.
+//! - `HELLO_ACK`: response to `HELLO`, protocol version used (u8), the lowest of the client and
+//!   server ones, and capabilities granted (u32), those requested and supported by the server.
+//! - `REJECT`: the client is refused, reason (u8, `RejectReason`) and suggested delay before
+//!   connecting again (u16, in s). The server closes the connection after it.
+//!
+
+use crate::{client::Position, traffic_infos::AddressType};
+
+
+/// Altitude value of a position message when the client does not know its altitude
+pub const ALTITUDE_UNKNOWN: i32 = i32::MIN;
+
+/// Maximum size of a message sent by a client
+pub const CLIENT_MSG_MAX_LEN: u16 = 64;
+
+/// Maximum number of addresses in an OWNSHIP message
+pub const MAX_OWNSHIP_ADDRESSES: usize = 8;
+
+/// Highest protocol version supported by the server
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Capabilities which can be requested by a client
+/// Compression and authentication are reserved for future versions, they are never granted
+pub const CAP_GDL90: u32 = 1 << 0;              // Traffic sent in GDL90 format
+#[allow(dead_code)]
+pub const CAP_COMPRESSION: u32 = 1 << 1;        // Compression of the server messages
+#[allow(dead_code)]
+pub const CAP_AUTH: u32 = 1 << 2;               // Authentication of the client
+
+/// Capabilities supported by the server
+pub const SUPPORTED_CAPABILITIES: u32 = CAP_GDL90;
+
+// Client message types
+const OWNSHIP: u8 = 0x10;
+const HELLO: u8 = 0x11;
+
+// Server message types
+const FILTER_ACK: u8 = 0x01;
+const HELLO_ACK: u8 = 0x02;
+const REJECT: u8 = 0x03;
+
+
+/// Filter parameters of a client, 0 meaning the server default for a request
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Filter {
+    pub radius: u16,                // In km
+    pub altitude_band: u16,         // In ft
+    pub max_targets: u16,
+    pub update_interval: u16,       // In ms
+}
+
+
+/// Reason of the refusal of a client, in a REJECT message
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum RejectReason {
+    ServerFull = 1,                 // Maximum number of clients of the server reached
+    PoolsFull = 2,                  // Maximum number of clients reached in all the pools
+}
+
+
+/// Message sent by a client
+pub enum ClientMsg {
+    Position(Position, Option<Filter>),
+    Ownship(Vec<(AddressType, u32)>),
+    Hello { version: u8, capabilities: u32 },
+}
+
+
+/// Parses a message sent by a client
+pub fn parse_client_msg(msg: &[u8]) -> anyhow::Result<ClientMsg> {
+    match msg.first() {
+        Some(&OWNSHIP) => Ok(ClientMsg::Ownship(parse_ownship_msg(&msg[1..])?)),
+        Some(&HELLO) => parse_hello_msg(&msg[1..]),
+        Some(0x06..=0xF9) => Err(anyhow::anyhow!("Unknown message type {:#04x}", msg[0])),
+        _ => {
+            let (position, filter) = parse_position_msg(msg)?;
+            Ok(ClientMsg::Position(position, filter))
+        }
+    }
+}
+
+
+// Parses a position message, with its optional filter request
+fn parse_position_msg(msg: &[u8]) -> anyhow::Result<(Position, Option<Filter>)> {
+    let mut parser = bytes_parser::BytesParser::from(msg);
+
+    let latitude = f64::from(parser.parse_i32()?) / 1_000_000.0;
+    anyhow::ensure!((-90.0..=90.0).contains(&latitude), "Latitude out of bounds");
+
+    let longitude = f64::from(parser.parse_i32()?) / 1_000_000.0;
+    anyhow::ensure!((-180.0..=180.0).contains(&longitude), "Longitude out of bounds");
+
+    // The altitude is optional, for compatibility with the clients which only send their 2D position
+    let altitude = if parser.is_at_end() {
+        None
+    }
+    else {
+        Some(parser.parse_i32()?).filter(|&altitude| altitude != ALTITUDE_UNKNOWN)
+    };
+
+    // The filter request is optional too
+    let filter = if parser.is_at_end() {
+        None
+    }
+    else {
+        Some(Filter {
+            radius: parser.parse_u16()?,
+            altitude_band: parser.parse_u16()?,
+            max_targets: parser.parse_u16()?,
+            update_interval: parser.parse_u16()?,
+        })
+    };
+
+    anyhow::ensure!(parser.is_at_end(), "Position message too long");
+
+    Ok((Position { latitude, longitude, altitude }, filter))
+}
+
+
+// Parses the content of an OWNSHIP message, after its type
+fn parse_ownship_msg(msg: &[u8]) -> anyhow::Result<Vec<(AddressType, u32)>> {
+    anyhow::ensure!(msg.len().is_multiple_of(4), "Invalid ownship message length");
+    anyhow::ensure!(msg.len() / 4 <= MAX_OWNSHIP_ADDRESSES, "Too many ownship addresses");
+
+    msg.chunks(4)
+        .map(|entry| {
+            let addr_type = AddressType::try_from(entry[0])?;
+            let address = u32::from_be_bytes([0, entry[1], entry[2], entry[3]]);
+            Ok((addr_type, address))
+        })
+        .collect()
+}
+
+
+// Parses the content of a HELLO message, after its type
+fn parse_hello_msg(msg: &[u8]) -> anyhow::Result<ClientMsg> {
+    let mut parser = bytes_parser::BytesParser::from(msg);
+
+    let version = parser.parse_u8()?;
+    anyhow::ensure!(version >= 1, "Invalid protocol version {}", version);
+    let capabilities = parser.parse_u32()?;
+
+    anyhow::ensure!(parser.is_at_end(), "Hello message too long");
+
+    Ok(ClientMsg::Hello { version, capabilities })
+}
+
+
+/// Formats a FILTER_ACK message in a provided buffer
+/// Returns the used size of the buffer
+pub fn make_filter_ack_msg(filter: &Filter, buffer: &mut [u8]) -> usize {
+    buffer[0] = FILTER_ACK;
+    buffer[1..3].copy_from_slice(&filter.radius.to_be_bytes());
+    buffer[3..5].copy_from_slice(&filter.altitude_band.to_be_bytes());
+    buffer[5..7].copy_from_slice(&filter.max_targets.to_be_bytes());
+    buffer[7..9].copy_from_slice(&filter.update_interval.to_be_bytes());
+    9
+}
+
+
+/// Formats a HELLO_ACK message in a provided buffer
+/// Returns the used size of the buffer
+pub fn make_hello_ack_msg(version: u8, capabilities: u32, buffer: &mut [u8]) -> usize {
+    buffer[0] = HELLO_ACK;
+    buffer[1] = version;
+    buffer[2..6].copy_from_slice(&capabilities.to_be_bytes());
+    6
+}
+
+
+/// Formats a REJECT message in a provided buffer
+/// Returns the used size of the buffer
+pub fn make_reject_msg(reason: RejectReason, retry_delay: u16, buffer: &mut [u8]) -> usize {
+    buffer[0] = REJECT;
+    buffer[1] = reason as u8;
+    buffer[2..4].copy_from_slice(&retry_delay.to_be_bytes());
+    4
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Builds a position message as sent by a client
+    fn position_msg(latitude: i32, longitude: i32, altitude: Option<i32>, filter: Option<Filter>) -> Vec<u8> {
+        let mut msg = [latitude.to_be_bytes(), longitude.to_be_bytes()].concat();
+        if let Some(altitude) = altitude {
+            msg.extend(altitude.to_be_bytes());
+        }
+        if let Some(filter) = filter {
+            for value in [filter.radius, filter.altitude_band, filter.max_targets, filter.update_interval] {
+                msg.extend(value.to_be_bytes());
+            }
+        }
+        msg
+    }
+
+
+    fn parse_position(msg: &[u8]) -> (Position, Option<Filter>) {
+        match parse_client_msg(msg).unwrap() {
+            ClientMsg::Position(position, filter) => (position, filter),
+            _ => panic!("Not a position message"),
+        }
+    }
+
+
+    #[test]
+    fn legacy_position() {
+        // Same message as the clients sending only their 2D position
+        let msg = [0x02, 0xb0, 0x8e, 0x40, 0xff, 0xe9, 0x1c, 0xa0];
+        assert_eq!(msg.to_vec(), position_msg(45_125_184, -1_500_000, None, None));
+        let (position, filter) = parse_position(&msg);
+        assert_eq!((position.latitude, position.longitude, position.altitude), (45.125184, -1.5, None));
+        assert!(filter.is_none());
+
+        // Both ends of the coordinates
+        let (position, _) = parse_position(&position_msg(-90_000_000, 180_000_000, None, None));
+        assert_eq!((position.latitude, position.longitude), (-90.0, 180.0));
+        assert!(parse_client_msg(&position_msg(90_000_001, 0, None, None)).is_err());
+        assert!(parse_client_msg(&position_msg(0, -180_000_001, None, None)).is_err());
+    }
+
+
+    #[test]
+    fn position_with_altitude_and_filter() {
+        let (position, filter) = parse_position(&position_msg(45_000_000, 5_000_000, Some(3500), None));
+        assert_eq!(position.altitude, Some(3500));
+        assert!(filter.is_none());
+        let (position, _) = parse_position(&position_msg(45_000_000, 5_000_000, Some(-200), None));
+        assert_eq!(position.altitude, Some(-200));
+
+        let requested = Filter { radius: 30, altitude_band: 5000, max_targets: 20, update_interval: 500 };
+        let msg = position_msg(45_000_000, 5_000_000, Some(ALTITUDE_UNKNOWN), Some(requested));
+        assert_eq!(msg.len(), 20);
+        let (position, filter) = parse_position(&msg);
+        assert_eq!((position.latitude, position.longitude, position.altitude), (45.0, 5.0, None));
+        assert_eq!(filter, Some(requested));
+    }
+
+
+    #[test]
+    fn position_of_wrong_length() {
+        let msg = position_msg(45_000_000, 5_000_000, Some(3500), Some(Filter::default()));
+        for len in [0, 4, 7, 10, 14, 19, 21] {
+            let mut msg = msg.clone();
+            msg.resize(len, 0);
+            assert!(parse_client_msg(&msg).is_err(), "{} bytes", len);
+        }
+    }
+
+
+    #[test]
+    fn filter_ack() {
+        let filter = Filter { radius: 30, altitude_band: 5000, max_targets: 20, update_interval: 500 };
+        let mut buffer = [0u8; 16];
+        let len = make_filter_ack_msg(&filter, &mut buffer);
+        assert_eq!(buffer[..len], [0x01, 0x00, 0x1e, 0x13, 0x88, 0x00, 0x14, 0x01, 0xf4]);
+    }
+
+
+    // Builds a HELLO message as sent by a client
+    fn hello_msg(version: u8, capabilities: u32) -> Vec<u8> {
+        [&[HELLO, version][..], &capabilities.to_be_bytes()].concat()
+    }
+
+
+    #[test]
+    fn hello_round_trip() {
+        for (version, capabilities) in [(1, CAP_GDL90), (2, CAP_GDL90 | CAP_COMPRESSION | CAP_AUTH), (255, 0)] {
+            let msg = hello_msg(version, capabilities);
+            assert!(matches!(parse_client_msg(&msg).unwrap(), ClientMsg::Hello { version: v, capabilities: c } if (v, c) == (version, capabilities)));
+        }
+        assert_eq!(hello_msg(1, CAP_GDL90), [0x11, 0x01, 0x00, 0x00, 0x00, 0x01]);
+    }
+
+
+    #[test]
+    fn invalid_hello() {
+        // Version 0 does not exist
+        assert!(parse_client_msg(&hello_msg(0, CAP_GDL90)).is_err());
+
+        let msg = hello_msg(1, CAP_GDL90);
+        assert!(parse_client_msg(&msg[..msg.len() - 1]).is_err());
+        assert!(parse_client_msg(&[&msg[..], &[0]].concat()).is_err());
+        assert!(parse_client_msg(&[0x20, 0x01]).is_err());
+    }
+
+
+    #[test]
+    fn hello_ack_and_reject() {
+        let mut buffer = [0u8; 16];
+        let len = make_hello_ack_msg(PROTOCOL_VERSION, SUPPORTED_CAPABILITIES, &mut buffer);
+        assert_eq!(buffer[..len], [0x02, 0x01, 0x00, 0x00, 0x00, 0x01]);
+        let len = make_reject_msg(RejectReason::PoolsFull, 30, &mut buffer);
+        assert_eq!(buffer[..len], [0x03, 0x02, 0x00, 0x1e]);
+        let len = make_reject_msg(RejectReason::ServerFull, 600, &mut buffer);
+        assert_eq!(buffer[..len], [0x03, 0x01, 0x02, 0x58]);
+    }
+}
@@ -1,53 +1,136 @@
-use crate::client_pool::ClientPool;
+use crate::{client_areas::SharedClientAreas, client_pool::ClientPool, config::SharedConfig, signals::Signals, traffic_store::SharedTrafficStore};
 
-use std::{net::TcpListener, thread};
+use nix::sys::{epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags, EpollTimeout}, signal::Signal};
+use std::{os::fd::AsFd, sync::{atomic::AtomicUsize, Arc}, time::{Duration, Instant}};
+
+
+// Event identifiers for use in epoll data field
+const EVENT_SIGNAL: u64 = 1;
+
+// Period of the maintenance of the traffic table
+const TRAFFIC_EXPIRY_PERIOD: Duration = Duration::from_secs(1);
+const TRAFFIC_STATS_PERIOD: Duration = Duration::from_secs(60);
 
 
 pub struct Server {
+    config: SharedConfig,
+    traffic_store: SharedTrafficStore,
     client_pools: Vec<ClientPool>
 }
 
 
 impl Server {
-    /// Creation of a new server
-    pub fn new() -> Self {
-        // Create a pool of clients for each CPU
-        let nb_cpus = thread::available_parallelism().unwrap().get();
-        let mut client_pools = Vec::new();
-        for _ in 0..nb_cpus {
-            client_pools.push(ClientPool::new());
-        }
-        log::info!("{} pools of clients created", nb_cpus);
+    /// Creation of a new server, whose pools begin to accept the client connections
+    /// and register the areas of the clients in client_areas
+    pub fn new(config: &SharedConfig, traffic_store: &SharedTrafficStore, client_areas: &SharedClientAreas) -> Self {
+        // Current number of clients for the whole server, shared by all the pools
+        let nb_server_clients = Arc::new(AtomicUsize::new(0));
+
+        // Create the pools of clients (one for each CPU by default)
+        let nb_pools = config.get().nb_pools();
+        let client_pools = ClientPool::new_pools(nb_pools, config, traffic_store, client_areas, nb_server_clients);
+        log::info!("{} pools of clients created, {:?} placement", nb_pools, config.get().server.placement);
+        log::info!("Listening for client connections on {}", config.get().listen_socket_addr());
 
-        Self {client_pools}
+        Self {
+            config: config.clone(),
+            traffic_store: traffic_store.clone(),
+            client_pools
+        }
     }
 
 
-    /// Listening and processing connections from clients
-    /// => This method is blocking
-    pub fn listen_connections(&self) {
-        let listener = TcpListener::bind("0.0.0.0:1664").unwrap();
+    /// Processing of the signals, while the pools process the connections from clients
+    /// => This method is blocking until a stop signal (SIGTERM or SIGINT) is received
+    /// The configuration is reloaded when SIGHUP is received
+    /// The old aircraft are regularly deleted from the traffic table
+    pub fn run(&self, signals: &Signals) {
+        // Wait for the signals
+        let epoll = Epoll::new(EpollCreateFlags::empty()).unwrap();
+        epoll.add(signals.as_fd(), EpollEvent::new(EpollFlags::EPOLLIN, EVENT_SIGNAL)).unwrap();
+
+        let mut epoll_events = [EpollEvent::empty(); 1];
+        let mut last_stats = Instant::now();
         loop {
-            // Wait for a new client connection
-            let (socket, _) = listener.accept().unwrap();
+            let nb_events = epoll.wait(&mut epoll_events, EpollTimeout::try_from(TRAFFIC_EXPIRY_PERIOD).unwrap()).unwrap();
+
+            // Maintenance of the traffic table
+            self.expire_traffic();
+            if last_stats.elapsed() >= TRAFFIC_STATS_PERIOD {
+                self.log_traffic_stats();
+                last_stats = Instant::now();
+            }
 
-            // Add the new client to the least populated pool
-            self.least_polpulated_pool().add_new_client(socket);
+            for epoll_event in epoll_events.iter().take(nb_events) {
+                match epoll_event.data() {
+                    EVENT_SIGNAL => {
+                        while let Some(signal) = signals.read() {
+                            if (signal == Signal::SIGTERM) || (signal == Signal::SIGINT) {
+                                // The pools close their listener, no more connection is accepted
+                                log::info!("{} received, stop accepting connections", signal);
+                                for client_pool in &self.client_pools {
+                                    client_pool.stop_accepting();
+                                }
+                                return;
+                            }
+                            else if signal == Signal::SIGHUP {
+                                self.reload_config();
+                            }
+                        }
+                    }
+
+                    event => panic!("Unknown event : {event}"),
+                }
+            }
         }
     }
 
 
-    fn least_polpulated_pool(&self) -> &ClientPool {
-        let mut min = self.client_pools[0].get_nb_clients();
-        let mut idx = 0;
-        for i in 1..self.client_pools.len() {
-            let nb_clients = self.client_pools[i].get_nb_clients();
-            if nb_clients < min {
-                min = nb_clients;
-                idx = i;
-            }
+    /// Stops all the pools, their clients must be closed before the deadline
+    /// => This method is blocking until all the pools are stopped
+    pub fn shutdown(self, deadline: Instant) {
+        // Ask all the pools to stop at the same time, then wait for them
+        for client_pool in &self.client_pools {
+            client_pool.shutdown(deadline);
+        }
+        self.log_traffic_stats();
+        for (i, client_pool) in self.client_pools.into_iter().enumerate() {
+            let summary = client_pool.join();
+            log::info!("Pool {} stopped : {} clients accepted, {} refused, {} sent to another pool when full, {} moved to another pool, \
+                {} traffic received, {} sent, {} dropped for {} slow clients, {} clients closed cleanly, {} forcibly",
+                i, summary.nb_clients_accepted, summary.nb_clients_refused, summary.nb_clients_overflowed, summary.nb_clients_migrated, summary.nb_traffic_received,
+                summary.nb_traffic_sent, summary.nb_traffic_dropped, summary.nb_slow_clients,
+                summary.nb_clients_closed_cleanly, summary.nb_clients_closed_forcibly);
+        }
+    }
+
+
+    fn expire_traffic(&self) {
+        let max_age = Duration::from_secs(self.config.get().traffic.max_age);
+        let nb_expired = self.traffic_store.lock().unwrap().expire(max_age);
+        if nb_expired > 0 {
+            log::debug!("{} aircraft expired from the traffic table", nb_expired);
+        }
+    }
+
+
+    fn log_traffic_stats(&self) {
+        let stats = self.traffic_store.lock().unwrap().stats();
+        let mut by_source: Vec<String> = stats.nb_aircraft_by_source.iter()
+            .map(|(source, nb)| format!("{} {}", nb, source))
+            .collect();
+        by_source.sort();
+        log::info!("Traffic table : {} aircraft ({}), {} reported by several sources",
+            stats.nb_aircraft, by_source.join(", "), stats.nb_fused_aircraft);
+    }
+
+
+    fn reload_config(&self) {
+        // In case of error, the current configuration is kept
+        match self.config.reload() {
+            Ok(()) => log::info!("Configuration reloaded"),
+            Err(e) => log::error!("Unable to reload the configuration, the current one is kept : {:#}", e),
         }
-        &self.client_pools[idx]
     }
 
 }
@@ -0,0 +1,46 @@
+//! Reception of the signals of the process through a file descriptor,
+//! so that they can be waited with epoll like any other event
+//!
+
+use nix::sys::{signal::{SigSet, Signal}, signalfd::{SfdFlags, SignalFd}};
+use std::os::fd::{AsFd, BorrowedFd};
+
+
+pub struct Signals {
+    fd: SignalFd,
+}
+
+impl Signals {
+    /// Blocks the handled signals and creates the file descriptor to receive them
+    /// Warning: must be called before the creation of any thread, so that all threads inherit
+    /// the signal mask and the signals are only delivered to the file descriptor
+    pub fn new() -> Self {
+        let mut mask = SigSet::empty();
+        mask.add(Signal::SIGINT);
+        mask.add(Signal::SIGTERM);
+        mask.add(Signal::SIGHUP);
+        mask.thread_block().unwrap();
+
+        let fd = SignalFd::with_flags(&mask, SfdFlags::SFD_NONBLOCK).unwrap();
+
+        Self {fd}
+    }
+
+    /// Reads the next pending signal, if any
+    pub fn read(&self) -> Option<Signal> {
+        match self.fd.read_signal() {
+            Ok(Some(info)) => Signal::try_from(info.ssi_signo as i32).ok(),
+            Ok(None) => None,
+            Err(e) => {
+                log::warn!("Signal read error : {}", e);
+                None
+            }
+        }
+    }
+}
+
+impl AsFd for Signals {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
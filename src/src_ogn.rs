@@ -1,55 +1,94 @@
-use crate::{internal_com, traffic_infos::{AddressType, TrafficInfos}};
+use crate::{client_areas::{self, SharedClientAreas}, config::{OgnConfig, Region, SharedConfig}, ddb::SharedDdb, stop_flag::StopFlag, traffic_infos::{AddressType, Source, TrafficInfos, OGN_ID_FLAG_NO_TRACKING}, traffic_store::{Publisher, SharedTrafficStore}};
 
 use quick_xml::{events::Event, Reader};
 use std::{thread, time, str::FromStr};
 
 
+// Maximum duration of a request to the web map
+const REQUEST_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+
 pub struct SrcOgn {
-    sender: internal_com::Sender,
+    config: SharedConfig,
+    client_areas: SharedClientAreas,
+    ddb: SharedDdb,
+    publisher: Publisher,
 }
 
 impl SrcOgn {
     /// Starts reception of OGN traffic
-    pub fn start_receive() {
-        thread::spawn(|| {
-            Self::work_thread();
-        });
+    pub fn start_receive(config: &SharedConfig, store: &SharedTrafficStore, client_areas: &SharedClientAreas, ddb: &SharedDdb, stop_flag: StopFlag) -> thread::JoinHandle<()> {
+        let ogn = Self::new(config, store, client_areas, ddb);
+        thread::spawn(move || {
+            ogn.work_thread(&stop_flag);
+        })
     }
 
 
-    fn new() -> SrcOgn {
+    fn new(config: &SharedConfig, store: &SharedTrafficStore, client_areas: &SharedClientAreas, ddb: &SharedDdb) -> SrcOgn {
         SrcOgn {
-            sender: internal_com::Sender::new(),
+            config: config.clone(),
+            client_areas: client_areas.clone(),
+            ddb: ddb.clone(),
+            publisher: Publisher::new(config, store, Source::Ogn),
         }
     }
 
 
-    fn work_thread() {
-        let ogn = Self::new();
+    fn work_thread(&self, stop_flag: &StopFlag) {
         loop {
-            if let Err(e) = ogn.get_and_send_positions() {
-                log::warn!("{:?}", e);
+            // The configuration is read at each request to take into account a reload
+            // One request is made for each box covering the areas of the clients
+            let config = self.config.get();
+            for region in Self::query_regions(&config.ogn, &self.client_areas) {
+                if let Err(e) = self.get_and_send_positions(&region) {
+                    log::warn!("{:?}", e);
+                }
+            }
+            if !stop_flag.sleep(time::Duration::from_secs(config.ogn.poll_interval)) {
+                break;
             }
-            thread::sleep(time::Duration::from_secs(5));
         }
+        log::info!("OGN source stopped");
     }
 
 
-    fn get_and_send_positions(&self) -> anyhow::Result<()> {
-        let ogn_string = Self::get_ogn_string()?;
+    /// Get the boxes to request from OGN, at most max_boxes
+    pub fn query_regions(config: &OgnConfig, client_areas: &SharedClientAreas) -> Vec<Region> {
+        if !config.dynamic_region {
+            return config.region.split_at_antimeridian();
+        }
+
+        // The areas are copied so as not to keep them locked while merging them
+        let boxes = client_areas.lock().unwrap().boxes();
+        if !boxes.is_empty() {
+            client_areas::merge_boxes(boxes, config.max_boxes)
+        }
+        else if config.worldwide_fallback {
+            vec![Region::WORLD]
+        }
+        else {
+            config.region.split_at_antimeridian()
+        }
+    }
+
+
+    fn get_and_send_positions(&self, region: &Region) -> anyhow::Result<()> {
+        let ogn_string = Self::get_ogn_string(region)?;
         self.parse_ogn_string(&ogn_string)?;
         Ok(())
     }
 
 
-    fn get_ogn_string() -> anyhow::Result<String> {
-        // We retrieve traffic information on France
-        let ogn_string = ureq::get("https://live.glidernet.org/lxml.php?\
-            a=0\
-            &b=51.3\
-            &c=42.1\
-            &d=8.4\
-            &e=-5.1")
+    fn get_ogn_string(region: &Region) -> anyhow::Result<String> {
+        // We retrieve traffic information on the configured region
+        let ogn_string = ureq::get("https://live.glidernet.org/lxml.php")
+            .query("a", "0")
+            .query("b", &region.north.to_string())
+            .query("c", &region.south.to_string())
+            .query("d", &region.east.to_string())
+            .query("e", &region.west.to_string())
+            .timeout(REQUEST_TIMEOUT)
             .call()?
             .into_string()?;
         Ok(ogn_string)
@@ -61,26 +100,28 @@ impl SrcOgn {
         let mut reader = Reader::from_str(ogn_string);
         loop {
             match reader.read_event()? {
-                Event::Empty(element) => {
-                    // OGN traffic is contained in empty XML elements with name "m"
-                    if element.local_name().as_ref() == b"m" {
-                        // Browse element attributes
-                        for attribute in element.attributes() {
-                            match attribute {
-                                Err(e) => return Err(anyhow::anyhow!("Incorrect attribute : {}", e)),
-                                Ok(attr) => {
-                                    // The attribute containing the traffic information is "a"
-                                    if attr.key.local_name().as_ref() == b"a" {
-                                        // We recover its value
-                                        let traffic_string = &(attr.unescape_value()?);
-
-                                        // Analysis of the traffic chain
-                                        let traffic_infos = Self::parse_traffic(traffic_string)?;
-                                        //println!("{:?}", traffic_infos);
-    
-                                        // Sending traffic information to clients
-                                        self.sender.send(&traffic_infos);
-                                    }    
+                // OGN traffic is contained in empty XML elements with name "m"
+                Event::Empty(element) if element.local_name().as_ref() == b"m" => {
+                    // Browse element attributes
+                    for attribute in element.attributes() {
+                        match attribute {
+                            Err(e) => return Err(anyhow::anyhow!("Incorrect attribute : {}", e)),
+                            Ok(attr) => {
+                                // The attribute containing the traffic information is "a"
+                                if attr.key.local_name().as_ref() == b"a" {
+                                    // We recover its value
+                                    let traffic_string = &(attr.unescape_value()?);
+
+                                    // Analysis of the traffic chain, the aircraft not to be tracked are ignored
+                                    let Some(mut traffic_infos) = Self::parse_traffic(traffic_string)? else {
+                                        continue;
+                                    };
+                                    //println!("{:?}", traffic_infos);
+
+                                    // Sending traffic information to clients, as allowed by the owner of the device
+                                    if self.ddb.read().unwrap().apply(&mut traffic_infos) {
+                                        self.publisher.publish(traffic_infos);
+                                    }
                                 }
                             }
                         }
@@ -95,7 +136,8 @@ impl SrcOgn {
     }
 
 
-    fn parse_traffic(traffic_string: &str) -> anyhow::Result<TrafficInfos> {
+    // Returns None if the owner does not want the aircraft to be tracked
+    fn parse_traffic(traffic_string: &str) -> anyhow::Result<Option<TrafficInfos>> {
         let mut traffic_infos = TrafficInfos::default();
 
         // Breaking down and parsing each field in the traffic chain
@@ -110,14 +152,23 @@ impl SrcOgn {
                 8 => traffic_infos.ground_speed = Some(Self::kmh_to_kt(i32::from_str(traffic_field)?)),
                 9 => traffic_infos.vertical_speed = Some(Self::mps_to_fpm(f64::from_str(traffic_field)?)),
                 13 => {
-                    let address = u32::from_str_radix(traffic_field, 16)?;
-                    traffic_infos.address = address & 0x00ff_ffff; // We only keep the 24 least significant bits
-                    traffic_infos.addr_type = AddressType::Ogn;
+                    // OGN id: flags and address type on the most significant byte, then the address
+                    let id = u32::from_str_radix(traffic_field, 16)?;
+                    let flags = (id >> 24) as u8;
+                    if flags & OGN_ID_FLAG_NO_TRACKING != 0 {
+                        return Ok(None);
+                    }
+                    traffic_infos.address = id & 0x00ff_ffff;
+                    // Only the explicit types are mapped, the ids without type are still sent as OGN addresses
+                    traffic_infos.addr_type = match flags & 0x03 {
+                        0 => AddressType::Ogn,
+                        _ => AddressType::from_ogn_id(flags),
+                    };
                 },
                 _ => () // The other fields are not used and are considered valid
             }
         }
-        Ok(traffic_infos)
+        Ok(Some(traffic_infos))
     }
 
 
@@ -135,4 +186,50 @@ impl SrcOgn {
         (mps * 196.850_394) as i32
     }
 
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Attribute of an aircraft of the web map, with its OGN id in the last field
+    const LXML_TRAFFIC: &str = "44.25683,6.00050,BA,F-CHBA,1684,16:58:29,2,342,91,-2.3,1,LFMX,0,2DDA5BA";
+
+
+    fn parse(traffic_string: &str) -> TrafficInfos {
+        SrcOgn::parse_traffic(traffic_string).unwrap().unwrap()
+    }
+
+
+    #[test]
+    fn traffic_of_the_web_map() {
+        let infos = parse(LXML_TRAFFIC);
+        assert_eq!((infos.latitude, infos.longitude), (44.25683, 6.0005));
+        assert_eq!(infos.callsign, "BA");
+        assert_eq!(infos.altitude, 5524);
+        assert_eq!((infos.track, infos.ground_speed, infos.vertical_speed), (Some(342), Some(49), Some(-452)));
+    }
+
+
+    #[test]
+    fn address_type_of_the_ogn_id() {
+        let address = |id: &str| {
+            let infos = parse(&LXML_TRAFFIC.replace("2DDA5BA", id));
+            (infos.addr_type, infos.address)
+        };
+        assert_eq!(address("2DDA5BA"), (AddressType::Flarm, 0xdda5ba));
+        assert_eq!(address("13950A1"), (AddressType::AdsbIcao, 0x3950a1));
+        assert_eq!(address("3A1B2C3"), (AddressType::Ogn, 0xa1b2c3));
+        assert_eq!(address("0A1B2C3"), (AddressType::Ogn, 0xa1b2c3));
+        assert_eq!(address("DDA5BA"), (AddressType::Ogn, 0xdda5ba));
+    }
+
+
+    #[test]
+    fn aircraft_not_to_be_tracked() {
+        assert!(SrcOgn::parse_traffic(&LXML_TRAFFIC.replace("2DDA5BA", "42DDA5BA")).unwrap().is_none());
+        assert!(SrcOgn::parse_traffic(&LXML_TRAFFIC.replace("2DDA5BA", "41ABCDEF")).unwrap().is_none());
+        assert!(SrcOgn::parse_traffic(&LXML_TRAFFIC.replace("2DDA5BA", "XYZ")).is_err());
+    }
+}
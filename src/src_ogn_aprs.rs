@@ -0,0 +1,463 @@
+//! Get aircraft informations from the OGN network through its APRS-IS servers
+//! Once logged in, the server streams the beacons received in the areas given by the filter of the login.
+//! The filter is updated on the same connection when the areas of the clients change.
+//! See <https://github.com/glidernet/ogn-aprs-protocol> for the format of the beacons.
+//!
+
+use crate::{client_areas::SharedClientAreas, config::{Region, SharedConfig}, ddb::SharedDdb, src_ogn::SrcOgn, stop_flag::StopFlag, traffic_infos::{AddressType, Source, TrafficInfos, OGN_ID_FLAG_NO_TRACKING}, traffic_store::{Publisher, SharedTrafficStore}};
+
+use anyhow::Context;
+use std::{io::{BufRead, BufReader, Read, Write}, net::TcpStream, thread, time::{Duration, Instant}, str::FromStr};
+
+// Maximum time to wait for data before checking if the stop is requested
+const STOP_CHECK_PERIOD: Duration = Duration::from_secs(1);
+
+// The server sends a comment every 20 seconds, the connection is considered lost without data for longer
+const DATA_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Interval between two comments sent to the server, which closes the idle connections
+const KEEPALIVE_PERIOD: Duration = Duration::from_secs(240);
+
+// Maximum length of a line, the APRS-IS lines are shorter
+const MAX_LINE_LEN: usize = 1024;
+
+// Length of the position of a beacon: timestamp, latitude, symbol table, longitude and symbol code
+const POSITION_LEN: usize = 26;
+
+
+pub struct SrcOgnAprs {
+    config: SharedConfig,
+    client_areas: SharedClientAreas,
+    ddb: SharedDdb,
+    publisher: Publisher,
+}
+
+impl SrcOgnAprs {
+    /// Starts reception of OGN traffic
+    pub fn start_receive(config: &SharedConfig, store: &SharedTrafficStore, client_areas: &SharedClientAreas, ddb: &SharedDdb, stop_flag: StopFlag) -> thread::JoinHandle<()> {
+        let ogn = Self::new(config, store, client_areas, ddb);
+        thread::spawn(move || {
+            ogn.work_thread(&stop_flag);
+        })
+    }
+
+
+    fn new(config: &SharedConfig, store: &SharedTrafficStore, client_areas: &SharedClientAreas, ddb: &SharedDdb) -> SrcOgnAprs {
+        SrcOgnAprs {
+            config: config.clone(),
+            client_areas: client_areas.clone(),
+            ddb: ddb.clone(),
+            publisher: Publisher::new(config, store, Source::Ogn),
+        }
+    }
+
+
+    fn work_thread(&self, stop_flag: &StopFlag) {
+        loop {
+            match self.get_and_send_positions(stop_flag) {
+                Err(e) => {
+                    log::warn!("{:#}", e);
+                    if !stop_flag.sleep(Duration::from_secs(5)) {
+                        break;
+                    }
+                }
+                Ok(()) => {
+                    // Stop requested, or reconnection without waiting because the login has changed
+                    if stop_flag.is_stopped() {
+                        break;
+                    }
+                }
+            }
+        }
+        log::info!("OGN source stopped");
+    }
+
+
+    /// Returns an error when the connection is lost, or Ok when the stop is requested or the login has changed
+    fn get_and_send_positions(&self, stop_flag: &StopFlag) -> anyhow::Result<()> {
+        // Connection to the APRS-IS server, which only sends the beacons of the requested regions
+        let ogn = self.config.get().ogn.clone();
+        let mut regions = SrcOgn::query_regions(&ogn, &self.client_areas);
+        let mut last_regions_update = Instant::now();
+        let mut connection = AprsConnection::connect(&ogn.aprs_address, &Self::login_line(&ogn.aprs_callsign, &regions))?;
+        log::info!("Connected to OGN APRS-IS {} with filter {}", ogn.aprs_address, Self::filter(&regions));
+
+        // Line reading and processing loop, until the stop is requested
+        loop {
+            let Some(line) = connection.next_line(stop_flag)? else {
+                return Ok(());
+            };
+
+            // If the server or the callsign have been changed by a configuration reload, we reconnect
+            let current = &self.config.get().ogn;
+            if current.aprs_address != ogn.aprs_address || current.aprs_callsign != ogn.aprs_callsign {
+                log::info!("OGN APRS-IS login has changed, reconnection");
+                return Ok(());
+            }
+
+            // The filter follows the areas of the clients, without changing it too often
+            if last_regions_update.elapsed() >= Duration::from_secs(current.region_update_interval) {
+                last_regions_update = Instant::now();
+                let new_regions = SrcOgn::query_regions(current, &self.client_areas);
+                if new_regions != regions {
+                    regions = new_regions;
+                    connection.send(&format!("#filter {}\r\n", Self::filter(&regions)))?;
+                    log::info!("OGN APRS-IS filter changed to {}", Self::filter(&regions));
+                }
+            }
+
+            // Comments of the server, only the response to the login is of interest
+            if let Some(comment) = line.strip_prefix('#') {
+                if comment.trim_start().starts_with("logresp") {
+                    log::info!("OGN APRS-IS login response :{}", comment);
+                }
+                continue;
+            }
+
+            // A wrong beacon does not prevent from receiving the next ones
+            // The traffic is published as allowed by the owner of the device
+            match Self::parse_beacon(&line) {
+                Ok(Some(mut traffic_infos)) => {
+                    if self.ddb.read().unwrap().apply(&mut traffic_infos) {
+                        self.publisher.publish(traffic_infos);
+                    }
+                }
+                Ok(None) => (),     // Not an aircraft position
+                Err(e) => log::debug!("Invalid OGN beacon ({:#}) : {}", e, line),
+            }
+        }
+    }
+
+
+    // Login line, without passcode for a read only access, with a filter on the regions
+    fn login_line(callsign: &str, regions: &[Region]) -> String {
+        format!("user {} pass -1 vers {} {} filter {}\r\n", callsign, env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"), Self::filter(regions))
+    }
+
+
+    // Area filter of the APRS-IS server, for each region: north latitude, west longitude, south latitude, east longitude
+    fn filter(regions: &[Region]) -> String {
+        regions.iter()
+            .map(|region| format!("a/{}/{}/{}/{}", region.north, region.west, region.south, region.east))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+
+    /// Parses an APRS line received from the OGN network
+    /// Returns None if it is not the position of an aircraft (receiver beacons, aircraft not to be tracked...)
+    fn parse_beacon(line: &str) -> anyhow::Result<Option<TrafficInfos>> {
+        // The sender and the path are followed by the APRS data
+        let (_, data) = line.split_once(':').context("No APRS data")?;
+
+        // The OGN positions begin with their timestamp
+        let Some(position) = data.strip_prefix('/').or_else(|| data.strip_prefix('@')) else {
+            return Ok(None);
+        };
+        anyhow::ensure!(position.len() >= POSITION_LEN && position.as_bytes()[..POSITION_LEN].is_ascii(), "Invalid APRS position");
+        let extension = &position[POSITION_LEN..];
+
+        // The aircraft are identified by the id field, the receivers do not have one
+        let mut fields = extension.split(' ');
+        let course_speed_altitude = fields.next().unwrap();
+        let mut id = None;
+        let mut precision = (0, 0);
+        let mut vertical_speed = None;
+        let mut turn_rate = None;
+        for field in fields {
+            if let Some(digits) = field.strip_prefix("!W").and_then(|field| field.strip_suffix('!')) {
+                // Thousandths of minute of the latitude and of the longitude
+                let digits: Vec<_> = digits.chars().filter_map(|digit| digit.to_digit(10)).collect();
+                anyhow::ensure!(digits.len() == 2, "Invalid position precision");
+                precision = (digits[0], digits[1]);
+            }
+            else if let Some(value) = field.strip_prefix("id").filter(|value| value.len() == 8 && value.is_ascii()) {
+                id = Some(value);
+            }
+            else if let Some(value) = field.strip_suffix("fpm") {
+                vertical_speed = Some(i32::from_str(value).context("Failed to parse OGN climb rate")?);
+            }
+            else if let Some(value) = field.strip_suffix("rot") {
+                // In half turns per minute, i.e. 3 degrees per second
+                turn_rate = Some(f64::from_str(value).context("Failed to parse OGN turn rate")? * 3.0);
+            }
+        }
+        let Some(id) = id else {
+            return Ok(None);
+        };
+
+        // The first byte of the id gives the flags and the address type, followed by the address
+        let flags = u8::from_str_radix(&id[..2], 16).context("Failed to parse OGN id")?;
+        let address = u32::from_str_radix(&id[2..], 16).context("Failed to parse OGN address")?;
+        if flags & OGN_ID_FLAG_NO_TRACKING != 0 {
+            return Ok(None);
+        }
+
+        // Position after the timestamp, the symbols are not used
+        let latitude = Self::parse_coordinate(&position[7..15], 2, precision.0, ('N', 'S')).context("Failed to parse OGN latitude")?;
+        let longitude = Self::parse_coordinate(&position[16..25], 3, precision.1, ('E', 'W')).context("Failed to parse OGN longitude")?;
+        anyhow::ensure!((-90.0..=90.0).contains(&latitude), "OGN latitude out of bounds");
+        anyhow::ensure!((-180.0..=180.0).contains(&longitude), "OGN longitude out of bounds");
+
+        // Optional course and speed, then altitude
+        let (course_speed, altitude) = course_speed_altitude.split_once("/A=").context("No OGN altitude")?;
+        let altitude = i32::from_str(altitude).context("Failed to parse OGN altitude")?;
+        let (track, ground_speed) = match course_speed.split_once('/') {
+            None => (None, None),
+            Some((course, speed)) => {
+                let course = u32::from_str(course).context("Failed to parse OGN course")?;
+                anyhow::ensure!(course <= 360, "OGN course out of range");
+                let speed = i32::from_str(speed).context("Failed to parse OGN speed")?;
+                // A course of 0 means that it is unknown
+                ((course != 0).then_some(course), Some(speed))
+            }
+        };
+
+        Ok(Some(TrafficInfos {
+            addr_type: AddressType::from_ogn_id(flags),
+            address,
+            callsign: String::new(),            // Not given by the beacons
+            altitude,
+            latitude,
+            longitude,
+            track,
+            ground_speed,
+            vertical_speed,
+            turn_rate,
+            ..Default::default()
+        }))
+    }
+
+
+    // Parses a latitude (DDMM.mmN) or a longitude (DDDMM.mmE) in degrees,
+    // with the thousandths of minute given by the precision field
+    fn parse_coordinate(field: &str, degrees_len: usize, thousandths: u32, (positive, negative): (char, char)) -> anyhow::Result<f64> {
+        let degrees = f64::from(u32::from_str(&field[..degrees_len])?);
+        let minutes = f64::from_str(&field[degrees_len..field.len() - 1])? + f64::from(thousandths) / 1000.0;
+        anyhow::ensure!(minutes < 60.0, "Minutes out of range");
+        let coordinate = degrees + minutes / 60.0;
+        match field.chars().last() {
+            Some(hemisphere) if hemisphere == positive => Ok(coordinate),
+            Some(hemisphere) if hemisphere == negative => Ok(-coordinate),
+            _ => Err(anyhow::anyhow!("Invalid hemisphere")),
+        }
+    }
+
+}
+
+
+// Connection to an APRS-IS server, giving the received lines
+struct AprsConnection {
+    reader: BufReader<TcpStream>,
+    line: Vec<u8>,                  // Line being received
+    last_received: Instant,
+    last_sent: Instant,
+}
+
+impl AprsConnection {
+    // Connects to the server and sends the login line
+    fn connect(address: &str, login: &str) -> anyhow::Result<Self> {
+        let mut sock = TcpStream::connect(address).context("Failed to connect to OGN APRS-IS")?;
+        Self::set_sock_options(&sock);
+        sock.write_all(login.as_bytes()).context("Failed to log in to OGN APRS-IS")?;
+
+        Ok(Self {
+            reader: BufReader::new(sock),
+            line: Vec::new(),
+            last_received: Instant::now(),
+            last_sent: Instant::now(),
+        })
+    }
+
+
+    fn set_sock_options(socket: &TcpStream) {
+        let sock = socket2::SockRef::from(socket);
+
+        // Setting TCP keepalive
+        let keepalive = socket2::TcpKeepalive::new()
+            .with_time(Duration::from_secs(30))
+            .with_interval(Duration::from_secs(5))
+            .with_retries(2);
+        sock.set_tcp_keepalive(&keepalive).unwrap();
+
+        // Setting a reception timeout to regularly check if the stop is requested
+        socket.set_read_timeout(Some(STOP_CHECK_PERIOD)).unwrap();
+    }
+
+
+    // Sends a line to the server, with its end of line
+    fn send(&mut self, line: &str) -> anyhow::Result<()> {
+        self.reader.get_mut().write_all(line.as_bytes()).context("Failed to send data to OGN APRS-IS")?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+
+    // Get the next line, without its end of line
+    // Returns None if the stop is requested
+    fn next_line(&mut self, stop_flag: &StopFlag) -> anyhow::Result<Option<String>> {
+        loop {
+            if stop_flag.is_stopped() {
+                return Ok(None);
+            }
+
+            // The server must regularly receive something to keep the connection open
+            if self.last_sent.elapsed() >= KEEPALIVE_PERIOD {
+                self.send("# keepalive\r\n")?;
+            }
+
+            // The part of the line already received is kept when the timeout expires
+            let max_len = (MAX_LINE_LEN - self.line.len()) as u64;
+            match self.reader.by_ref().take(max_len).read_until(b'\n', &mut self.line) {
+                Ok(_) if self.line.ends_with(b"\n") => {
+                    self.last_received = Instant::now();
+                    let line = String::from_utf8_lossy(&self.line).trim_end().to_string();
+                    self.line.clear();
+                    return Ok(Some(line));
+                }
+                Ok(_) => {
+                    anyhow::ensure!(self.line.len() < MAX_LINE_LEN, "Line too long received from OGN APRS-IS");
+                    return Err(anyhow::anyhow!("Connection closed by OGN APRS-IS"));
+                }
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {
+                    // Nothing received during the timeout
+                    anyhow::ensure!(self.last_received.elapsed() < DATA_TIMEOUT, "No data received from OGN APRS-IS for {} s", DATA_TIMEOUT.as_secs());
+                }
+                Err(e) => return Err(e).context("Failed to read data from OGN APRS-IS"),
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{client_areas::ClientAreas, config::OgnConfig};
+    use std::net::TcpListener;
+
+    const FLARM_BEACON: &str = "FLRDDA5BA>OGFLR,qAS,LFMX:/165829h4415.41N/00600.03E'342/049/A=005524 !W65! id0ADDA5BA -454fpm -1.1rot 8.8dB 0e +51.2kHz gps4x5";
+
+
+    fn parse(line: &str) -> TrafficInfos {
+        SrcOgnAprs::parse_beacon(line).unwrap().unwrap()
+    }
+
+
+    #[test]
+    fn flarm_beacon() {
+        let infos = parse(FLARM_BEACON);
+        assert_eq!(infos.addr_type, AddressType::Flarm);
+        assert_eq!(infos.address, 0xDDA5BA);
+        assert!((infos.latitude - (44.0 + 15.416 / 60.0)).abs() < 1e-9);
+        assert!((infos.longitude - (6.0 + 0.035 / 60.0)).abs() < 1e-9);
+        assert_eq!(infos.altitude, 5524);
+        assert_eq!(infos.track, Some(342));
+        assert_eq!(infos.ground_speed, Some(49));
+        assert_eq!(infos.vertical_speed, Some(-454));
+        assert!((infos.turn_rate.unwrap() - -3.3).abs() < 1e-9);
+    }
+
+
+    #[test]
+    fn beacon_without_optional_fields() {
+        // South and west hemispheres, without course, speed, climb and turn rates
+        let infos = parse("OGN123456>OGNTRK,qAS,Rcv:/092540h3352.50S/15112.75W'/A=-00012 id07123456");
+        assert_eq!((infos.addr_type, infos.address), (AddressType::Ogn, 0x123456));
+        assert!((infos.latitude - -(33.0 + 52.5 / 60.0)).abs() < 1e-9);
+        assert!((infos.longitude - -(151.0 + 12.75 / 60.0)).abs() < 1e-9);
+        assert_eq!(infos.altitude, -12);
+        assert_eq!((infos.track, infos.ground_speed, infos.vertical_speed, infos.turn_rate), (None, None, None, None));
+
+        // Course unknown
+        let infos = parse("ICA3D1C35>OGFLR,qAS,Rcv:/092540h4612.50N/00112.75E'000/000/A=000394 id053D1C35 +000fpm +0.0rot");
+        assert_eq!((infos.track, infos.ground_speed), (None, Some(0)));
+
+        // ICAO address of a FLARM device, and random address
+        assert_eq!((infos.addr_type, infos.address), (AddressType::AdsbIcao, 0x3D1C35));
+        assert_eq!(parse(&FLARM_BEACON.replace("id0ADDA5BA", "id08DDA5BA")).addr_type, AddressType::Random);
+    }
+
+
+    #[test]
+    fn ignored_lines() {
+        // Receiver position and status, aircraft which does not want to be tracked
+        assert!(SrcOgnAprs::parse_beacon("LFMX>OGNSDR,TCPIP*,qAC,GLIDERN2:/165825h4405.35NI00559.59E&/A=001729").unwrap().is_none());
+        assert!(SrcOgnAprs::parse_beacon("LFMX>OGNSDR,TCPIP*,qAC,GLIDERN2:>165825h v0.2.8.RPI-GPU CPU:0.5 RAM:759.1/970.5MB").unwrap().is_none());
+        assert!(SrcOgnAprs::parse_beacon(&FLARM_BEACON.replace("id0ADDA5BA", "id4ADDA5BA")).unwrap().is_none());
+    }
+
+
+    #[test]
+    fn invalid_beacons() {
+        for line in [
+            "FLRDDA5BA>OGFLR,qAS,LFMX",
+            "FLRDDA5BA>OGFLR,qAS,LFMX:/165829h4415.41N/00600.03E",
+            "FLRDDA5BA>OGFLR,qAS,LFMX:/165829h4415.41N/00600.03E'342/049 id0ADDA5BA",
+            "FLRDDA5BA>OGFLR,qAS,LFMX:/165829h9415.41N/00600.03E'342/049/A=005524 id0ADDA5BA",
+            "FLRDDA5BA>OGFLR,qAS,LFMX:/165829h4415.41X/00600.03E'342/049/A=005524 id0ADDA5BA",
+            "FLRDDA5BA>OGFLR,qAS,LFMX:/165829h4415.41N/00600.03E'342/049/A=005524 id0ADDA5BX",
+            "FLRDDA5BA>OGFLR,qAS,LFMX:/165829h4415.41N/00600.03E'342/049/A=005524 id0ADDA5BA fastfpm",
+        ] {
+            assert!(SrcOgnAprs::parse_beacon(line).is_err(), "{}", line);
+        }
+    }
+
+
+    #[test]
+    fn login_and_stream_from_local_server() {
+        // Stand-in APRS-IS server, sending its banner, the login response and beacons cut in several writes
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+            sock.write_all(b"# aprsc 2.1.14-g5e22b37\r\n").unwrap();
+            let mut login = String::new();
+            BufReader::new(sock.try_clone().unwrap()).read_line(&mut login).unwrap();
+            sock.write_all(b"# logresp SRRUST unverified, server GLIDERN1\r\n").unwrap();
+            let (begin, end) = FLARM_BEACON.split_at(40);
+            sock.write_all(begin.as_bytes()).unwrap();
+            thread::sleep(Duration::from_millis(100));
+            sock.write_all(format!("{}\r\n{}\r\n", end, FLARM_BEACON.replace("DDA5BA", "DDA5BB")).as_bytes()).unwrap();
+            login
+        });
+
+        let region = Region { north: 51.3, south: 42.1, east: 8.4, west: -5.1 };
+        let mut connection = AprsConnection::connect(&address, &SrcOgnAprs::login_line("SRRUST", &[region, Region::WORLD])).unwrap();
+        let stop_flag = StopFlag::new();
+        let mut lines = Vec::new();
+        let error = loop {
+            match connection.next_line(&stop_flag) {
+                Ok(line) => lines.push(line.unwrap()),
+                Err(e) => break e,
+            }
+        };
+
+        let login = server.join().unwrap();
+        assert_eq!(login, format!("user SRRUST pass -1 vers srrust {} filter a/51.3/-5.1/42.1/8.4 a/90/-180/-90/180\r\n", env!("CARGO_PKG_VERSION")));
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[2], FLARM_BEACON);
+        let addresses: Vec<_> = lines.iter().filter_map(|line| SrcOgnAprs::parse_beacon(line).ok().flatten()).map(|infos| infos.address).collect();
+        assert_eq!(addresses, [0xDDA5BA, 0xDDA5BB]);
+        assert_eq!(error.to_string(), "Connection closed by OGN APRS-IS");
+
+        // The stop is seen without waiting for data
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut connection = AprsConnection::connect(&listener.local_addr().unwrap().to_string(), "user SRRUST pass -1\r\n").unwrap();
+        stop_flag.stop();
+        assert!(connection.next_line(&stop_flag).unwrap().is_none());
+    }
+
+
+    #[test]
+    fn region_across_the_antimeridian() {
+        let config = OgnConfig {
+            dynamic_region: false,
+            region: Region { north: -10.0, south: -25.0, east: -170.0, west: 170.0 },
+            ..Default::default()
+        };
+        let regions = SrcOgn::query_regions(&config, &ClientAreas::new_shared());
+        assert_eq!(SrcOgnAprs::filter(&regions), "a/-10/170/-25/180 a/-10/-180/-25/-170");
+    }
+
+}
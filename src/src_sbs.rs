@@ -0,0 +1,416 @@
+//! Get aircraft informations from any SBS-1 BaseStation feed, like the port 30003 of dump1090
+//! The MSG messages of all the transmission types are accepted in any order: the information they give
+//! is accumulated for each aircraft, which is published as soon as its position is known.
+//! See <http://woodair.net/sbs/article/barebones42_socket_data.htm> for SBS message specification
+//!
+
+use crate::{config::SharedConfig, stop_flag::StopFlag, traffic_infos::{AddressType, Source, TrafficInfos}, traffic_store::{Publisher, SharedTrafficStore}};
+
+use anyhow::Context;
+use core::str;
+use std::{collections::HashMap, io::Read, net::TcpStream, thread, time::{Duration, Instant}, str::FromStr};
+
+// Position of data fields of an SBS MSG message
+const SBS_FIELD_POS_MESSAGE_TYPE: usize = 0;
+const SBS_FIELD_POS_TRANSMISSION_TYPE: usize = 1;
+const SBS_FIELD_POS_HEX_IDENT: usize = 4;
+const SBS_FIELD_POS_CALLSIGN: usize = 10;
+const SBS_FIELD_POS_ALTITUDE: usize = 11;
+const SBS_FIELD_POS_GROUND_SPEED: usize = 12;
+const SBS_FIELD_POS_TRACK: usize = 13;
+const SBS_FIELD_POS_LATITUDE: usize = 14;
+const SBS_FIELD_POS_LONGITUDE: usize = 15;
+const SBS_FIELD_POS_VERTICAL_RATE: usize = 16;
+
+// A position older than this is not published again with the information received after it
+const POSITION_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Interval between two removals of the aircraft no longer received
+pub const PURGE_PERIOD: Duration = Duration::from_secs(10);
+
+// Maximum time to wait for data before checking if the stop is requested
+const STOP_CHECK_PERIOD: Duration = Duration::from_secs(1);
+
+
+/// Result of the reading of an SBS feed
+pub enum SbsRead<'a> {
+    Message(&'a [u8]),                  // Next message, without its end of line
+    Timeout,                            // Nothing received during the read timeout
+    Stopped,                            // Stop requested
+}
+
+
+pub struct SrcSbs {
+    config: SharedConfig,
+    address: String,                        // host:port of the feed
+    publisher: Publisher,
+}
+
+impl SrcSbs {
+    /// Starts reception of the traffic of an SBS feed
+    pub fn start_receive(config: &SharedConfig, store: &SharedTrafficStore, address: &str, stop_flag: StopFlag) -> thread::JoinHandle<()> {
+        let sbs = Self::new(config, store, address);
+        thread::spawn(move || {
+            sbs.work_thread(&stop_flag);
+        })
+    }
+
+
+    fn new(config: &SharedConfig, store: &SharedTrafficStore, address: &str) -> SrcSbs {
+        SrcSbs {
+            config: config.clone(),
+            address: String::from(address),
+            publisher: Publisher::new(config, store, Source::Sbs),
+        }
+    }
+
+
+    fn work_thread(&self, stop_flag: &StopFlag) {
+        // Ok is only returned when the stop is requested
+        while let Err(e) = self.get_and_send_positions(stop_flag) {
+            log::warn!("SBS feed {} : {:#}", self.address, e);
+            if !stop_flag.sleep(Duration::from_secs(5)) {
+                break;
+            }
+        }
+        log::info!("SBS feed {} stopped", self.address);
+    }
+
+
+    /// Returns an error when the connection is lost, or Ok when the stop is requested
+    fn get_and_send_positions(&self, stop_flag: &StopFlag) -> anyhow::Result<()> {
+        // variable for get_message
+        let mut rx_buf = [0u8; 100_000];
+        let mut rx_buf_current_size = 0usize;
+        let mut msg_begin_offset = 0usize;
+
+        // State of the aircraft, built again after a reconnection
+        let mut aircraft = SbsAircraft::new();
+        let mut last_purge = Instant::now();
+
+        // Connection to the feed
+        let mut sock = TcpStream::connect(&self.address).context("Failed to connect")?;
+        log::info!("Connected to SBS feed {}", self.address);
+        Self::set_sock_options(&sock);
+
+        // Message reading and processing loop, until the stop is requested
+        loop {
+            match Self::get_message(&mut sock, &mut rx_buf, &mut rx_buf_current_size, &mut msg_begin_offset, stop_flag)? {
+                SbsRead::Message(msg) => aircraft.update_and_publish(msg, Instant::now(), &self.publisher),
+                SbsRead::Timeout => (),
+                SbsRead::Stopped => return Ok(()),
+            }
+            let now = Instant::now();
+
+            // The aircraft no longer received are forgotten after the same time as in the traffic table
+            if now.duration_since(last_purge) >= PURGE_PERIOD {
+                last_purge = now;
+                aircraft.purge(now, Duration::from_secs(self.config.get().traffic.max_age));
+            }
+        }
+    }
+
+    /// Sets the options of the socket of an SBS feed
+    pub fn set_sock_options(socket: &TcpStream) {
+        let sock = socket2::SockRef::from(socket);
+
+        // Setting TCP keepalive, to quickly detect a silent disconnection from the remote
+        let keepalive = socket2::TcpKeepalive::new()
+            .with_time(Duration::from_secs(30))
+            .with_interval(Duration::from_secs(5))
+            .with_retries(2);
+        sock.set_tcp_keepalive(&keepalive).unwrap();
+
+        // Setting a reception timeout to regularly check if the stop is requested
+        socket.set_read_timeout(Some(STOP_CHECK_PERIOD)).unwrap();
+    }
+
+
+    /// Get the next message of an SBS feed
+    /// Also returns when nothing is received during the read timeout, or when the stop is requested
+    pub fn get_message<'a>(socket: &mut TcpStream, rx_buf: &'a mut [u8], rx_buf_current_size: &mut usize, begin_offset: &mut usize,
+        stop_flag: &StopFlag) -> anyhow::Result<SbsRead<'a>> {
+        const MSG_END_VALUE: u8 = b'\n';
+        let mut current_offset = *begin_offset;
+
+        // Look for the end of the SBS message
+        'msg_end_search: loop {
+            // If we have processed all the data received, we retrieve new data
+            if current_offset >= *rx_buf_current_size {
+                // If there is no more space in the rx buffer,
+                // we shift the message to the beginning of the buffer to be able to fill it
+                if *rx_buf_current_size >= rx_buf.len() {
+                    rx_buf.copy_within(*begin_offset..*rx_buf_current_size, 0usize);
+                    *rx_buf_current_size -= *begin_offset;
+                    *begin_offset = 0;
+                    current_offset = *rx_buf_current_size;
+
+                    // If the buffer is still full, it means that the message is larger than
+                    // the reception buffer, this is not normal, we return an error
+                    anyhow::ensure!(*rx_buf_current_size < rx_buf.len(), "SBS message too long");
+                }
+
+                // The stop is checked before each read, a busy feed never reaches the timeout
+                if stop_flag.is_stopped() {
+                    return Ok(SbsRead::Stopped);
+                }
+
+                // Retrieve new data, the beginning of the message already received is kept for the next call
+                let nb = match socket.read(&mut rx_buf[*rx_buf_current_size..]) {
+                    Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {
+                        return Ok(SbsRead::Timeout);
+                    }
+                    result => result.context("Failed to read SBS data")?,
+                };
+                anyhow::ensure!(nb > 0, "SBS connection closed by the remote");
+                *rx_buf_current_size += nb;
+            }
+
+            // Look for the next end of message in the received data
+            while current_offset < *rx_buf_current_size {
+                // If we found the end of message, we leave the big loop
+                if rx_buf[current_offset] == MSG_END_VALUE {
+                    break 'msg_end_search;
+                }
+                current_offset += 1;
+            }
+        }
+
+        // Found the end of message, we set the beginning of the next message
+        let current_begin_offset = *begin_offset;
+        *begin_offset = current_offset + 1;
+
+        Ok(SbsRead::Message(&rx_buf[current_begin_offset..current_offset]))
+    }
+}
+
+
+// Information accumulated for an aircraft
+struct AircraftState {
+    infos: TrafficInfos,
+    altitude_known: bool,
+    position_time: Option<Instant>,         // Reception of the last position
+    last_message: Instant,
+}
+
+
+/// State of the aircraft of an SBS feed, updated by each message received
+#[derive(Default)]
+pub struct SbsAircraft {
+    aircraft: HashMap<(AddressType, u32), AircraftState>,
+}
+
+impl SbsAircraft {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+
+    /// Updates the state of the aircraft with an SBS message
+    /// Returns the traffic information of the aircraft if the message has given information on it
+    /// and its position is known, None otherwise
+    pub fn update(&mut self, message: &[u8], now: Instant) -> anyhow::Result<Option<TrafficInfos>> {
+        let msg = str::from_utf8(message).context("Invalid character in SBS message")?;
+        let fields: Vec<&str> = msg.trim_end_matches('\r').split(',').collect();
+        let field = |position: usize| fields.get(position).map_or("", |field| field.trim());
+
+        // The other messages (SEL, ID, AIR, STA, CLK) do not give the state of the aircraft
+        if field(SBS_FIELD_POS_MESSAGE_TYPE) != "MSG" {
+            return Ok(None);
+        }
+        let transmission_type = u32::from_str(field(SBS_FIELD_POS_TRANSMISSION_TYPE)).context("Failed to parse SBS MSG transmission type")?;
+        anyhow::ensure!((1..=8).contains(&transmission_type), "Unexpected SBS MSG transmission type {}", transmission_type);
+
+        // Hex identifier of the mode S transponder, prefixed with '~' by dump1090 when it is not an ICAO address
+        let hex_ident = field(SBS_FIELD_POS_HEX_IDENT);
+        let (addr_type, hex_ident) = match hex_ident.strip_prefix('~') {
+            Some(hex_ident) => (AddressType::Random, hex_ident),
+            None => (AddressType::AdsbIcao, hex_ident),
+        };
+        let address = u32::from_str_radix(hex_ident, 16).context("Failed to parse SBS MSG hex identifier")?;
+        anyhow::ensure!(address <= 0xffffff, "SBS MSG hex identifier out of range");
+
+        // All the fields are checked before changing the state, an empty field gives no information
+        let callsign = field(SBS_FIELD_POS_CALLSIGN);
+        let altitude = Self::parse_number(field(SBS_FIELD_POS_ALTITUDE)).context("Failed to parse SBS MSG altitude")?;
+        let ground_speed = Self::parse_number(field(SBS_FIELD_POS_GROUND_SPEED)).context("Failed to parse SBS MSG ground speed")?;
+        let track = Self::parse_number(field(SBS_FIELD_POS_TRACK)).context("Failed to parse SBS MSG track")?;
+        anyhow::ensure!(track.is_none_or(|track| (0.0..=360.0).contains(&track)), "SBS MSG track out of range");
+        let latitude = Self::parse_number(field(SBS_FIELD_POS_LATITUDE)).context("Failed to parse SBS MSG latitude")?;
+        anyhow::ensure!(latitude.is_none_or(|latitude| (-90.0..=90.0).contains(&latitude)), "SBS MSG latitude out of range");
+        let longitude = Self::parse_number(field(SBS_FIELD_POS_LONGITUDE)).context("Failed to parse SBS MSG longitude")?;
+        anyhow::ensure!(longitude.is_none_or(|longitude| (-180.0..=180.0).contains(&longitude)), "SBS MSG longitude out of range");
+        let vertical_rate = Self::parse_number(field(SBS_FIELD_POS_VERTICAL_RATE)).context("Failed to parse SBS MSG vertical rate")?;
+
+        let state = self.aircraft.entry((addr_type, address)).or_insert_with(|| AircraftState {
+            infos: TrafficInfos { addr_type, address, ..Default::default() },
+            altitude_known: false,
+            position_time: None,
+            last_message: now,
+        });
+        state.last_message = now;
+
+        let mut updated = false;
+        if !callsign.is_empty() {
+            state.infos.callsign = String::from(callsign);
+            updated = true;
+        }
+        if let Some(altitude) = altitude {
+            state.infos.altitude = altitude.round() as i32;
+            state.altitude_known = true;
+            updated = true;
+        }
+        if let Some(ground_speed) = ground_speed {
+            state.infos.ground_speed = Some(ground_speed as i32);
+            updated = true;
+        }
+        if let Some(track) = track {
+            state.infos.track = Some(track as u32);
+            updated = true;
+        }
+        if let (Some(latitude), Some(longitude)) = (latitude, longitude) {
+            state.infos.latitude = latitude;
+            state.infos.longitude = longitude;
+            state.position_time = Some(now);
+            updated = true;
+        }
+        if let Some(vertical_rate) = vertical_rate {
+            state.infos.vertical_speed = Some(vertical_rate.round() as i32);
+            updated = true;
+        }
+
+        // The altitude is part of the position of the traffic reports
+        let position_known = state.altitude_known &&
+            state.position_time.is_some_and(|position_time| now.duration_since(position_time) <= POSITION_TIMEOUT);
+        Ok((updated && position_known).then(|| state.infos.clone()))
+    }
+
+
+    /// Updates the state of the aircraft with an SBS message, then publishes the aircraft if its information is complete
+    /// A wrong message does not prevent from receiving the next ones, it is only logged
+    pub fn update_and_publish(&mut self, message: &[u8], now: Instant, publisher: &Publisher) {
+        match self.update(message, now) {
+            Ok(Some(traffic_infos)) => publisher.publish(traffic_infos),
+            Ok(None) => (),     // Position not known yet, or message without aircraft information
+            Err(e) => log::debug!("Invalid SBS message ({:#}) : {}", e, String::from_utf8_lossy(message)),
+        }
+    }
+
+
+    /// Forgets the aircraft without message for longer than max_age
+    pub fn purge(&mut self, now: Instant, max_age: Duration) {
+        self.aircraft.retain(|_, state| now.duration_since(state.last_message) <= max_age);
+    }
+
+
+    // Numerical field, which can be empty
+    fn parse_number(field: &str) -> anyhow::Result<Option<f64>> {
+        if field.is_empty() {
+            return Ok(None);
+        }
+        let number = f64::from_str(field)?;
+        anyhow::ensure!(number.is_finite(), "Not a finite number");
+        Ok(Some(number))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Messages of dump1090 for the same aircraft
+    const MSG_IDENTIFICATION: &str = "MSG,1,1,1,4CA2D6,1,2024/06/01,12:00:00.000,2024/06/01,12:00:00.000,RYR4TX  ,,,,,,,,,,,0";
+    const MSG_POSITION: &str = "MSG,3,1,1,4CA2D6,1,2024/06/01,12:00:00.100,2024/06/01,12:00:00.100,,35000,,,45.50000,-1.25000,,,0,0,0,0";
+    const MSG_VELOCITY: &str = "MSG,4,1,1,4CA2D6,1,2024/06/01,12:00:00.200,2024/06/01,12:00:00.200,,,452,271,,,-64,,,,,0";
+    const MSG_ALTITUDE: &str = "MSG,5,1,1,4CA2D6,1,2024/06/01,12:00:00.300,2024/06/01,12:00:00.300,,35025,,,,,,,0,,0,0";
+    const MSG_ALL_CALL: &str = "MSG,8,1,1,4CA2D6,1,2024/06/01,12:00:00.400,2024/06/01,12:00:00.400,,,,,,,,,,,,0";
+
+
+    fn update(aircraft: &mut SbsAircraft, message: &str, now: Instant) -> Option<TrafficInfos> {
+        aircraft.update(message.as_bytes(), now).unwrap()
+    }
+
+
+    #[test]
+    fn messages_in_any_order() {
+        let mut aircraft = SbsAircraft::new();
+        let now = Instant::now();
+
+        // Nothing is published before the position
+        assert!(update(&mut aircraft, MSG_VELOCITY, now).is_none());
+        assert!(update(&mut aircraft, MSG_IDENTIFICATION, now).is_none());
+
+        // The position completes the information received before
+        let infos = update(&mut aircraft, &format!("{}\r", MSG_POSITION), now).unwrap();
+        assert_eq!(infos.addr_type, AddressType::AdsbIcao);
+        assert_eq!(infos.address, 0x4ca2d6);
+        assert_eq!(infos.callsign, "RYR4TX");
+        assert_eq!(infos.altitude, 35000);
+        assert_eq!((infos.latitude, infos.longitude), (45.5, -1.25));
+        assert_eq!((infos.ground_speed, infos.track, infos.vertical_speed), (Some(452), Some(271), Some(-64)));
+
+        // Then each message giving information is published, the others are not
+        assert_eq!(update(&mut aircraft, MSG_ALTITUDE, now).unwrap().altitude, 35025);
+        assert!(update(&mut aircraft, MSG_ALL_CALL, now).is_none());
+        assert!(update(&mut aircraft, "STA,,1,1,4CA2D6,1,2024/06/01,12:00:00.500,2024/06/01,12:00:00.500,RM", now).is_none());
+
+        // Until the position becomes too old
+        assert!(update(&mut aircraft, MSG_VELOCITY, now + POSITION_TIMEOUT * 2).is_none());
+    }
+
+
+    #[test]
+    fn position_without_altitude() {
+        let mut aircraft = SbsAircraft::new();
+        let now = Instant::now();
+        let position = MSG_POSITION.replace(",35000,", ",,");
+        assert!(update(&mut aircraft, &position, now).is_none());
+        assert_eq!(update(&mut aircraft, MSG_ALTITUDE, now).unwrap().latitude, 45.5);
+    }
+
+
+    #[test]
+    fn non_icao_addresses() {
+        let mut aircraft = SbsAircraft::new();
+        let now = Instant::now();
+        let infos = update(&mut aircraft, &MSG_POSITION.replace("4CA2D6", "~4CA2D6"), now).unwrap();
+        assert_eq!((infos.addr_type, infos.address), (AddressType::Random, 0x4ca2d6));
+
+        // Not mixed with the aircraft of the same ICAO address
+        assert!(update(&mut aircraft, MSG_VELOCITY, now).is_none());
+    }
+
+
+    #[test]
+    fn invalid_messages() {
+        let mut aircraft = SbsAircraft::new();
+        let now = Instant::now();
+        for message in [
+            MSG_POSITION.replace("MSG,3", "MSG,9"),
+            MSG_POSITION.replace("MSG,3", "MSG,"),
+            MSG_POSITION.replace("4CA2D6", "4CA2G6"),
+            MSG_POSITION.replace("4CA2D6", "14CA2D6"),
+            MSG_POSITION.replace("45.50000", "95.0"),
+            MSG_POSITION.replace("-1.25000", "abc"),
+            MSG_VELOCITY.replace(",271,", ",400,"),
+        ] {
+            assert!(aircraft.update(message.as_bytes(), now).is_err(), "{}", message);
+        }
+
+        // An invalid message does not change the state
+        assert!(aircraft.aircraft.is_empty());
+    }
+
+
+    #[test]
+    fn purge_aircraft_no_longer_received() {
+        let mut aircraft = SbsAircraft::new();
+        let now = Instant::now();
+        update(&mut aircraft, MSG_POSITION, now);
+        update(&mut aircraft, &MSG_POSITION.replace("4CA2D6", "3950A1"), now + Duration::from_secs(50));
+        aircraft.purge(now + Duration::from_secs(70), Duration::from_secs(60));
+        assert_eq!(aircraft.aircraft.len(), 1);
+        assert!(aircraft.aircraft.contains_key(&(AddressType::AdsbIcao, 0x3950a1)));
+    }
+}
@@ -0,0 +1,42 @@
+use std::{sync::{atomic::{AtomicBool, Ordering}, Arc}, thread, time::{Duration, Instant}};
+
+
+// Maximum time between two checks of the flag while sleeping
+const SLEEP_CHECK_PERIOD: Duration = Duration::from_millis(100);
+
+
+/// Stop request shared between the main thread and the worker threads
+#[derive(Clone, Default)]
+pub struct StopFlag(Arc<AtomicBool>);
+
+impl StopFlag {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Asks all the threads sharing the flag to stop
+    pub fn stop(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Indicates whether the stop has been requested
+    pub fn is_stopped(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Sleeps for the given duration, or less if the stop is requested in the meantime
+    /// Returns false if the stop has been requested
+    pub fn sleep(&self, duration: Duration) -> bool {
+        let end = Instant::now() + duration;
+        loop {
+            if self.is_stopped() {
+                return false;
+            }
+            let now = Instant::now();
+            if now >= end {
+                return true;
+            }
+            thread::sleep((end - now).min(SLEEP_CHECK_PERIOD));
+        }
+    }
+}
@@ -1,15 +1,40 @@
+use crate::gdl90;
+
 use serde::{Serialize, Deserialize};
+use std::{fmt, str::FromStr, sync::Arc};
+
+/// Maximum length in bytes of the text fields of the traffic information, so that it always fits
+/// in a message of the internal bus with its encoded report
+pub const MAX_TEXT_LEN: usize = 64;
+
+/// Flag of the first byte of the OGN ids: the owner does not want the aircraft to be tracked
+pub const OGN_ID_FLAG_NO_TRACKING: u8 = 0x40;
 
-/// Address type as defined by GDL90, “Address Type” field
-#[derive(Default, Debug, Serialize, Deserialize)]
+/// Address type, mapped to the GDL90 “Address Type” field
+/// FLARM and OGN addresses have the same GDL90 type
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum AddressType {
     #[default]
     AdsbIcao,
-    Ogn
+    Random,         // Address chosen at random by the device
+    Flarm,          // Address assigned by FLARM
+    Ogn,            // Address of an OGN tracker
+}
+
+impl AddressType {
+    /// Get the address type of an OGN id, given by the 2 lowest bits of its first byte
+    pub fn from_ogn_id(id_flags: u8) -> Self {
+        match id_flags & 0x03 {
+            0 => AddressType::Random,
+            1 => AddressType::AdsbIcao,
+            2 => AddressType::Flarm,
+            _ => AddressType::Ogn,
+        }
+    }
 }
 
 /// Information regarding traffic
-#[derive(Default, Debug, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct TrafficInfos {
     pub addr_type: AddressType,
     pub address: u32,                   // on 24 bits
@@ -20,4 +45,63 @@ pub struct TrafficInfos {
     pub track: Option<u32>,             // in degrees
     pub ground_speed: Option<i32>,      // in kt
     pub vertical_speed: Option<i32>,    // in fpm
+    pub turn_rate: Option<f64>,         // in degrees per second, positive to the right
+    pub registration: String,           // empty if unknown
+    pub competition_number: String,     // empty if unknown
+    pub aircraft_model: String,         // empty if unknown
+}
+
+/// Traffic information with its GDL90 traffic report, encoded once and shared by all the clients
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncodedTraffic {
+    pub infos: TrafficInfos,
+    pub report: Arc<[u8]>,
+}
+
+impl EncodedTraffic {
+    pub fn new(mut infos: TrafficInfos) -> Self {
+        // The text fields can be long when they come from the OGN device database
+        for text in [&mut infos.callsign, &mut infos.registration, &mut infos.competition_number, &mut infos.aircraft_model] {
+            text.truncate(text.floor_char_boundary(MAX_TEXT_LEN));
+        }
+
+        let mut buffer = [0u8; 100];
+        let len = gdl90::make_traffic_report_message(&infos, &mut buffer).unwrap();
+        Self {
+            infos,
+            report: Arc::from(&buffer[..len]),
+        }
+    }
+}
+
+/// Network from which traffic information is received
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    Ogn,
+    Adsbhub,
+    Sbs,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Ogn => write!(f, "OGN"),
+            Source::Adsbhub => write!(f, "ADSBHub"),
+            Source::Sbs => write!(f, "SBS"),
+        }
+    }
+}
+
+impl FromStr for Source {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ogn" => Ok(Source::Ogn),
+            "adsbhub" => Ok(Source::Adsbhub),
+            "sbs" => Ok(Source::Sbs),
+            _ => Err(format!("Unknown source '{}'", s)),
+        }
+    }
 }
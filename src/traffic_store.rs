@@ -0,0 +1,496 @@
+//! Central table of the known traffic
+//! Each aircraft is identified by its address type and its address. The successive reports of the
+//! sources are merged in the table, which is used to send the traffic information to the clients.
+//! The same aircraft reported by several sources with the same ICAO address is fused into
+//! a single track, built from the best source.
+//! The identity of the aircraft whose owner refuses it in the OGN device database is never sent,
+//! whatever the source which gives it.
+//!
+
+use crate::{config::{FusionConfig, SharedConfig}, ddb::SharedDdb, geo, internal_com, traffic_infos::{AddressType, EncodedTraffic, Source, TrafficInfos}};
+
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+
+// Last report of a source for an aircraft
+struct SourceReport {
+    infos: TrafficInfos,            // Merge of all the reports received from the source
+    last_seen: Instant,
+}
+
+
+/// Known state of an aircraft
+pub struct TrafficEntry {
+    pub traffic: EncodedTraffic,    // State sent to the clients, built from the selected source
+    pub source: Source,             // Selected source
+    pub last_seen: Instant,         // Time of the last report, whatever the source
+    reports: HashMap<Source, SourceReport>,
+    anonymous: bool,                // One of the addresses of the aircraft must not be identified
+}
+
+
+/// Number of aircraft known for each source
+#[derive(Default, Debug)]
+pub struct TrafficStats {
+    pub nb_aircraft: usize,
+    pub nb_aircraft_by_source: HashMap<Source, usize>,
+    pub nb_fused_aircraft: usize,   // Aircraft reported by several sources
+}
+
+
+#[derive(Default)]
+pub struct TrafficStore {
+    entries: HashMap<(AddressType, u32), TrafficEntry>,
+    last_reports: HashMap<Source, Instant>,     // Time of the last report of each source
+    ddb: SharedDdb,                             // Wishes of the owners of the aircraft
+}
+
+/// Traffic table shared between the sources and the pools
+pub type SharedTrafficStore = Arc<Mutex<TrafficStore>>;
+
+
+impl TrafficStore {
+    pub fn new_shared(ddb: &SharedDdb) -> SharedTrafficStore {
+        Arc::new(Mutex::new(Self::new(ddb)))
+    }
+
+
+    pub fn new(ddb: &SharedDdb) -> Self {
+        Self {
+            ddb: ddb.clone(),
+            ..Default::default()
+        }
+    }
+
+
+    /// Merges a report in the table
+    /// The fields not provided by the report (None or empty) keep their last known value
+    /// Returns the resulting state of the aircraft, or None if the report does not change
+    /// what is sent to the clients because a better source is used for the aircraft
+    pub fn update(&mut self, infos: TrafficInfos, source: Source, fusion: &FusionConfig) -> Option<&TrafficEntry> {
+        let now = Instant::now();
+        self.last_reports.insert(source, now);
+        let key = self.track_key(&infos, fusion);
+        let identified = self.ddb.read().unwrap().identified(infos.addr_type, infos.address);
+
+        let entry = self.entries.entry(key).or_insert_with(|| TrafficEntry {
+            traffic: EncodedTraffic { infos: TrafficInfos::default(), report: Arc::from([]) },
+            source,
+            last_seen: now,
+            reports: HashMap::new(),
+            anonymous: false,
+        });
+        entry.anonymous |= !identified;
+
+        // Merge the report with the previous ones of the same source
+        entry.reports.entry(source)
+            .and_modify(|report| {
+                Self::merge(&mut report.infos, &infos);
+                report.last_seen = now;
+            })
+            .or_insert_with(|| SourceReport {
+                infos,
+                last_seen: now,
+            });
+        entry.last_seen = now;
+
+        // Select the best source for the aircraft, only its reports are sent to the clients
+        let selected_source = Self::select_source(&entry.reports, fusion);
+        if selected_source != source {
+            return None;
+        }
+        entry.source = selected_source;
+        // The state is encoded once for all the clients, without what could identify an anonymous aircraft,
+        // which can have been kept from a previous report or completed by another source
+        let mut infos = Self::fused_infos(key, &entry.reports, selected_source);
+        if entry.anonymous {
+            Self::hide_identity(&mut infos);
+        }
+        entry.traffic = EncodedTraffic::new(infos);
+
+        Some(entry)
+    }
+
+
+    /// Deletes the aircraft not seen for more than max_age
+    /// Returns the number of deleted aircraft
+    pub fn expire(&mut self, max_age: Duration) -> usize {
+        let nb_before = self.entries.len();
+        let now = Instant::now();
+        self.entries.retain(|_, entry| {
+            entry.reports.retain(|_, report| now.duration_since(report.last_seen) <= max_age);
+            !entry.reports.is_empty()
+        });
+        nb_before - self.entries.len()
+    }
+
+
+    /// Iterates over the aircraft seen for less than max_age
+    pub fn iter(&self, max_age: Duration) -> impl Iterator<Item = &TrafficEntry> {
+        let now = Instant::now();
+        self.entries.values().filter(move |entry| now.duration_since(entry.last_seen) <= max_age)
+    }
+
+
+    /// Indicates whether the source has sent a report for less than timeout
+    pub fn source_alive(&self, source: Source, timeout: Duration) -> bool {
+        self.last_reports.get(&source).is_some_and(|last_report| last_report.elapsed() <= timeout)
+    }
+
+
+    /// Get the number of aircraft in the table
+    pub fn stats(&self) -> TrafficStats {
+        let mut stats = TrafficStats {
+            nb_aircraft: self.entries.len(),
+            ..Default::default()
+        };
+        for entry in self.entries.values() {
+            *stats.nb_aircraft_by_source.entry(entry.source).or_default() += 1;
+            if entry.reports.len() > 1 {
+                stats.nb_fused_aircraft += 1;
+            }
+        }
+        stats
+    }
+
+
+    // Get the key of the track to which the report belongs
+    // A report with an address not seen as ICAO by its source is attached to the ICAO track with
+    // the same address if their positions are consistent
+    fn track_key(&mut self, infos: &TrafficInfos, fusion: &FusionConfig) -> (AddressType, u32) {
+        let key = (infos.addr_type, infos.address);
+        if !fusion.enabled || self.entries.contains_key(&key) {
+            return key;
+        }
+
+        let icao_key = (AddressType::AdsbIcao, infos.address);
+        match infos.addr_type {
+            AddressType::AdsbIcao => {
+                // An aircraft already known by another source with another address type becomes an ICAO track
+                let other_key = [AddressType::Random, AddressType::Flarm, AddressType::Ogn].into_iter()
+                    .map(|addr_type| (addr_type, infos.address))
+                    .find(|other_key| self.entries.get(other_key).is_some_and(|entry| Self::same_aircraft(&entry.traffic.infos, infos, fusion)));
+                if let Some(other_key) = other_key {
+                    let entry = self.entries.remove(&other_key).unwrap();
+                    log::debug!("Aircraft {:06X} fused with its ICAO track", infos.address);
+                    self.entries.insert(icao_key, entry);
+                }
+                icao_key
+            }
+            AddressType::Random | AddressType::Flarm | AddressType::Ogn => {
+                if self.entries.get(&icao_key).is_some_and(|entry| Self::same_aircraft(&entry.traffic.infos, infos, fusion)) {
+                    log::debug!("Aircraft {:06X} fused with its ICAO track", infos.address);
+                    icao_key
+                }
+                else {
+                    key
+                }
+            }
+        }
+    }
+
+
+    // Two reports with the same address are from the same aircraft if they are close to each other
+    fn same_aircraft(infos1: &TrafficInfos, infos2: &TrafficInfos, fusion: &FusionConfig) -> bool {
+        (geo::distance_km(infos1.latitude, infos1.longitude, infos2.latitude, infos2.longitude) <= fusion.max_distance) &&
+        (infos1.altitude.abs_diff(infos2.altitude) <= fusion.max_altitude_difference)
+    }
+
+
+    // The best source is the one with the highest priority among the recent reports,
+    // or the most recent if all the reports are old
+    fn select_source(reports: &HashMap<Source, SourceReport>, fusion: &FusionConfig) -> Source {
+        let now = Instant::now();
+        let stale_after = Duration::from_secs(fusion.stale_after);
+        let priority = |source: &Source| fusion.priority.iter().position(|s| s == source).unwrap_or(usize::MAX);
+
+        reports.iter()
+            .filter(|(_, report)| now.duration_since(report.last_seen) <= stale_after)
+            .min_by_key(|(source, _)| priority(source))
+            .or_else(|| reports.iter().max_by_key(|(_, report)| report.last_seen))
+            .map(|(source, _)| *source)
+            .unwrap()
+    }
+
+
+    // Information of the selected source, completed with the other sources
+    fn fused_infos(key: (AddressType, u32), reports: &HashMap<Source, SourceReport>, selected_source: Source) -> TrafficInfos {
+        let mut infos = reports[&selected_source].infos.clone();
+        (infos.addr_type, infos.address) = key;
+        for (source, report) in reports {
+            if *source != selected_source {
+                Self::complete(&mut infos, &report.infos);
+            }
+        }
+        infos
+    }
+
+
+    fn merge(infos: &mut TrafficInfos, new_infos: &TrafficInfos) {
+        // The position is always provided
+        infos.latitude = new_infos.latitude;
+        infos.longitude = new_infos.longitude;
+        infos.altitude = new_infos.altitude;
+
+        // Optional information
+        if !new_infos.callsign.is_empty() {
+            infos.callsign.clone_from(&new_infos.callsign);
+        }
+        if new_infos.track.is_some() {
+            infos.track = new_infos.track;
+        }
+        if new_infos.ground_speed.is_some() {
+            infos.ground_speed = new_infos.ground_speed;
+        }
+        if new_infos.vertical_speed.is_some() {
+            infos.vertical_speed = new_infos.vertical_speed;
+        }
+        if new_infos.turn_rate.is_some() {
+            infos.turn_rate = new_infos.turn_rate;
+        }
+        if !new_infos.registration.is_empty() {
+            infos.registration.clone_from(&new_infos.registration);
+        }
+        if !new_infos.competition_number.is_empty() {
+            infos.competition_number.clone_from(&new_infos.competition_number);
+        }
+        if !new_infos.aircraft_model.is_empty() {
+            infos.aircraft_model.clone_from(&new_infos.aircraft_model);
+        }
+    }
+
+
+    fn hide_identity(infos: &mut TrafficInfos) {
+        infos.callsign.clear();
+        infos.registration.clear();
+        infos.competition_number.clear();
+        infos.aircraft_model.clear();
+    }
+
+
+    // Fill the missing optional information with the one of another source
+    fn complete(infos: &mut TrafficInfos, other_infos: &TrafficInfos) {
+        if infos.callsign.is_empty() {
+            infos.callsign.clone_from(&other_infos.callsign);
+        }
+        infos.track = infos.track.or(other_infos.track);
+        infos.ground_speed = infos.ground_speed.or(other_infos.ground_speed);
+        infos.vertical_speed = infos.vertical_speed.or(other_infos.vertical_speed);
+        infos.turn_rate = infos.turn_rate.or(other_infos.turn_rate);
+        if infos.registration.is_empty() {
+            infos.registration.clone_from(&other_infos.registration);
+        }
+        if infos.competition_number.is_empty() {
+            infos.competition_number.clone_from(&other_infos.competition_number);
+        }
+        if infos.aircraft_model.is_empty() {
+            infos.aircraft_model.clone_from(&other_infos.aircraft_model);
+        }
+    }
+
+}
+
+
+/// Publication of the traffic reports of a source: the reports are merged in the traffic table,
+/// then the resulting state of the aircraft is sent to all the pools
+pub struct Publisher {
+    config: SharedConfig,
+    store: SharedTrafficStore,
+    sender: internal_com::Sender,
+    source: Source,
+}
+
+impl Publisher {
+    pub fn new(config: &SharedConfig, store: &SharedTrafficStore, source: Source) -> Self {
+        Self {
+            config: config.clone(),
+            store: store.clone(),
+            sender: internal_com::Sender::new(config.get().multicast_socket_addr(), config.get().server.region_size),
+            source,
+        }
+    }
+
+
+    /// Publishes a traffic report
+    pub fn publish(&self, infos: TrafficInfos) {
+        let config = self.config.get();
+
+        // The lock is not kept during the sending
+        let traffic = self.store.lock().unwrap()
+            .update(infos, self.source, &config.fusion)
+            .map(|entry| entry.traffic.clone());
+
+        if let Some(traffic) = traffic {
+            self.sender.send(&traffic);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ddb::Ddb, gdl90};
+    use std::sync::RwLock;
+
+    // A FLARM and an ICAO device whose owners refuse to be identified
+    const DDB: &str = r#"{"devices":[
+        {"device_type":"F","device_id":"DD1234","aircraft_model":"Duo Discus","registration":"F-CDUO","cn":"DU","tracked":"Y","identified":"N"},
+        {"device_type":"I","device_id":"3950A1","aircraft_model":"Robin DR400","registration":"F-GABC","cn":"","tracked":"Y","identified":"N"}
+    ]}"#;
+
+
+    fn report(addr_type: AddressType, address: u32, callsign: &str) -> TrafficInfos {
+        TrafficInfos {
+            addr_type,
+            address,
+            callsign: String::from(callsign),
+            altitude: 3000,
+            latitude: 45.0,
+            longitude: 5.0,
+            ..Default::default()
+        }
+    }
+
+
+    // Callsign and registration sent to the clients after a report
+    fn update(store: &mut TrafficStore, infos: TrafficInfos, source: Source) -> (String, String) {
+        let entry = store.update(infos, source, &FusionConfig::default()).unwrap();
+        (entry.traffic.infos.callsign.clone(), entry.traffic.infos.registration.clone())
+    }
+
+
+    // Makes the last report of a source for an aircraft older
+    fn age_report(store: &mut TrafficStore, key: (AddressType, u32), source: Source, age: Duration) {
+        let report = store.entries.get_mut(&key).unwrap().reports.get_mut(&source).unwrap();
+        report.last_seen -= age;
+    }
+
+
+    #[test]
+    fn tracks_of_the_same_icao_address_fused() {
+        let mut store = TrafficStore::new(&Ddb::new_shared());
+        let fusion = FusionConfig::default();
+
+        // OGN aircraft with an ICAO address and the same aircraft received by ADS-B
+        store.update(TrafficInfos { track: Some(90), ..report(AddressType::AdsbIcao, 0x3950a1, "") }, Source::Ogn, &fusion);
+        let entry = store.update(report(AddressType::AdsbIcao, 0x3950a1, "AFR12"), Source::Adsbhub, &fusion).unwrap();
+        assert_eq!((entry.source, entry.traffic.infos.callsign.as_str(), entry.traffic.infos.track), (Source::Adsbhub, "AFR12", Some(90)));
+
+        // FLARM track reported before the ADS-B track of the same aircraft, and after it
+        store.update(report(AddressType::Flarm, 0xdda5ba, "BA"), Source::Ogn, &fusion);
+        let entry = store.update(report(AddressType::AdsbIcao, 0xdda5ba, ""), Source::Sbs, &fusion).unwrap();
+        assert_eq!((entry.traffic.infos.addr_type, entry.traffic.infos.callsign.as_str()), (AddressType::AdsbIcao, "BA"));
+        store.update(report(AddressType::AdsbIcao, 0x4ca2d6, ""), Source::Adsbhub, &fusion);
+        assert!(store.update(report(AddressType::Ogn, 0x4ca2d6, "XYZ"), Source::Ogn, &fusion).is_none());
+
+        let stats = store.stats();
+        assert_eq!((stats.nb_aircraft, stats.nb_fused_aircraft), (3, 3));
+        assert!(!store.entries.contains_key(&(AddressType::Flarm, 0xdda5ba)));
+    }
+
+
+    #[test]
+    fn source_priority_and_stale_source() {
+        let mut store = TrafficStore::new(&Ddb::new_shared());
+        let fusion = FusionConfig::default();
+        let key = (AddressType::AdsbIcao, 0x3950a1);
+
+        // The reports of a less accurate source are not sent while the best one is received
+        assert!(store.update(report(AddressType::AdsbIcao, 0x3950a1, ""), Source::Ogn, &fusion).is_some());
+        assert_eq!(store.update(report(AddressType::AdsbIcao, 0x3950a1, ""), Source::Adsbhub, &fusion).unwrap().source, Source::Adsbhub);
+        assert!(store.update(report(AddressType::AdsbIcao, 0x3950a1, ""), Source::Ogn, &fusion).is_none());
+        assert_eq!(store.update(report(AddressType::AdsbIcao, 0x3950a1, ""), Source::Sbs, &fusion).unwrap().source, Source::Sbs);
+        assert!(store.update(report(AddressType::AdsbIcao, 0x3950a1, ""), Source::Adsbhub, &fusion).is_none());
+
+        // The next source is used when the best one is stale, until it is received again
+        let stale = Duration::from_secs(fusion.stale_after + 1);
+        age_report(&mut store, key, Source::Sbs, stale);
+        assert_eq!(store.update(report(AddressType::AdsbIcao, 0x3950a1, ""), Source::Adsbhub, &fusion).unwrap().source, Source::Adsbhub);
+        assert_eq!(store.update(report(AddressType::AdsbIcao, 0x3950a1, ""), Source::Sbs, &fusion).unwrap().source, Source::Sbs);
+
+        // When all the sources are stale, the most recent is used
+        age_report(&mut store, key, Source::Sbs, stale * 2);
+        age_report(&mut store, key, Source::Adsbhub, stale * 3);
+        age_report(&mut store, key, Source::Ogn, stale);
+        assert_eq!(TrafficStore::select_source(&store.entries[&key].reports, &fusion), Source::Ogn);
+    }
+
+
+    #[test]
+    fn no_fusion_of_inconsistent_tracks() {
+        let mut store = TrafficStore::new(&Ddb::new_shared());
+        let mut fusion = FusionConfig::default();
+
+        // Too far from each other, horizontally or vertically
+        store.update(report(AddressType::Flarm, 0xdda5ba, ""), Source::Ogn, &fusion);
+        store.update(TrafficInfos { latitude: 45.1, ..report(AddressType::AdsbIcao, 0xdda5ba, "") }, Source::Adsbhub, &fusion);
+        store.update(report(AddressType::Ogn, 0x2a3f61, ""), Source::Ogn, &fusion);
+        store.update(TrafficInfos { altitude: 5000, ..report(AddressType::AdsbIcao, 0x2a3f61, "") }, Source::Adsbhub, &fusion);
+
+        // Or with the fusion disabled
+        fusion.enabled = false;
+        store.update(report(AddressType::Random, 0x4ca2d6, ""), Source::Ogn, &fusion);
+        store.update(report(AddressType::AdsbIcao, 0x4ca2d6, ""), Source::Adsbhub, &fusion);
+
+        let stats = store.stats();
+        assert_eq!((stats.nb_aircraft, stats.nb_fused_aircraft), (6, 0));
+    }
+
+
+    #[test]
+    fn expiry_of_a_fused_track() {
+        let mut store = TrafficStore::new(&Ddb::new_shared());
+        let fusion = FusionConfig::default();
+        let key = (AddressType::AdsbIcao, 0xdda5ba);
+        let max_age = Duration::from_secs(60);
+        store.update(report(AddressType::Flarm, 0xdda5ba, "BA"), Source::Ogn, &fusion);
+        store.update(report(AddressType::AdsbIcao, 0xdda5ba, ""), Source::Adsbhub, &fusion);
+
+        // The track is kept while one of its sources is received
+        age_report(&mut store, key, Source::Adsbhub, max_age * 2);
+        assert_eq!(store.expire(max_age), 0);
+        assert_eq!(store.entries[&key].reports.len(), 1);
+        assert_eq!(store.iter(max_age).count(), 1);
+
+        // Then forgotten with its last source
+        age_report(&mut store, key, Source::Ogn, max_age * 2);
+        assert_eq!(store.expire(max_age), 1);
+        assert!(store.entries.is_empty());
+    }
+
+
+    #[test]
+    fn identity_of_an_aircraft_already_known_hidden() {
+        let ddb = Ddb::new_shared();
+        let mut store = TrafficStore::new(&ddb);
+        let infos = TrafficInfos { registration: String::from("F-CDUO"), ..report(AddressType::Flarm, 0xdd1234, "DU") };
+        assert_eq!(update(&mut store, infos, Source::Ogn), (String::from("DU"), String::from("F-CDUO")));
+
+        // The callsign blanked by the OGN source is not replaced by the one of the previous reports
+        *ddb.write().unwrap() = Ddb::parse(DDB).unwrap();
+        assert_eq!(update(&mut store, report(AddressType::Flarm, 0xdd1234, ""), Source::Ogn), (String::new(), String::new()));
+    }
+
+
+    #[test]
+    fn identity_hidden_whatever_the_source() {
+        let mut store = TrafficStore::new(&Arc::new(RwLock::new(Ddb::parse(DDB).unwrap())));
+
+        // ICAO device of the database reported by an ADS-B source
+        assert_eq!(update(&mut store, report(AddressType::AdsbIcao, 0x3950a1, "FGABC"), Source::Sbs), (String::new(), String::new()));
+
+        // FLARM device fused with the ICAO track of an ADS-B source, which gives a callsign
+        assert!(update(&mut store, report(AddressType::Flarm, 0xdd1234, ""), Source::Ogn).0.is_empty());
+        let entry = store.update(report(AddressType::AdsbIcao, 0xdd1234, "FCDUO"), Source::Adsbhub, &FusionConfig::default()).unwrap();
+        assert_eq!((entry.traffic.infos.addr_type, entry.source), (AddressType::AdsbIcao, Source::Adsbhub));
+        assert!(entry.traffic.infos.callsign.is_empty());
+        let message = gdl90::decode_message(&entry.traffic.report).unwrap();
+        assert!(matches!(message, gdl90::Message::TrafficReport(sent) if sent.callsign.is_empty()));
+
+        // The other aircraft keep their identity, completed by the other sources
+        let infos = TrafficInfos { registration: String::from("F-CHBA"), ..report(AddressType::Flarm, 0xdda5ba, "BA") };
+        update(&mut store, infos, Source::Ogn);
+        assert_eq!(update(&mut store, report(AddressType::AdsbIcao, 0xdda5ba, ""), Source::Adsbhub), (String::from("BA"), String::from("F-CHBA")));
+    }
+}